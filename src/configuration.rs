@@ -1,39 +1,681 @@
+use crate::spiders::{
+    ExtractionMode, LatencyTracker, OversizedSource, PriceBounds, ScrollAxis, ScrollStrategy,
+    SourceLimit, WaitCondition, DEFAULTABLE_FIELDS, REQUIRABLE_FIELDS,
+};
+use anyhow::{anyhow, Context};
 use config::Config;
+use regex::Regex;
+use scraper::Selector;
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::OnceLock, time::Duration};
+use url::Url;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub out_path: PathBuf,
     pub headless: bool,
+    /// Webdriver endpoint, or list of endpoints assigned to the spiders round-robin
+    #[serde(default)]
+    pub webdriver_url: WebdriverUrl,
+    /// Basic auth credentials for remote webdriver grids (eg: BrowserStack, Sauce Labs,
+    /// a Selenium grid behind a proxy), sent on every webdriver request
+    pub webdriver_credentials: Option<WebdriverCredentials>,
+    /// Settings of the http client shared by the features not using the webdriver
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// `Accept-Language` sent by the http client and the browser (eg: "es-PE"),
+    /// some stores change the currency or number format with it
+    pub accept_language: Option<String>,
+    /// WebDriver capabilities deep-merged over the generated ones (eg: chrome args or prefs),
+    /// on conflicts these values win and arrays are replaced instead of appended
+    pub capabilities: Option<serde_json::Value>,
     pub delay_milis: u64,
+    /// Scale the delay between subroutes with a moving average of the page load latency
+    /// of each spider, between `adaptive_delay_min_milis` and `adaptive_delay_max_milis`
+    #[serde(default)]
+    pub adaptive_delay: bool,
+    #[serde(default)]
+    pub adaptive_delay_min_milis: u64,
+    #[serde(default = "default_adaptive_delay_max_milis")]
+    pub adaptive_delay_max_milis: u64,
+    /// Wait between the launch of each spider
+    #[serde(default)]
+    pub inter_spider_delay_milis: u64,
+    /// Subroutes scraped at the same time by each spider
     pub spiders_buffer_size: usize,
+    /// Spiders running at the same time, the rest wait for one of them to finish
+    #[serde(default = "default_crawlers_buffer_size")]
+    pub crawlers_buffer_size: usize,
+    /// Encoding of the output files
+    #[serde(default)]
+    pub output_encoding: OutputEncoding,
+    /// Format of the output files
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Renames the output columns (field name -> column name)
+    #[serde(default)]
+    pub column_names: HashMap<String, String>,
+    /// Items whose price changed by more than this factor (up or down) from the previous
+    /// run are written to a separate `_suspicious` file, disabled if `None`
+    pub price_anomaly_factor: Option<f64>,
+    /// Write a `{name}_{date}_digest.json` with the new and disappeared items since the
+    /// previous csv output and this many biggest price drops, disabled if `None`
+    pub digest_top_n: Option<usize>,
+    /// Lower edges of the price buckets counted on the run report
+    #[serde(default = "default_price_histogram_edges")]
+    pub price_histogram_edges: Vec<f64>,
+    /// Serialize the output in parallel chunks, useful for runs with lots of items
+    #[serde(default)]
+    pub parallel_write: bool,
+    /// When writing a csv fails (even by a panic), keep the rows written before the failure
+    /// on `{prefix}_{date}.csv.partial` instead of discarding them. Rows are serialized
+    /// one at a time so the partial file is a valid csv, can't be used with `parallel_write`
+    #[serde(default)]
+    pub keep_partial_csv: bool,
+    /// Write one file per category: `{out_path}/{spider}/{category}_{date}.csv`
+    #[serde(default)]
+    pub split_by_category: bool,
+    /// Save a screenshot on `{out_path}/screenshots` when a subroute has no items
+    #[serde(default)]
+    pub screenshot_on_empty: bool,
+    /// Abort the crawl on the first subroute that fails to be scraped
+    #[serde(default)]
+    pub fail_fast: bool,
+    /// Exit with an error after the crawl if a subroute item count is out of its
+    /// expected range (see `expected_counts`)
+    #[serde(default)]
+    pub fail_on_count_anomaly: bool,
+    /// Log the time spent on each phase of the crawl (goto, wait, scroll, source, parse and
+    /// write) per spider at the end of the run
+    #[serde(default)]
+    pub profile: bool,
+    /// Record the attribute names found on the item elements and save the ones added or
+    /// removed since the previous run on `{name}_{date}_schema_drift.json`
+    #[serde(default)]
+    pub track_schema: bool,
+    /// Write the non fatal problems found while scraping to `{spider}_{date}_warnings.jsonl`
+    #[serde(default)]
+    pub collect_warnings: bool,
+    /// Write prices as an object with their currency (eg: `{"amount":1.5,"currency":"PEN"}`,
+    /// as json on csv cells) instead of the bare amount
+    #[serde(default)]
+    pub structured_price: bool,
+    /// Prices outside these bounds (eg: `0.0` placeholders) are dropped with a warning
+    #[serde(default)]
+    pub price_bounds: PriceBounds,
+    /// Build an id from the uri (or name and brand) of the items without one,
+    /// instead of dropping them
+    #[serde(default)]
+    pub synthesize_id: bool,
+    /// Values for the missing item fields (eg: `brand: "Sin marca"`),
+    /// see [`DEFAULTABLE_FIELDS`]
+    #[serde(default)]
+    pub field_defaults: HashMap<String, String>,
+    /// Fields every written row must have (eg: `[id, price]`), see [`REQUIRABLE_FIELDS`]
+    #[serde(default)]
+    pub required_fields: Vec<String>,
+    /// What to do with the rows missing a required field
+    #[serde(default)]
+    pub rejected_rows: RejectedRows,
+    /// How the items are stored
+    #[serde(default)]
+    pub sink: Sink,
+    /// Spreadsheet written by the `google_sheets` sink
+    pub google_sheets: Option<GoogleSheetsSettings>,
+    /// Also write every spider's items to `all_{date}.csv` with a common set of columns
+    #[serde(default)]
+    pub write_combined: bool,
+    /// Collect the subroutes in order and break duplicated ids by the lowest category, for
+    /// reproducible outputs (eg: golden-file tests) at the cost of throughput
+    #[serde(default)]
+    pub deterministic: bool,
+    /// Maximum size of a page source to be parsed, unlimited if `None`
+    pub max_source_bytes: Option<usize>,
+    /// What to do with the page sources bigger than `max_source_bytes`
+    #[serde(default)]
+    pub oversized_source: OversizedSource,
+    /// Groups of spiders sharing the same catalog (eg: `[metro, wong]`), their items are
+    /// merged into `{spider1}_{spider2}_..._{date}` without duplicated ids (per zone), keeping
+    /// the item of the first spider of the group
+    #[serde(default)]
+    pub catalog_groups: Vec<Vec<String>>,
     pub infinite_scrolling: InfiniteScrollingSettings,
     pub metro: InfiniteScrollingSpiderSettings,
     pub wong: InfiniteScrollingSpiderSettings,
     pub plaza_vea: MultipageSpiderSettings,
+    /// Http client built from these settings on first use, see [`crate::http::shared_client`]
+    #[serde(skip)]
+    pub(crate) http_client: OnceLock<reqwest::Client>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct InfiniteScrollingSettings {
     pub scroll_delay_milis: u64,
     pub scroll_checks: usize,
+    /// Wait after scrolling before reading the page, lets lazy-loaded items finish rendering
+    #[serde(default)]
+    pub post_scroll_settle_milis: u64,
+    /// Extra wait after a subroute finishes scrolling, added to `delay_milis`
+    #[serde(default)]
+    pub cooldown_after_scroll_milis: u64,
+    /// Only extract the items loaded on the initial render, without scrolling
+    #[serde(default)]
+    pub no_scroll: bool,
+    /// Retries of a failed page height read before stopping the scroll
+    #[serde(default = "default_height_retries")]
+    pub height_retries: usize,
+    #[serde(default = "default_height_retry_delay_milis")]
+    pub height_retry_delay_milis: u64,
 }
 
+/// Settings shared by every kind of spider, flattened on their settings
 #[derive(Debug, Deserialize)]
-pub struct InfiniteScrollingSpiderSettings {
+pub struct CommonSpiderSettings {
     pub name: String,
+    /// Disabled spiders are not run
+    #[serde(default = "default_true")]
+    pub enabled: bool,
     pub base_url: String,
     pub subroutes: Vec<String>,
     pub selector: String,
+    /// Where to read the items from, `selector` is still waited for after loading a page
+    #[serde(default)]
+    pub extraction: ExtractionMode,
+    /// Only collect these attributes from the matched elements
+    pub collect_attrs: Option<Vec<String>>,
+    /// Take the missing brands from the start of the item names, removing them from the names
+    pub brand_from_name: Option<BrandFromName>,
+    /// Delivery zones to scrape, the spider runs once per zone
+    #[serde(default)]
+    pub zones: Vec<ZoneConfig>,
+    /// Expected item count ranges of some subroutes, the subroutes out of range are
+    /// written to `{spider}_{date}_anomalies.json`
+    #[serde(default)]
+    pub expected_counts: Vec<ExpectedCount>,
+    /// Element with the expected number of items (eg: "1.234 productos")
+    pub count_selector: Option<String>,
+    /// JavaScript to run after loading a page, before waiting for the items
+    pub pre_scrape_js: Option<String>,
+    /// `<iframe>` holding the items, entered after loading a page so the items are waited
+    /// for and extracted inside it. Only one level is supported: the selector is matched on
+    /// the top document, so a grid inside nested frames can't be reached
+    pub iframe_selector: Option<String>,
+    /// Where to find the item price when `data-price` is missing or invalid
+    /// (eg: the `content` attr of `meta[itemprop=price]`)
+    pub price: Option<FieldSelector>,
+    /// Where to find the item rating
+    pub rating: Option<FieldSelector>,
+    /// Where to find the item review count
+    pub reviews: Option<FieldSelector>,
+    /// Where to find the marketplace seller of the item, the spider name is used if missing
+    pub seller: Option<FieldSelector>,
+    /// Wall-clock budget for the spider, no new subroutes are started after it
+    pub time_budget_secs: Option<u64>,
+    /// Selectors to try in order when `selector` doesn't match any element
+    #[serde(default)]
+    pub fallback_selectors: Vec<String>,
+    /// Regex replacements applied in order to the page source before parsing, to fix up
+    /// broken markup. Each one scans the whole source, so keep them few and specific
+    #[serde(default)]
+    pub html_preprocess: Vec<HtmlReplacement>,
+    /// Element only present on captcha/anti-bot pages
+    pub blocked_indicator_selector: Option<String>,
+    /// Pause after being blocked before continuing with the next subroutes
+    pub blocked_backoff_secs: Option<u64>,
+    /// Fail the subroutes landing on a host other than the one of `base_url` (or
+    /// `extra_hosts`), eg: after a redirect to a login wall
+    #[serde(default)]
+    pub verify_host: bool,
+    /// Other hosts allowed when `verify_host` is set (eg: CDNs or regional domains)
+    #[serde(default)]
+    pub extra_hosts: Vec<String>,
+    #[serde(default)]
+    pub wait_condition: WaitCondition,
+    /// Query params removed from the item uris (eg: `utm_source`)
+    #[serde(default)]
+    pub strip_query_params: Vec<String>,
+    /// Remove all the query params from the item uris
+    #[serde(default)]
+    pub strip_all_query: bool,
+    /// Item attribute with the EAN barcode, the item json-ld is used if missing
+    #[serde(default = "default_ean_attr")]
+    pub ean_attr: String,
+    /// Promotion badges of an item (eg: "2x1", "-30%"), the text of every match is kept
+    pub promotions_selector: Option<String>,
+    /// Items to skip (eg: sponsored cards), matching it themselves or inside a match
+    pub exclude_selector: Option<String>,
+    /// Words before the price of variable-price items (eg: "Desde"), removed before parsing
+    /// the price and flagged on `price_is_from`
+    #[serde(default)]
+    pub price_prefixes: Vec<String>,
+    /// Integer part of a price rendered on separate elements (eg: `<span>9</span>`),
+    /// used when the item has no valid `data-price`
+    pub price_int_selector: Option<String>,
+    /// Decimal part of a price rendered on separate elements (eg: `<span>90</span>`),
+    /// see `price_int_selector`
+    pub price_dec_selector: Option<String>,
+    /// Visit the detail page of each item to extract its attributes, see `details`
+    #[serde(default)]
+    pub fetch_details: bool,
+    pub details: Option<DetailsSettings>,
+    /// Stop the spider after this many subroutes fail in a row
+    pub max_consecutive_failures: Option<usize>,
+    /// Stop starting new subroutes once this many items were collected
+    pub max_total_items: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InfiniteScrollingSpiderSettings {
+    #[serde(flatten)]
+    pub common: CommonSpiderSettings,
+    /// How to detect that there are no more items to load
+    #[serde(default)]
+    pub scroll_strategy: ScrollStrategy,
+    /// Direction in which the page loads more items (`vertical` or `horizontal`)
+    #[serde(default)]
+    pub scroll_axis: ScrollAxis,
+    /// Scrollable element to scroll instead of the page (eg: a horizontal carousel)
+    pub scroll_container_selector: Option<String>,
+    /// Element that triggers loading more items when scrolled into view
+    pub sentinel_selector: Option<String>,
+    /// "Load more" button to click when present after scrolling
+    pub load_more_selector: Option<String>,
+    /// Button revealing the price of an item (eg: "Ver precio"), clicked on every item
+    /// after scrolling. Slow: each click is a webdriver round trip plus a short wait, so a
+    /// page with 500 items takes minutes longer, use only when the prices are hidden
+    pub reveal_price_selector: Option<String>,
+    /// Maximum number of `reveal_price_selector` buttons clicked per page
+    #[serde(default = "default_reveal_price_max_clicks")]
+    pub reveal_price_max_clicks: usize,
+    /// Additional prices to extract as pairs of (attribute, label),
+    /// eg: a loyalty price besides the regular one
+    #[serde(default)]
+    pub price_attrs: Vec<(String, String)>,
+    /// Save the scroll position reached on each subroute to
+    /// `{out_path}/{spider}_scroll_positions.json`, the next run scrolls quickly back to it
+    /// before continuing
+    #[serde(default)]
+    pub resumable_scroll: bool,
+    /// With `resumable_scroll`, time spent scrolling each subroute per run, the position
+    /// reached is saved for the next run
+    pub scroll_chunk_secs: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MultipageSpiderSettings {
+    #[serde(flatten)]
+    pub common: CommonSpiderSettings,
+    /// Regex used to extract the sku from the product `href` when `data-sku` is missing,
+    /// the first capture group is used as the sku
+    pub sku_from_href_regex: Option<String>,
+}
+
+/// Location of a value inside an item element
+/// The value is read from `attr` (or the text if `None`) of the child element matching
+/// `selector` (or the item element itself if `None`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct FieldSelector {
+    pub selector: Option<String>,
+    pub attr: Option<String>,
+}
+
+/// Regex replacement applied to the page source before parsing (eg: closing a tag)
+#[derive(Debug, Clone, Deserialize)]
+pub struct HtmlReplacement {
+    pub pattern: String,
+    /// Can reference the captured groups (eg: `$1`)
+    pub replacement: String,
+}
+
+/// Range of items a subroute is expected to have, both ends included
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExpectedCount {
+    pub subroute: String,
+    pub min: Option<usize>,
+    pub max: Option<usize>,
+}
+
+impl ExpectedCount {
+    pub fn contains(&self, count: usize) -> bool {
+        self.min.is_none_or(|min| count >= min) && self.max.is_none_or(|max| count <= max)
+    }
+}
+
+/// How to take the missing brands from the start of the item names
+/// (eg: "NESTLE Leche Ideal 400g")
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BrandFromName {
+    /// The leading uppercase words
+    Uppercase,
+    /// The longest of these brands the name starts with (case insensitive)
+    KnownBrands(Vec<String>),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneConfig {
     pub name: String,
-    pub base_url: String,
-    pub subroutes: Vec<String>,
-    pub selector: String,
+    /// Cookies to set on `base_url` to select the zone
+    #[serde(default)]
+    pub cookies: HashMap<String, String>,
+    /// Element to click on `base_url` to select the zone
+    pub selector: Option<String>,
+}
+
+/// Attributes table of the product detail pages, read as key/value pairs from each row
+#[derive(Debug, Clone, Deserialize)]
+pub struct DetailsSettings {
+    /// Rows of the table (eg: "table.specifications tr")
+    pub row_selector: String,
+    /// Attribute name inside a row (eg: "th")
+    pub key_selector: String,
+    /// Attribute value inside a row (eg: "td")
+    pub value_selector: String,
+    /// Detail pages fetched at the same time
+    #[serde(default = "default_details_concurrency")]
+    pub concurrency: usize,
+}
+
+fn default_crawlers_buffer_size() -> usize {
+    3
+}
+
+fn default_adaptive_delay_max_milis() -> u64 {
+    10_000
+}
+
+fn default_height_retries() -> usize {
+    2
+}
+
+fn default_height_retry_delay_milis() -> u64 {
+    500
+}
+
+fn default_reveal_price_max_clicks() -> usize {
+    500
+}
+
+fn default_details_concurrency() -> usize {
+    1
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_ean_attr() -> String {
+    "data-ean".to_string()
+}
+
+fn default_price_histogram_edges() -> Vec<f64> {
+    vec![0.0, 10.0, 50.0, 100.0]
+}
+
+impl Settings {
+    /// Builds the settings from a yaml string, without reading any file or environment variable
+    pub fn from_yaml_str(yaml: &str) -> Result<Self, config::ConfigError> {
+        Config::builder()
+            .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .build()?
+            .try_deserialize()
+    }
+
+    /// Gets the webdriver endpoint of the `i`-th spider (see [`WebdriverUrl::get`]) with the
+    /// credentials embedded as user info, which fantoccini sends as basic auth
+    pub fn webdriver_endpoint(&self, i: usize) -> anyhow::Result<String> {
+        let url = self.webdriver_url.get(i);
+        let credentials = match &self.webdriver_credentials {
+            Some(credentials) => credentials,
+            None => return Ok(url.to_string()),
+        };
+        let mut url =
+            Url::parse(url).with_context(|| format!("Invalid webdriver url {:?}", url))?;
+        let access_key = credentials
+            .access_key
+            .resolve()
+            .context("Failed to get the webdriver access key")?;
+        url.set_username(&credentials.username)
+            .and_then(|_| url.set_password(Some(&access_key)))
+            .map_err(|_| anyhow!("Can't set credentials on webdriver url {:?}", url.as_str()))?;
+        Ok(url.into())
+    }
+
+    /// Limit on the size of the page sources, if any
+    pub fn source_limit(&self) -> Option<SourceLimit> {
+        self.max_source_bytes.map(|max_bytes| SourceLimit {
+            max_bytes,
+            action: self.oversized_source,
+        })
+    }
+
+    /// Page load latency tracker of a spider, if `adaptive_delay` is set
+    pub fn latency_tracker(&self) -> Option<LatencyTracker> {
+        self.adaptive_delay.then(|| {
+            LatencyTracker::new(
+                Duration::from_millis(self.adaptive_delay_min_milis),
+                Duration::from_millis(self.adaptive_delay_max_milis),
+            )
+        })
+    }
+
+    /// Names of the configured spiders
+    pub fn spider_names(&self) -> [&str; 3] {
+        [
+            &self.metro.common.name,
+            &self.wong.common.name,
+            &self.plaza_vea.common.name,
+        ]
+    }
+
+    /// Checks the values that can't be validated when deserializing (selectors, urls, etc.)
+    /// Returns all the problems found
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.spiders_buffer_size == 0 {
+            errors.push("spiders_buffer_size must be greater than 0".to_string());
+        }
+        if self.crawlers_buffer_size == 0 {
+            errors.push("crawlers_buffer_size must be greater than 0".to_string());
+        }
+        if self.adaptive_delay && self.adaptive_delay_min_milis > self.adaptive_delay_max_milis {
+            errors.push(format!(
+                "adaptive_delay_min_milis ({}) can't be greater than adaptive_delay_max_milis ({})",
+                self.adaptive_delay_min_milis, self.adaptive_delay_max_milis
+            ));
+        }
+        if let Some(factor) = self.price_anomaly_factor {
+            if factor <= 1.0 {
+                errors.push(format!(
+                    "price_anomaly_factor must be greater than 1: {}",
+                    factor
+                ));
+            }
+        }
+        for field in self.field_defaults.keys() {
+            if !DEFAULTABLE_FIELDS.contains(&field.as_str()) {
+                errors.push(format!(
+                    "field_defaults: unknown field {:?}, expected one of {:?}",
+                    field, DEFAULTABLE_FIELDS
+                ));
+            }
+        }
+        if matches!(self.sink, Sink::GoogleSheets) && self.google_sheets.is_none() {
+            errors.push("sink google_sheets requires the google_sheets settings".to_string());
+        }
+        for field in &self.required_fields {
+            if !REQUIRABLE_FIELDS.contains(&field.as_str()) {
+                errors.push(format!(
+                    "required_fields: unknown field {:?}, expected one of {:?}",
+                    field, REQUIRABLE_FIELDS
+                ));
+            }
+        }
+        if let PriceBounds {
+            min: Some(min),
+            max: Some(max),
+        } = self.price_bounds
+        {
+            if min > max {
+                errors.push(format!(
+                    "price_bounds.min must not be greater than price_bounds.max: {} > {}",
+                    min, max
+                ));
+            }
+        }
+        if let Some(capabilities) = &self.capabilities {
+            if !capabilities.is_object() {
+                errors.push("capabilities must be a mapping".to_string());
+            }
+        }
+        if self.keep_partial_csv && self.parallel_write {
+            errors.push("keep_partial_csv can't be used with parallel_write".to_string());
+        }
+        if self.http.proxy_credentials.is_some() && self.http.proxy.is_none() {
+            errors.push("http.proxy_credentials is set without http.proxy".to_string());
+        }
+        for spider in [&self.metro, &self.wong] {
+            let selectors = [
+                &spider.scroll_container_selector,
+                &spider.sentinel_selector,
+                &spider.load_more_selector,
+                &spider.reveal_price_selector,
+            ]
+            .into_iter()
+            .flatten();
+            validate_spider(&mut errors, &spider.common, selectors);
+            if spider.scroll_chunk_secs.is_some() && !spider.resumable_scroll {
+                errors.push(format!(
+                    "{}: scroll_chunk_secs is set without resumable_scroll",
+                    spider.common.name
+                ));
+            }
+        }
+        let spider = &self.plaza_vea;
+        validate_spider(&mut errors, &spider.common, std::iter::empty());
+        if let Some(re) = &spider.sku_from_href_regex {
+            if let Err(e) = Regex::new(re) {
+                errors.push(format!(
+                    "{}: invalid sku_from_href_regex {:?}: {}",
+                    spider.common.name, re, e
+                ));
+            }
+        }
+        let names = self.spider_names();
+        self.catalog_groups
+            .iter()
+            .flatten()
+            .filter(|name| !names.contains(&name.as_str()))
+            .for_each(|name| errors.push(format!("catalog_groups: unknown spider {:?}", name)));
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Validates the settings shared by all spiders, along with the `selectors` of the settings
+/// of its kind of spider
+fn validate_spider<'a>(
+    errors: &mut Vec<String>,
+    spider: &'a CommonSpiderSettings,
+    selectors: impl Iterator<Item = &'a String>,
+) {
+    let name = &spider.name;
+    if name.trim().is_empty() {
+        errors.push("Spider name can't be empty".to_string());
+    }
+    match Url::parse(&spider.base_url) {
+        Ok(url) if ["http", "https"].contains(&url.scheme()) => {}
+        Ok(_) => errors.push(format!(
+            "{}: base_url must be http(s): {:?}",
+            name, spider.base_url
+        )),
+        Err(e) => errors.push(format!(
+            "{}: invalid base_url {:?}: {}",
+            name, spider.base_url, e
+        )),
+    }
+    let common_selectors = [&spider.selector]
+        .into_iter()
+        .chain(&spider.fallback_selectors)
+        .chain(&spider.count_selector)
+        .chain(&spider.iframe_selector)
+        .chain(&spider.blocked_indicator_selector)
+        .chain(&spider.promotions_selector)
+        .chain(&spider.exclude_selector)
+        .chain(&spider.price_int_selector)
+        .chain(&spider.price_dec_selector)
+        .chain(
+            spider
+                .details
+                .iter()
+                .flat_map(|x| [&x.row_selector, &x.key_selector, &x.value_selector]),
+        )
+        .chain(spider.price.iter().flat_map(|x| &x.selector))
+        .chain(spider.rating.iter().flat_map(|x| &x.selector))
+        .chain(spider.reviews.iter().flat_map(|x| &x.selector))
+        .chain(spider.seller.iter().flat_map(|x| &x.selector))
+        .chain(spider.zones.iter().flat_map(|x| &x.selector));
+    common_selectors
+        .chain(selectors)
+        .filter(|selector| Selector::parse(selector).is_err())
+        .for_each(|selector| errors.push(format!("{}: invalid selector {:?}", name, selector)));
+    validate_html_preprocess(errors, name, &spider.html_preprocess);
+    validate_extraction(errors, name, &spider.extraction);
+    validate_price_parts(
+        errors,
+        name,
+        &spider.price_int_selector,
+        &spider.price_dec_selector,
+    );
+}
+
+fn validate_html_preprocess(
+    errors: &mut Vec<String>,
+    name: &str,
+    replacements: &[HtmlReplacement],
+) {
+    replacements
+        .iter()
+        .filter_map(|x| Regex::new(&x.pattern).err().map(|e| (&x.pattern, e)))
+        .for_each(|(pattern, e)| {
+            errors.push(format!(
+                "{}: invalid html_preprocess pattern {:?}: {}",
+                name, pattern, e
+            ))
+        });
+}
+
+fn validate_extraction(errors: &mut Vec<String>, name: &str, extraction: &ExtractionMode) {
+    if let ExtractionMode::EmbeddedJson {
+        script_id,
+        field_map,
+        ..
+    } = extraction
+    {
+        if script_id.trim().is_empty() {
+            errors.push(format!("{}: extraction script_id can't be empty", name));
+        }
+        if field_map.is_empty() {
+            errors.push(format!("{}: extraction field_map can't be empty", name));
+        }
+    }
+}
+
+fn validate_price_parts(
+    errors: &mut Vec<String>,
+    name: &str,
+    int_selector: &Option<String>,
+    dec_selector: &Option<String>,
+) {
+    if int_selector.is_none() && dec_selector.is_some() {
+        errors.push(format!(
+            "{}: price_dec_selector is set without price_int_selector",
+            name
+        ));
+    }
 }
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
@@ -62,6 +704,166 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
     settings.try_deserialize()
 }
 
+/// Google Sheet written by the `google_sheets` sink, the tabs of the spiders must exist
+#[derive(Debug, Clone, Deserialize)]
+pub struct GoogleSheetsSettings {
+    pub spreadsheet_id: String,
+    /// Json key of a service account with edit access to the spreadsheet
+    pub service_account_key: Secret,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HttpConfig {
+    pub user_agent: Option<String>,
+    /// Timeout of every request made with the shared http client
+    pub timeout_secs: Option<u64>,
+    /// Responses with a bigger body fail instead of being downloaded completely, checked on
+    /// the detail pages (the only store pages fetched without the webdriver)
+    pub max_body_bytes: Option<usize>,
+    /// Proxy used for all requests (eg: "http://localhost:8080")
+    pub proxy: Option<String>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// Credentials sent to the proxy
+    pub proxy_credentials: Option<ProxyCredentials>,
+}
+
+/// Only basic auth is supported, fantoccini doesn't allow setting other headers
+#[derive(Debug, Deserialize)]
+pub struct WebdriverCredentials {
+    pub username: String,
+    pub access_key: Secret,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: Secret,
+}
+
+/// Credential read on demand from an environment variable or a file,
+/// eg: `{ env: "PROXY_PASSWORD" }` or `{ file: "/run/secrets/proxy_password" }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Secret {
+    Env(String),
+    File(PathBuf),
+}
+
+impl Secret {
+    /// Gets the value of the secret, without trailing newlines on files
+    pub fn resolve(&self) -> anyhow::Result<String> {
+        match self {
+            Self::Env(var) => std::env::var(var)
+                .with_context(|| format!("Failed to read secret from env var {:?}", var)),
+            Self::File(path) => std::fs::read_to_string(path)
+                .map(|x| x.trim_end_matches(['\r', '\n']).to_string())
+                .with_context(|| format!("Failed to read secret from {:?}", path)),
+        }
+    }
+}
+
+const DEFAULT_WEBDRIVER_URL: &str = "http://localhost:4444";
+
+/// One or many webdriver endpoints
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum WebdriverUrl {
+    Single(String),
+    Pool(Vec<String>),
+}
+
+impl Default for WebdriverUrl {
+    fn default() -> Self {
+        Self::Single(DEFAULT_WEBDRIVER_URL.to_string())
+    }
+}
+
+impl WebdriverUrl {
+    /// Gets the endpoint for the `i`-th spider, cycling through the pool
+    pub fn get(&self, i: usize) -> &str {
+        match self {
+            Self::Single(url) => url,
+            Self::Pool(urls) if urls.is_empty() => DEFAULT_WEBDRIVER_URL,
+            Self::Pool(urls) => &urls[i % urls.len()],
+        }
+    }
+}
+
+/// What to do with the rows missing a required field
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectedRows {
+    /// Log and drop them
+    #[default]
+    Drop,
+    /// Write them to `{spider}_{date}_rejected.jsonl`
+    File,
+}
+
+/// Ways to store the scraped items
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    /// A new `{spider}_{date}.csv` file per run
+    #[default]
+    Daily,
+    /// A single growing `{spider}.csv` where only new or price-changed items are appended,
+    /// rows still present with the same price get their `last_seen` date updated
+    IncrementalCsv,
+    /// The daily files, also replacing the `{spider}` tab of the spreadsheet configured on
+    /// `google_sheets` with the items
+    GoogleSheets,
+}
+
+/// The supported formats for the output files.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A single json array, `column_names` are not applied
+    Json,
+    /// One json object per line, `column_names` are not applied
+    Jsonl,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            other => Err(format!(
+                "{:?} is not a supported output format. Use either `csv`, `json` or `jsonl`.",
+                other
+            )),
+        }
+    }
+}
+
+/// The supported encodings for the output files.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    #[default]
+    Utf8,
+    /// Characters that can't be represented are replaced by `?`
+    Latin1,
+}
+
 /// The possible runtime environment for our application.
 pub enum Environment {
     Local,
@@ -91,3 +893,63 @@ impl TryFrom<String> for Environment {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Configuration of the three spiders without subroutes, writing to `out_path`
+    pub(crate) fn test_settings(out_path: &Path) -> Settings {
+        Settings::from_yaml_str(&format!(
+            r#"
+out_path: {:?}
+headless: true
+delay_milis: 0
+spiders_buffer_size: 2
+infinite_scrolling:
+  scroll_delay_milis: 0
+  scroll_checks: 1
+metro:
+  name: "metro"
+  base_url: "https://www.metro.pe"
+  selector: ".product-item"
+  subroutes: []
+wong:
+  name: "wong"
+  base_url: "https://www.wong.pe"
+  selector: ".product-item"
+  subroutes: []
+plaza_vea:
+  name: "plaza_vea"
+  base_url: "https://www.plazavea.com.pe"
+  selector: ".showcase-grid > .Showcase"
+  subroutes: []
+"#,
+            out_path
+        ))
+        .expect("Invalid test configuration")
+    }
+
+    #[test]
+    fn test_settings_are_valid() {
+        let settings = test_settings(Path::new("output_test"));
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_checks_the_common_and_specific_selectors() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.metro.common.exclude_selector = Some("[".to_string());
+        settings.metro.sentinel_selector = Some("]".to_string());
+        settings.plaza_vea.common.count_selector = Some("[".to_string());
+        assert_eq!(
+            settings.validate().unwrap_err(),
+            [
+                r#"metro: invalid selector "[""#,
+                r#"metro: invalid selector "]""#,
+                r#"plaza_vea: invalid selector "[""#,
+            ]
+        );
+    }
+}
@@ -1,18 +1,45 @@
 use crate::{
-    configuration::Settings, error_chain_fmt, get_peru_date, spawn_blocking_with_tracing,
-    spiders::Spider,
+    configuration::{ExpectedCount, OutputEncoding, OutputFormat, RejectedRows, Settings, Sink},
+    encoding::TranscodingWriter,
+    error_chain_fmt,
+    http::shared_client,
+    last_segment_slug,
+    sheets::{sheet_rows, SheetsExporter},
+    spawn_blocking_with_tracing,
+    spiders::{
+        serialize_csv_row, CommonRow, Phase, Price, Spider, SpiderError, SpiderItem,
+        SubrouteStatus, Warning, Warnings,
+    },
 };
 use anyhow::Context;
-use std::{fmt::Display, io::BufWriter, path::PathBuf};
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use futures::future::{join_all, try_join_all};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    io::{BufReader, BufWriter, Read, Write},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
-    fs::{create_dir, File},
-    time::Instant,
+    fs::{create_dir_all, remove_file, rename, write, File},
+    sync::Semaphore,
+    task::AbortHandle,
+    time::{sleep, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
 #[derive(thiserror::Error)]
 pub enum CrawlerError {
     #[error("Provided out_path is not a directory: {0}")]
     OutPathNoDir(PathBuf),
+    #[error("Failed to scrape spider")]
+    ScrapeError(#[source] SpiderError),
     #[error("Something went wrong.")]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -23,81 +50,1678 @@ impl std::fmt::Debug for CrawlerError {
     }
 }
 
+/// Summary of a crawler run
+#[derive(Debug, Default)]
+pub struct CrawlReport {
+    /// Number of items written
+    pub items: usize,
+    /// Whether the run was cancelled and only partial results were written
+    pub cancelled: bool,
+    /// Whether the time budget was exceeded and only partial results were written
+    pub budget_exceeded: bool,
+    /// Whether the item cap was reached and only partial results were written
+    pub capped: bool,
+    /// Number of items by price bucket (lower edge, count)
+    pub price_histogram: Vec<(f64, usize)>,
+    /// Outcome of each subroute (of every zone)
+    pub subroutes: Vec<SubrouteStatus>,
+    /// Items as common rows, only kept when `write_combined` or `catalog_groups` are set
+    pub combined: Vec<CommonRow>,
+    /// Subroutes with an item count out of their expected range
+    pub count_anomalies: usize,
+    /// Items not written for missing a required field
+    pub rejected: usize,
+    /// Name of the spider
+    pub spider: String,
+    /// Time spent on each phase, only collected when `profile` is set
+    pub profile: Vec<(Phase, Duration)>,
+}
+
+/// Number of items serialized per task when `parallel_write` is enabled
+const PARALLEL_WRITE_CHUNK_SIZE: usize = 10_000;
+/// Rows written between flushes when `keep_partial_csv` is enabled
+const PARTIAL_CSV_FLUSH_ROWS: usize = 1_000;
+
+/// Options used when writing the output files
+#[derive(Debug, Clone)]
+struct OutputOptions {
+    encoding: OutputEncoding,
+    format: OutputFormat,
+    /// Field name -> column name
+    column_names: HashMap<String, String>,
+    price_anomaly_factor: Option<f64>,
+    digest_top_n: Option<usize>,
+    parallel_write: bool,
+    keep_partial_csv: bool,
+    split_by_category: bool,
+    /// Lower edges of the price buckets counted on the report
+    price_histogram_edges: Vec<f64>,
+    collect_warnings: bool,
+    required_fields: Vec<String>,
+    rejected_rows: RejectedRows,
+    sink: Sink,
+    /// Set when the sink is `google_sheets`
+    sheets: Option<SheetsExporter>,
+    /// Keep the items as common rows, for the combined output and the catalog groups
+    collect_common_rows: bool,
+}
+
+/// Builds the exporter of the `google_sheets` sink, logging why it can't be built
+fn sheets_exporter(configuration: &Settings) -> Option<SheetsExporter> {
+    if !matches!(configuration.sink, Sink::GoogleSheets) {
+        return None;
+    }
+    let settings = match &configuration.google_sheets {
+        Some(settings) => settings.clone(),
+        None => {
+            tracing::error!("The google_sheets sink is set without google_sheets settings");
+            return None;
+        }
+    };
+    match shared_client(configuration) {
+        Ok(client) => Some(SheetsExporter::new(client, settings)),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to build the sheets http client.");
+            None
+        }
+    }
+}
+
+impl OutputOptions {
+    fn new(configuration: &Settings) -> Self {
+        Self {
+            encoding: configuration.output_encoding,
+            format: configuration.output_format,
+            column_names: configuration.column_names.clone(),
+            price_anomaly_factor: configuration.price_anomaly_factor,
+            digest_top_n: configuration.digest_top_n,
+            parallel_write: configuration.parallel_write,
+            keep_partial_csv: configuration.keep_partial_csv,
+            split_by_category: configuration.split_by_category,
+            price_histogram_edges: configuration.price_histogram_edges.clone(),
+            collect_warnings: configuration.collect_warnings,
+            required_fields: configuration.required_fields.clone(),
+            rejected_rows: configuration.rejected_rows,
+            sink: configuration.sink,
+            sheets: sheets_exporter(configuration),
+            collect_common_rows: configuration.write_combined
+                || !configuration.catalog_groups.is_empty(),
+        }
+    }
+}
+
 pub struct Crawler<T>
 where
     T: Spider + Sync + Display,
 {
     spider: T,
     path: PathBuf,
+    /// Date used on the output files (YYYYMMDD)
+    date: String,
     buffer_size: usize,
+    fail_fast: bool,
+    deterministic: bool,
+    output: OutputOptions,
+    cancel: CancellationToken,
 }
 
 impl<T> Crawler<T>
 where
     T: Spider + Sync + Display,
 {
-    pub fn new(spider: T, configuration: &Settings) -> Self {
+    pub fn new(spider: T, configuration: &Settings, date: &str, cancel: CancellationToken) -> Self {
         Self {
             spider,
             path: configuration.out_path.clone(),
+            date: date.to_string(),
             buffer_size: configuration.spiders_buffer_size,
+            fail_fast: configuration.fail_fast,
+            deterministic: configuration.deterministic,
+            output: OutputOptions::new(configuration),
+            cancel,
         }
     }
 
     /// Process spider and save results on `out_path`
     #[tracing::instrument(skip(self), fields(path=?self.path, buffer_size=self.buffer_size))]
-    pub async fn process(self) -> Result<usize, CrawlerError> {
+    pub async fn process(self) -> Result<CrawlReport, CrawlerError> {
         if !self.path.exists() {
-            create_dir(&self.path)
+            create_dir_all(&self.path)
                 .await
                 .context("Failed to create dir for `out_path`")?;
         } else if !self.path.is_dir() {
             return Err(CrawlerError::OutPathNoDir(self.path));
         }
-        let date = get_peru_date();
-        let n = match process_spider(self.path, self.spider, date, self.buffer_size).await {
+        let res = process_spider(
+            self.path,
+            &self.spider,
+            self.date,
+            self.buffer_size,
+            self.fail_fast,
+            self.deterministic,
+            self.output,
+            self.cancel.clone(),
+        )
+        .await;
+        if let Err(e) = self.spider.close().await {
+            tracing::warn!(error.cause_chain = ?e, error.message = %e, "Failed to close spider.");
+        }
+        let report = match res {
+            Err(e) if self.fail_fast => return Err(e),
             Err(e) => {
                 tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to process spider.");
-                0
+                CrawlReport {
+                    cancelled: self.cancel.is_cancelled(),
+                    spider: self.spider.name().to_string(),
+                    profile: self
+                        .spider
+                        .profiler()
+                        .map(|profiler| profiler.totals())
+                        .unwrap_or_default(),
+                    ..Default::default()
+                }
             }
-            Ok(n) => n,
+            Ok(report) => report,
         };
 
-        Ok(n)
+        Ok(report)
+    }
+}
+
+/// Object safe version of [`Crawler`], erasing the item type of its spider so the crawlers
+/// of different spiders can be held together (eg: `Vec<Box<dyn BoxedCrawler>>`)
+#[async_trait]
+pub trait BoxedCrawler: Send {
+    /// Name of the spider
+    fn name(&self) -> &str;
+    /// Process the spider, see [`Crawler::process`]
+    async fn run(self: Box<Self>) -> Result<CrawlReport, CrawlerError>;
+}
+
+#[async_trait]
+impl<T> BoxedCrawler for Crawler<T>
+where
+    T: Spider + Send + Sync + Display + 'static,
+{
+    fn name(&self) -> &str {
+        self.spider.name()
+    }
+
+    async fn run(self: Box<Self>) -> Result<CrawlReport, CrawlerError> {
+        self.process().await
     }
 }
 
+/// Runs `crawlers` on their own tasks, up to `crawlers_buffer_size` at the same time and
+/// waiting `delay` between the launch of each one (unless `cancel` is triggered)
+/// With `fail_fast` the first error is returned, otherwise the failed crawlers are logged
+/// and left out of the reports. Fails if `crawlers_buffer_size` is 0
+pub async fn process_all(
+    crawlers: Vec<Box<dyn BoxedCrawler>>,
+    crawlers_buffer_size: usize,
+    delay: Duration,
+    fail_fast: bool,
+    cancel: &CancellationToken,
+) -> Result<Vec<CrawlReport>, CrawlerError> {
+    if crawlers_buffer_size == 0 {
+        return Err(anyhow::anyhow!("crawlers_buffer_size must be greater than 0").into());
+    }
+    let permits = Arc::new(Semaphore::new(crawlers_buffer_size));
+    let mut tasks = Vec::with_capacity(crawlers.len());
+    for (i, crawler) in crawlers.into_iter().enumerate() {
+        if i > 0 && !delay.is_zero() {
+            tracing::info!("Waiting {:?} before launching the next spider", delay);
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = cancel.cancelled() => {}
+            }
+        }
+        let permits = permits.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = permits
+                .acquire_owned()
+                .await
+                .context("Failed to acquire crawler permit")?;
+            crawler.run().await
+        }));
+    }
+    if fail_fast {
+        let abort_handles = tasks
+            .iter()
+            .map(|task| task.abort_handle())
+            .collect::<Vec<_>>();
+        let res = try_join_all(
+            tasks
+                .into_iter()
+                .map(|task| async move { task.await.context("Failed to execute task")? }),
+        )
+        .await;
+        if res.is_err() {
+            // Stop the crawlers still running instead of letting them finish detached
+            abort_handles.iter().for_each(AbortHandle::abort);
+        }
+        return res;
+    }
+    let reports = join_all(tasks)
+        .await
+        .into_iter()
+        .filter_map(|res| match res {
+            Ok(Ok(report)) => Some(report),
+            Ok(Err(e)) => {
+                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to process crawler.");
+                None
+            }
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to execute task.");
+                None
+            }
+        })
+        .collect();
+    Ok(reports)
+}
+
 /// Process and save results on of a spider
-/// Returns the number of elements processed
-#[tracing::instrument(fields(spider=%spider))]
+/// Returns a report with the number of elements processed
+#[tracing::instrument(skip(output, cancel), fields(spider=%spider))]
+#[allow(clippy::too_many_arguments)]
 async fn process_spider<T>(
     out_path: PathBuf,
-    spider: T,
+    spider: &T,
     date: String,
     spiders_buffer_size: usize,
-) -> Result<usize, CrawlerError>
+    fail_fast: bool,
+    deterministic: bool,
+    output: OutputOptions,
+    cancel: CancellationToken,
+) -> Result<CrawlReport, CrawlerError>
 where
     T: Spider + Sync + Display,
 {
     tracing::info!("Start scrapping");
     let now = Instant::now();
-    let mut path = out_path.clone();
-    path.push(format!("{}_{}.csv", spider.name(), date));
-    let file = File::create(path)
+    let zones = spider.zones().to_vec();
+    let mut budget_exceeded = false;
+    let mut capped = false;
+    let mut rejected = 0;
+    let profiler = spider.profiler().cloned().unwrap_or_default();
+    let mut subroutes = Vec::new();
+    let mut combined = Vec::new();
+    let warnings = if output.collect_warnings {
+        Warnings::enabled()
+    } else {
+        Warnings::default()
+    };
+    let mut price_histogram = output
+        .price_histogram_edges
+        .iter()
+        .map(|&edge| (edge, 0))
+        .collect::<Vec<_>>();
+    let n = if zones.is_empty() {
+        let mut summary = spider
+            .scrape_all(
+                spiders_buffer_size,
+                fail_fast,
+                deterministic,
+                &warnings,
+                &cancel,
+            )
+            .await
+            .map_err(CrawlerError::ScrapeError)?;
+        budget_exceeded = summary.budget_exceeded;
+        rejected =
+            reject_incomplete(&out_path, spider.name(), &date, &mut summary.items, &output).await?;
+        capped = summary.capped;
+        subroutes = summary.statuses;
+        add_to_histogram(&mut price_histogram, &summary.items);
+        if output.collect_common_rows {
+            add_common_rows(&mut combined, spider.name(), &summary.items);
+        }
+        if output.collect_warnings {
+            save_warnings(&out_path, spider.name(), &date, warnings.take()).await?;
+        }
+        let start = profiler.start();
+        let n = save_items(&out_path, spider.name(), &date, summary.items, &output).await?;
+        profiler.record(Phase::Write, start);
+        n
+    } else {
+        let mut n = 0;
+        for zone in zones {
+            if cancel.is_cancelled() {
+                break;
+            }
+            if let Err(e) = spider.set_zone(&zone).await {
+                if fail_fast {
+                    return Err(CrawlerError::ScrapeError(e));
+                }
+                tracing::error!(error.cause_chain = ?e, error.message = %e, zone = %zone.name, "Failed to set zone.");
+                continue;
+            }
+            let prefix = format!("{}_{}", spider.name(), zone.name);
+            let mut summary = spider
+                .scrape_all(
+                    spiders_buffer_size,
+                    fail_fast,
+                    deterministic,
+                    &warnings,
+                    &cancel,
+                )
+                .await
+                .map_err(CrawlerError::ScrapeError)?;
+            budget_exceeded |= summary.budget_exceeded;
+            rejected +=
+                reject_incomplete(&out_path, &prefix, &date, &mut summary.items, &output).await?;
+            capped |= summary.capped;
+            subroutes.append(&mut summary.statuses);
+            add_to_histogram(&mut price_histogram, &summary.items);
+            summary
+                .items
+                .iter_mut()
+                .for_each(|item| item.set_zone(&zone.name));
+            if output.collect_common_rows {
+                add_common_rows(&mut combined, spider.name(), &summary.items);
+            }
+            if output.collect_warnings {
+                save_warnings(&out_path, &prefix, &date, warnings.take()).await?;
+            }
+            let start = profiler.start();
+            n += save_items(&out_path, &prefix, &date, summary.items, &output).await?;
+            profiler.record(Phase::Write, start);
+        }
+        n
+    };
+    let cancelled = cancel.is_cancelled();
+    if cancelled {
+        tracing::warn!("Cancelled, saved partial results");
+    }
+    if budget_exceeded {
+        tracing::warn!("Time budget exceeded, saved partial results");
+    }
+    if capped {
+        tracing::warn!("Item cap reached, saved partial results");
+    }
+    tracing::info!("Scraped {} elements in {:?}", n, now.elapsed());
+    tracing::info!("Price histogram: {:?}", price_histogram);
+    let failed = subroutes
+        .iter()
+        .filter_map(|x| x.result.as_ref().err().map(|e| (&x.subroute, e)))
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        tracing::warn!(
+            "Failed {} of {} subroutes: {:?}",
+            failed.len(),
+            subroutes.len(),
+            failed
+        );
+    }
+    let anomalies = count_anomalies(&subroutes, spider.expected_counts());
+    if !anomalies.is_empty() {
+        tracing::warn!(
+            "{} subroutes with an unexpected item count: {:?}",
+            anomalies.len(),
+            anomalies
+        );
+        save_anomalies(&out_path, spider.name(), &date, &anomalies).await?;
+    }
+    let count_anomalies = anomalies.len();
+    if let Some(keys) = spider.schema_tracker().and_then(|x| x.keys()) {
+        track_schema(&out_path, spider.name(), &date, &keys).await?;
+    }
+    Ok(CrawlReport {
+        items: n,
+        cancelled,
+        budget_exceeded,
+        capped,
+        price_histogram,
+        subroutes,
+        combined,
+        count_anomalies,
+        rejected,
+        spider: spider.name().to_string(),
+        profile: profiler.totals(),
+    })
+}
+
+/// Appends the common rows of `items` (see [`CommonRow`]) scraped by `store`
+fn add_common_rows<I: SpiderItem>(rows: &mut Vec<CommonRow>, store: &str, items: &[I]) {
+    rows.extend(items.iter().map(|item| CommonRow {
+        store: store.to_string(),
+        ..item.common_row()
+    }));
+}
+
+/// Writes the common rows of all the spiders on `all_{date}`
+#[tracing::instrument(skip(configuration, rows))]
+pub async fn save_combined(
+    configuration: &Settings,
+    date: &str,
+    rows: Vec<CommonRow>,
+) -> Result<usize, CrawlerError> {
+    save_common_rows(configuration, "all", date, rows).await
+}
+
+/// Writes the merged common rows of a catalog group on `{spider1}_{spider2}_..._{date}`,
+/// see [`crate::merge::merge_catalog_group`]
+#[tracing::instrument(skip(configuration, rows))]
+pub async fn save_merged(
+    configuration: &Settings,
+    group: &[String],
+    date: &str,
+    rows: Vec<CommonRow>,
+) -> Result<usize, CrawlerError> {
+    save_common_rows(configuration, &group.join("_"), date, rows).await
+}
+
+/// Writes common rows on `{prefix}_{date}` with the configured format
+async fn save_common_rows(
+    configuration: &Settings,
+    prefix: &str,
+    date: &str,
+    rows: Vec<CommonRow>,
+) -> Result<usize, CrawlerError> {
+    let output = OutputOptions::new(configuration);
+    let path =
+        configuration
+            .out_path
+            .join(format!("{}_{}.{}", prefix, date, output.format.extension()));
+    write_items(path, rows, output).await
+}
+
+/// Writes the `items` of a spider to `{prefix}_{date}`, using the configured output options
+pub async fn save_spider_items<I>(
+    configuration: &Settings,
+    prefix: &str,
+    date: &str,
+    items: Vec<I>,
+) -> Result<usize, CrawlerError>
+where
+    I: SpiderItem + Serialize + Send + Sync + 'static,
+{
+    let output = OutputOptions::new(configuration);
+    create_dir_all(&configuration.out_path)
+        .await
+        .context("Failed to create dir for `out_path`")?;
+    save_items(&configuration.out_path, prefix, date, items, &output).await
+}
+
+/// Counts the prices of `items` on the bucket with the greatest lower edge not above them,
+/// prices below all the edges are not counted
+fn add_to_histogram<I: SpiderItem>(histogram: &mut [(f64, usize)], items: &[I]) {
+    items
+        .iter()
+        .filter_map(SpiderItem::price)
+        .for_each(|price| {
+            if let Some((_, count)) = histogram
+                .iter_mut()
+                .filter(|(edge, _)| *edge <= price)
+                .max_by(|(a, _), (b, _)| a.total_cmp(b))
+            {
+                *count += 1;
+            }
+        });
+}
+
+/// Subroute with an item count out of its expected range
+#[derive(Debug, Serialize)]
+struct CountAnomaly<'a> {
+    subroute: &'a str,
+    count: usize,
+    min: Option<usize>,
+    max: Option<usize>,
+}
+
+/// Checks the item count of the successful `subroutes` against their `expected` range,
+/// the failed ones are already reported
+fn count_anomalies<'a>(
+    subroutes: &'a [SubrouteStatus],
+    expected: &'a [ExpectedCount],
+) -> Vec<CountAnomaly<'a>> {
+    subroutes
+        .iter()
+        .filter_map(|status| {
+            let count = *status.result.as_ref().ok()?;
+            let expected = expected.iter().find(|x| x.subroute == status.subroute)?;
+            (!expected.contains(count)).then_some(CountAnomaly {
+                subroute: &status.subroute,
+                count,
+                min: expected.min,
+                max: expected.max,
+            })
+        })
+        .collect()
+}
+
+/// Saves `anomalies` on `{prefix}_{date}_anomalies.json`
+async fn save_anomalies(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    anomalies: &[CountAnomaly<'_>],
+) -> Result<(), CrawlerError> {
+    let path = out_path.join(format!("{}_{}_anomalies.json", prefix, date));
+    let content =
+        serde_json::to_string_pretty(anomalies).context("Failed to serialize anomalies")?;
+    write(&path, content)
+        .await
+        .with_context(|| format!("Failed to write anomalies to {:?}", path))?;
+    Ok(())
+}
+
+/// Attribute names added and removed since the previous run
+#[derive(Debug, Serialize)]
+struct SchemaDrift<'a> {
+    /// File with the attribute names of the previous run
+    previous: &'a Path,
+    added: Vec<&'a String>,
+    removed: Vec<&'a String>,
+}
+
+/// Saves the attribute names found on this run (`keys`) on `{prefix}_{date}_schema.json`,
+/// and the ones added or removed since the previous run on `{prefix}_{date}_schema_drift.json`
+async fn track_schema(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    keys: &BTreeSet<String>,
+) -> Result<(), CrawlerError> {
+    // Nothing was parsed (eg: every subroute failed), not a change on the items
+    if keys.is_empty() {
+        tracing::warn!("No item attributes recorded, skipping schema tracking");
+        return Ok(());
+    }
+    let previous_path = find_previous_file(out_path, prefix, date, "_schema.json")?;
+    let path = out_path.join(format!("{}_{}_schema.json", prefix, date));
+    let content = serde_json::to_string_pretty(keys).context("Failed to serialize schema")?;
+    write(&path, content)
+        .await
+        .with_context(|| format!("Failed to write schema to {:?}", path))?;
+    let previous_path = match previous_path {
+        Some(previous_path) => previous_path,
+        None => {
+            tracing::info!("No previous schema found for {:?}", prefix);
+            return Ok(());
+        }
+    };
+    let content = tokio::fs::read_to_string(&previous_path)
+        .await
+        .with_context(|| format!("Failed to read schema from {:?}", previous_path))?;
+    let previous = serde_json::from_str::<BTreeSet<String>>(&content)
+        .with_context(|| format!("Invalid schema on {:?}", previous_path))?;
+    let drift = SchemaDrift {
+        previous: &previous_path,
+        added: keys.difference(&previous).collect(),
+        removed: previous.difference(keys).collect(),
+    };
+    if drift.added.is_empty() && drift.removed.is_empty() {
+        return Ok(());
+    }
+    tracing::warn!(
+        "Item attributes changed since {:?}: added={:?} removed={:?}",
+        previous_path,
+        drift.added,
+        drift.removed
+    );
+    let path = out_path.join(format!("{}_{}_schema_drift.json", prefix, date));
+    let content =
+        serde_json::to_string_pretty(&drift).context("Failed to serialize schema drift")?;
+    write(&path, content)
+        .await
+        .with_context(|| format!("Failed to write schema drift to {:?}", path))?;
+    Ok(())
+}
+
+/// Removes the items missing any of the `required_fields`, writing them to
+/// `{prefix}_{date}_rejected.jsonl` if `rejected_rows` is set to a file
+/// Returns the number of items removed
+async fn reject_incomplete<I: SpiderItem + Serialize>(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    items: &mut Vec<I>,
+    output: &OutputOptions,
+) -> Result<usize, CrawlerError> {
+    if output.required_fields.is_empty() {
+        return Ok(0);
+    }
+    let (complete, rejected): (Vec<_>, Vec<_>) = std::mem::take(items)
+        .into_iter()
+        .partition(|item| output.required_fields.iter().all(|x| item.has_field(x)));
+    *items = complete;
+    if rejected.is_empty() {
+        return Ok(0);
+    }
+    tracing::warn!(
+        "Rejected {} items missing a required field of {:?}",
+        rejected.len(),
+        output.required_fields
+    );
+    if let RejectedRows::File = output.rejected_rows {
+        let path = out_path.join(format!("{}_{}_rejected.jsonl", prefix, date));
+        let mut content = String::new();
+        for item in &rejected {
+            content.push_str(&serde_json::to_string(item).context("Failed to serialize item")?);
+            content.push('\n');
+        }
+        write(&path, content)
+            .await
+            .with_context(|| format!("Failed to write rejected items to {:?}", path))?;
+    }
+    Ok(rejected.len())
+}
+
+/// Saves `warnings` on `{prefix}_{date}_warnings.jsonl`, one json object per line
+async fn save_warnings(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    warnings: Vec<Warning>,
+) -> Result<(), CrawlerError> {
+    let path = out_path.join(format!("{}_{}_warnings.jsonl", prefix, date));
+    let mut content = String::new();
+    for warning in warnings {
+        content.push_str(&serde_json::to_string(&warning).context("Failed to serialize warning")?);
+        content.push('\n');
+    }
+    write(&path, content)
+        .await
+        .with_context(|| format!("Failed to write warnings to {:?}", path))?;
+    Ok(())
+}
+
+/// Saves `items` on `{prefix}_{date}.{ext}`, or on `{prefix}/{category}_{date}.{ext}` if
+/// `split_by_category` is set, or on `{prefix}.csv` for the incremental sink (always csv)
+/// Returns the number of elements written
+async fn save_items<I>(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    items: Vec<I>,
+    output: &OutputOptions,
+) -> Result<usize, CrawlerError>
+where
+    I: SpiderItem + Serialize + Send + Sync + 'static,
+{
+    if let Some(sheets) = &output.sheets {
+        let res = match sheet_rows(&items, &output.column_names) {
+            Ok(rows) => sheets.replace_sheet(prefix, rows).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to export items to google sheets.");
+        }
+    }
+    if let Sink::IncrementalCsv = output.sink {
+        let path = out_path.join(format!("{}.csv", prefix));
+        let (date, column_names) = (date.to_string(), output.column_names.clone());
+        return spawn_blocking_with_tracing(move || {
+            save_items_incremental(&path, &date, items, &column_names)
+        })
+        .await
+        .context("Failed to join task")?;
+    }
+    if !output.split_by_category {
+        return save_items_file(out_path, prefix, date, items, output).await;
+    }
+    let mut categories = HashMap::<String, Vec<I>>::new();
+    items.into_iter().for_each(|item| {
+        let category = category_slug(item.category());
+        categories.entry(category).or_default().push(item);
+    });
+    let out_path = out_path.join(prefix);
+    create_dir_all(&out_path)
+        .await
+        .context("Failed to create dir for categories")?;
+    let mut n = 0;
+    for (category, items) in categories {
+        n += save_items_file(&out_path, &category, date, items, output).await?;
+    }
+    Ok(n)
+}
+
+/// Column with the last date an item was seen with the same price on the incremental sink
+const LAST_SEEN_COLUMN: &str = "last_seen";
+
+/// Appends the new or price-changed `items` to the csv on `path`, updating the `last_seen`
+/// column of the rows still present with the same price. A missing file is created and the
+/// header of an existing one is preserved
+/// Returns the number of items saved, like the other sinks: the rows appended plus the rows
+/// whose `last_seen` was updated
+fn save_items_incremental<I>(
+    path: &Path,
+    date: &str,
+    items: Vec<I>,
+    column_names: &HashMap<String, String>,
+) -> Result<usize, CrawlerError>
+where
+    I: SpiderItem + Serialize,
+{
+    let first = match items.first() {
+        Some(first) => first,
+        None => return Ok(0),
+    };
+    let column = |field: &str| {
+        column_names
+            .get(field)
+            .cloned()
+            .unwrap_or_else(|| field.to_string())
+    };
+    let mut headers = get_headers(first)
+        .context("Failed to get headers")?
+        .iter()
+        .map(|field| column(field))
+        .collect::<csv::StringRecord>();
+    headers.push_field(LAST_SEEN_COLUMN);
+    let mut rows = Vec::new();
+    if path.exists() {
+        let mut rdr = csv::Reader::from_path(path).context("Failed to open incremental file")?;
+        let existing_headers = rdr
+            .headers()
+            .context("Failed to read incremental file headers")?
+            .clone();
+        if existing_headers != headers {
+            return Err(anyhow::anyhow!(
+                "Columns of {:?} don't match the items: {:?}",
+                path,
+                existing_headers
+            )
+            .into());
+        }
+        rows = rdr
+            .records()
+            .collect::<csv::Result<Vec<_>>>()
+            .context("Failed to read incremental file")?;
+    }
+    let position = |name: &str| {
+        headers
+            .iter()
+            .position(|x| x == name)
+            .with_context(|| format!("Column {:?} not found", name))
+    };
+    let (id_idx, price_idx) = (
+        position(&column(I::ID_FIELD))?,
+        position(&column(I::PRICE_FIELD))?,
+    );
+    let last_seen_idx = headers.len() - 1;
+    // Latest row of each id
+    let mut latest = rows
+        .iter()
+        .enumerate()
+        .filter_map(|(i, row)| Some((row.get(id_idx)?.to_string(), i)))
+        .collect::<HashMap<_, _>>();
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    for item in &items {
+        serialize_csv_row(&mut wtr, item).context("Failed to serialize item")?;
+    }
+    let data = wtr
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("Failed to serialize items")?;
+    let (mut appended, mut updated) = (0, 0);
+    for record in csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(data.as_slice())
+        .records()
+    {
+        let mut record = record.context("Failed to read serialized item")?;
+        record.push_field(date);
+        let id = record.get(id_idx).unwrap_or_default().to_string();
+        match latest.get(&id) {
+            Some(&i) if rows[i].get(price_idx) == record.get(price_idx) => {
+                rows[i] = rows[i]
+                    .iter()
+                    .enumerate()
+                    .map(|(j, x)| if j == last_seen_idx { date } else { x })
+                    .collect();
+                updated += 1;
+            }
+            _ => {
+                latest.insert(id, rows.len());
+                rows.push(record);
+                appended += 1;
+            }
+        }
+    }
+    // Rewrite the whole file through a temporary one so a failure doesn't corrupt it
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let res = (|| -> csv::Result<()> {
+        let mut wtr = csv::Writer::from_path(&tmp_path)?;
+        wtr.write_record(&headers)?;
+        for row in &rows {
+            wtr.write_record(row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    })();
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(anyhow::Error::from(e)
+            .context("Failed to write incremental file")
+            .into());
+    }
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to rename {:?} to {:?}", tmp_path, path))?;
+    tracing::info!(
+        "Appended {} new or changed items to {:?}, {} were unchanged",
+        appended,
+        path,
+        updated
+    );
+    Ok(appended + updated)
+}
+
+/// Gets a file name friendly version of `category`
+fn category_slug(category: Option<&str>) -> String {
+    let slug = category.map(last_segment_slug).unwrap_or_default();
+    if slug.is_empty() {
+        "uncategorized".to_string()
+    } else {
+        slug
+    }
+}
+
+/// Saves `items` on `{prefix}_{date}.{ext}`, if `price_anomaly_factor` is set, items with
+/// suspicious price changes are saved on `{prefix}_{date}_suspicious.{ext}` instead
+/// If `digest_top_n` is set, the changes since the previous file are saved on
+/// `{prefix}_{date}_digest.json`
+/// Returns the number of elements written on the main file
+async fn save_items_file<I>(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    items: Vec<I>,
+    output: &OutputOptions,
+) -> Result<usize, CrawlerError>
+where
+    I: SpiderItem + Serialize + Send + Sync + 'static,
+{
+    let previous = if output.price_anomaly_factor.is_some() || output.digest_top_n.is_some() {
+        let out_path = out_path.to_path_buf();
+        let prefix = prefix.to_string();
+        let date = date.to_string();
+        let column_names = output.column_names.clone();
+        let format = output.format;
+        spawn_blocking_with_tracing(move || {
+            load_previous_prices::<I>(&out_path, &prefix, &date, format, &column_names)
+        })
+        .await
+        .context("Failed to join task")??
+    } else {
+        None
+    };
+    let items = match output.price_anomaly_factor {
+        Some(factor) => {
+            let empty = HashMap::new();
+            let previous = previous.as_ref().unwrap_or(&empty);
+            let (items, suspicious) = items
+                .into_iter()
+                .partition::<Vec<_>, _>(|item| !is_price_anomaly(item, previous, factor));
+            if !suspicious.is_empty() {
+                tracing::warn!(
+                    "Found {} items with suspicious price changes",
+                    suspicious.len()
+                );
+                suspicious.iter().for_each(|item| {
+                    tracing::debug!(
+                        id = item.id(),
+                        price = ?item.price(),
+                        previous_price = ?previous.get(item.id()),
+                        "Suspicious price change"
+                    )
+                });
+                let path = out_path.join(format!(
+                    "{}_{}_suspicious.{}",
+                    prefix,
+                    date,
+                    output.format.extension()
+                ));
+                write_items(path, suspicious, output.clone()).await?;
+            }
+            items
+        }
+        None => items,
+    };
+    let digest = output
+        .digest_top_n
+        .map(|top_n| Digest::new(&items, previous.as_ref(), top_n));
+    let path = out_path.join(format!("{}_{}.{}", prefix, date, output.format.extension()));
+    let n = write_items(path, items, output.clone()).await?;
+    if let Some(digest) = digest {
+        tracing::info!(
+            "Digest for {:?}: {} items, {} new, {} disappeared, {} price drops",
+            prefix,
+            digest.items,
+            digest.new,
+            digest.disappeared,
+            digest.price_drops.len()
+        );
+        save_digest(out_path, prefix, date, &digest).await?;
+    }
+    Ok(n)
+}
+
+/// Changes of the items since the previous run
+#[derive(Debug, Serialize)]
+struct Digest {
+    /// Whether there was no previous file, every item is new
+    first_run: bool,
+    items: usize,
+    new: usize,
+    disappeared: usize,
+    /// Biggest price drops first (relative to the previous price)
+    price_drops: Vec<PriceDrop>,
+}
+
+#[derive(Debug, Serialize)]
+struct PriceDrop {
+    id: String,
+    name: Option<String>,
+    previous_price: f64,
+    price: f64,
+    /// Relative change (eg: -0.25 for a 25% drop)
+    change: f64,
+}
+
+impl Digest {
+    /// Compares `items` with the `previous` prices by id, `None` on the first run
+    fn new<I: SpiderItem>(
+        items: &[I],
+        previous: Option<&HashMap<String, Option<f64>>>,
+        top_n: usize,
+    ) -> Self {
+        let previous = match previous {
+            Some(previous) => previous,
+            None => {
+                return Self {
+                    first_run: true,
+                    items: items.len(),
+                    new: items.len(),
+                    disappeared: 0,
+                    price_drops: Vec::new(),
+                }
+            }
+        };
+        let new = items
+            .iter()
+            .filter(|item| !previous.contains_key(item.id()))
+            .count();
+        let ids = items.iter().map(SpiderItem::id).collect::<HashSet<_>>();
+        let disappeared = previous
+            .keys()
+            .filter(|id| !ids.contains(id.as_str()))
+            .count();
+        let mut price_drops = items
+            .iter()
+            .filter_map(|item| {
+                let price = item.price()?;
+                let previous_price = (*previous.get(item.id())?)?;
+                (previous_price > 0.0 && price < previous_price).then(|| PriceDrop {
+                    id: item.id().to_string(),
+                    name: item.common_row().name,
+                    previous_price,
+                    price,
+                    change: price / previous_price - 1.0,
+                })
+            })
+            .collect::<Vec<_>>();
+        price_drops.sort_by(|a, b| a.change.total_cmp(&b.change));
+        price_drops.truncate(top_n);
+        Self {
+            first_run: false,
+            items: items.len(),
+            new,
+            disappeared,
+            price_drops,
+        }
+    }
+}
+
+/// Saves `digest` on `{prefix}_{date}_digest.json`
+async fn save_digest(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    digest: &Digest,
+) -> Result<(), CrawlerError> {
+    let path = out_path.join(format!("{}_{}_digest.json", prefix, date));
+    let content = serde_json::to_string_pretty(digest).context("Failed to serialize digest")?;
+    write(&path, content)
+        .await
+        .with_context(|| format!("Failed to write digest to {:?}", path))?;
+    Ok(())
+}
+
+/// Checks if the price of `item` changed by more than `factor` (up or down)
+fn is_price_anomaly<I: SpiderItem>(
+    item: &I,
+    previous: &HashMap<String, Option<f64>>,
+    factor: f64,
+) -> bool {
+    match (item.price(), previous.get(item.id())) {
+        (Some(price), Some(&Some(previous_price))) if price > 0.0 && previous_price > 0.0 => {
+            let ratio = price / previous_price;
+            ratio > factor || ratio < 1.0 / factor
+        }
+        _ => false,
+    }
+}
+
+/// Finds the latest `{prefix}_{date}{suffix}` (or `{suffix}.gz`) before `date` on `out_path`
+fn find_previous_file(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    suffix: &str,
+) -> Result<Option<PathBuf>, CrawlerError> {
+    let previous_path = std::fs::read_dir(out_path)
+        .context("Failed to read `out_path`")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let file_name = entry.file_name().into_string().ok()?;
+            let file_name = file_name.strip_suffix(".gz").unwrap_or(&file_name);
+            let file_date = file_name
+                .strip_prefix(prefix)?
+                .strip_prefix('_')?
+                .strip_suffix(suffix)?
+                .to_string();
+            let is_date = file_date.len() == 8 && file_date.chars().all(|c| c.is_ascii_digit());
+            (is_date && file_date.as_str() < date).then(|| (file_date, entry.path()))
+        })
+        .max()
+        .map(|(_, path)| path);
+    Ok(previous_path)
+}
+
+/// Opens an output file for reading, decompressing it if it has a `.gz` extension
+fn open_output_file(path: &Path) -> std::io::Result<Box<dyn Read>> {
+    let file = BufReader::new(std::fs::File::open(path)?);
+    if path.extension().map(|x| x == "gz").unwrap_or(false) {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Loads the prices (if valid) by id of the latest `{prefix}_{date}.{ext}` before `date` on
+/// `out_path`, `ext` being the one of the configured `format`
+/// Returns `None` if there is no previous file
+fn load_previous_prices<I: SpiderItem>(
+    out_path: &Path,
+    prefix: &str,
+    date: &str,
+    format: OutputFormat,
+    column_names: &HashMap<String, String>,
+) -> Result<Option<HashMap<String, Option<f64>>>, CrawlerError> {
+    let suffix = format!(".{}", format.extension());
+    let previous_path = match find_previous_file(out_path, prefix, date, &suffix)? {
+        Some(previous_path) => previous_path,
+        None => {
+            tracing::info!("No previous file found for {:?}", prefix);
+            return Ok(None);
+        }
+    };
+    tracing::info!("Comparing prices with {:?}", previous_path);
+    let reader = open_output_file(&previous_path).context("Failed to open previous file")?;
+    let prices = match format {
+        OutputFormat::Csv => read_csv_prices::<I>(reader, &previous_path, column_names)?,
+        OutputFormat::Json => {
+            let values = serde_json::from_reader::<_, Vec<serde_json::Value>>(reader)
+                .context("Failed to read previous file")?;
+            json_prices::<I>(values)
+        }
+        OutputFormat::Jsonl => json_prices::<I>(
+            serde_json::Deserializer::from_reader(reader)
+                .into_iter::<serde_json::Value>()
+                .filter_map(|value| value.ok()),
+        ),
+    };
+    Ok(Some(prices))
+}
+
+/// Reads the prices by id of a previous csv file, with the configured `column_names`
+fn read_csv_prices<I: SpiderItem>(
+    reader: Box<dyn Read>,
+    previous_path: &Path,
+    column_names: &HashMap<String, String>,
+) -> Result<HashMap<String, Option<f64>>, CrawlerError> {
+    let column = |field: &str| {
+        column_names
+            .get(field)
+            .cloned()
+            .unwrap_or_else(|| field.to_string())
+    };
+    let (id_column, price_column) = (column(I::ID_FIELD), column(I::PRICE_FIELD));
+    let mut rdr = csv::Reader::from_reader(reader);
+    let headers = rdr
+        .byte_headers()
+        .context("Failed to read previous file headers")?
+        .clone();
+    let position = |name: &str| {
+        headers
+            .iter()
+            .position(|x| x == name.as_bytes())
+            .with_context(|| format!("Column {:?} not found on {:?}", name, previous_path))
+    };
+    let (id_idx, price_idx) = (position(&id_column)?, position(&price_column)?);
+    let prices = rdr
+        .byte_records()
+        .filter_map(|record| record.ok())
+        .filter_map(|record| {
+            let id = String::from_utf8_lossy(record.get(id_idx)?).to_string();
+            let price = std::str::from_utf8(record.get(price_idx)?)
+                .ok()
+                .and_then(|x| x.parse::<Price>().ok())
+                .map(|x| x.amount);
+            Some((id, price))
+        })
+        .collect();
+    Ok(prices)
+}
+
+/// Gets the prices by id of the items of a previous json or jsonl file
+fn json_prices<I: SpiderItem>(
+    values: impl IntoIterator<Item = serde_json::Value>,
+) -> HashMap<String, Option<f64>> {
+    values
+        .into_iter()
+        .filter_map(|value| {
+            let id = match &value[I::ID_FIELD] {
+                serde_json::Value::String(x) => x.clone(),
+                serde_json::Value::Number(x) => x.to_string(),
+                _ => return None,
+            };
+            let price = match &value[I::PRICE_FIELD] {
+                serde_json::Value::Number(x) => x.as_f64(),
+                serde_json::Value::Object(price) => price.get("amount").and_then(|x| x.as_f64()),
+                // Structured prices written by older versions, as json strings
+                serde_json::Value::String(x) => x.parse::<Price>().ok().map(|x| x.amount),
+                _ => None,
+            };
+            Some((id, price))
+        })
+        .collect()
+}
+
+/// Writes `items` on `path` using the configured format
+/// Returns the number of elements written
+async fn write_items<I>(
+    path: PathBuf,
+    items: Vec<I>,
+    output: OutputOptions,
+) -> Result<usize, CrawlerError>
+where
+    I: Serialize + Send + Sync + 'static,
+{
+    // Write to a temporary file first so a failed write never leaves a partial file behind
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let file = File::create(&tmp_path)
         .await
         .context("Failed to create file")?
         .into_std()
         .await;
-    let items = spider.scrape_all(spiders_buffer_size).await;
     let n = items.len();
-    spawn_blocking_with_tracing(move || {
-        let mut wtr = csv::Writer::from_writer(BufWriter::new(file));
-        items.into_iter().for_each(|item| {
-            wtr.serialize(item).unwrap();
-        });
+    let keep_partial = output.keep_partial_csv && matches!(output.format, OutputFormat::Csv);
+    let res = spawn_blocking_with_tracing(move || {
+        let sync_file = file.try_clone()?;
+        let writer: Box<dyn Write> = match output.encoding {
+            OutputEncoding::Utf8 => Box::new(BufWriter::new(file)),
+            OutputEncoding::Latin1 => Box::new(TranscodingWriter::new(
+                BufWriter::new(file),
+                encoding_rs::WINDOWS_1252,
+            )),
+        };
+        match output.format {
+            OutputFormat::Csv => {
+                write_csv(writer, items, &output).context("Failed to write csv")?
+            }
+            OutputFormat::Json => write_json(writer, items).context("Failed to write json")?,
+            OutputFormat::Jsonl => write_jsonl(writer, items).context("Failed to write jsonl")?,
+        }
+        sync_file.sync_all().context("Failed to sync file")?;
+        anyhow::Ok(())
     })
     .await
-    .context("Failed to join task")?;
-    tracing::info!("Scraped {} elements in {:?}", n, now.elapsed());
+    .context("Failed to join task")
+    .and_then(|res| res);
+    if let Err(e) = res {
+        if keep_partial {
+            keep_partial_file(&tmp_path, &path).await;
+        } else if let Err(e) = remove_file(&tmp_path).await {
+            tracing::warn!(error.message = %e, "Failed to remove temporary file {:?}", tmp_path);
+        }
+        return Err(e.into());
+    }
+    rename(&tmp_path, &path)
+        .await
+        .with_context(|| format!("Failed to rename {:?} to {:?}", tmp_path, path))?;
     Ok(n)
 }
+
+/// Moves the temporary file of a failed write to `{path}.partial`
+async fn keep_partial_file(tmp_path: &Path, path: &Path) {
+    let mut partial_path = path.to_path_buf().into_os_string();
+    partial_path.push(".partial");
+    let partial_path = PathBuf::from(partial_path);
+    match rename(tmp_path, &partial_path).await {
+        Ok(()) => tracing::warn!(
+            "Kept the rows written before the failure on {:?}",
+            partial_path
+        ),
+        Err(e) => {
+            tracing::warn!(error.message = %e, "Failed to rename {:?} to {:?}", tmp_path, partial_path)
+        }
+    }
+}
+
+/// Serializes `items` as a json array into `writer`
+fn write_json<I: Serialize>(mut writer: Box<dyn Write>, items: Vec<I>) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut writer, &items)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Serializes `items` as json lines into `writer`
+fn write_jsonl<I: Serialize>(mut writer: Box<dyn Write>, items: Vec<I>) -> anyhow::Result<()> {
+    for item in items {
+        serde_json::to_writer(&mut writer, &item)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Serializes `items` as csv into `writer`
+fn write_csv<I>(writer: Box<dyn Write>, items: Vec<I>, output: &OutputOptions) -> csv::Result<()>
+where
+    I: Serialize + Sync,
+{
+    let manual_headers =
+        !output.column_names.is_empty() || output.parallel_write || output.keep_partial_csv;
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(!manual_headers)
+        .from_writer(writer);
+    if manual_headers {
+        if let Some(item) = items.first() {
+            let columns = get_headers(item)?
+                .into_iter()
+                .map(|field| match output.column_names.get(&field) {
+                    Some(column) => column.clone(),
+                    None if output.column_names.is_empty() => field,
+                    None => {
+                        tracing::debug!("No column name for field {:?}", field);
+                        field
+                    }
+                })
+                .collect::<Vec<_>>();
+            wtr.write_record(columns)?;
+        }
+    }
+    if output.parallel_write {
+        let chunks = items
+            .par_chunks(PARALLEL_WRITE_CHUNK_SIZE)
+            .map(|chunk| {
+                let mut chunk_wtr = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(Vec::new());
+                for item in chunk {
+                    serialize_csv_row(&mut chunk_wtr, item)?;
+                }
+                chunk_wtr
+                    .into_inner()
+                    .map_err(|e| csv::Error::from(e.into_error()))
+            })
+            .collect::<csv::Result<Vec<_>>>()?;
+        let mut writer = wtr
+            .into_inner()
+            .map_err(|e| csv::Error::from(e.into_error()))?;
+        for chunk in chunks {
+            writer.write_all(&chunk)?;
+        }
+        writer.flush()?;
+    } else if output.keep_partial_csv {
+        let writer = wtr
+            .into_inner()
+            .map_err(|e| csv::Error::from(e.into_error()))?;
+        write_csv_rows(writer, &items)?;
+    } else {
+        for item in items {
+            serialize_csv_row(&mut wtr, &item)?;
+        }
+        wtr.flush()?;
+    }
+    Ok(())
+}
+
+/// Serializes `items` into `writer` one row at a time, flushing every
+/// [`PARTIAL_CSV_FLUSH_ROWS`] rows and before returning an error or resuming a panic, so the
+/// rows before a failed one are always complete
+fn write_csv_rows<I: Serialize>(mut writer: Box<dyn Write>, items: &[I]) -> csv::Result<()> {
+    for (i, item) in items.iter().enumerate() {
+        let row = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut row_wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(Vec::new());
+            serialize_csv_row(&mut row_wtr, item)?;
+            row_wtr
+                .into_inner()
+                .map_err(|e| csv::Error::from(e.into_error()))
+        }));
+        match row {
+            Ok(Ok(row)) => writer.write_all(&row)?,
+            Ok(Err(e)) => {
+                writer.flush()?;
+                return Err(e);
+            }
+            Err(panic) => {
+                writer.flush()?;
+                tracing::error!("Panicked serializing item {}, {} rows were written", i, i);
+                std::panic::resume_unwind(panic);
+            }
+        }
+        if (i + 1) % PARTIAL_CSV_FLUSH_ROWS == 0 {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Gets the csv headers (field names in order) of `item`
+pub(crate) fn get_headers<I: Serialize>(item: &I) -> csv::Result<Vec<String>> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    serialize_csv_row(&mut wtr, item)?;
+    let data = wtr.into_inner().map_err(|e| e.into_error())?;
+    let headers = csv::Reader::from_reader(data.as_slice())
+        .headers()?
+        .iter()
+        .map(String::from)
+        .collect();
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        configuration::tests::test_settings,
+        spiders::{MockItem, MockResponse, MockSpider},
+    };
+
+    const DATE: &str = "20240101";
+
+    /// Empty directory on the system temp dir, unique per test
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("peru-prices-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn mock_spider() -> MockSpider {
+        MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![(
+                "a",
+                MockResponse::ok(vec![
+                    MockItem::new(1, Some("x")),
+                    MockItem::new(2, Some("y")),
+                ]),
+            )],
+            0,
+        )
+    }
+
+    /// How serializing a [`FailingItem`] fails
+    #[derive(Debug, Clone, Copy)]
+    enum Failure {
+        Error,
+        Panic,
+    }
+
+    /// Item whose serialization fails when `failure` is set
+    #[derive(Debug)]
+    struct FailingItem {
+        id: usize,
+        failure: Option<Failure>,
+    }
+
+    impl Serialize for FailingItem {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::{Error, SerializeStruct};
+            match self.failure {
+                Some(Failure::Error) => {
+                    return Err(S::Error::custom(format!(
+                        "Failed to serialize item {}",
+                        self.id
+                    )))
+                }
+                Some(Failure::Panic) => panic!("Panicked serializing item {}", self.id),
+                None => {}
+            }
+            let mut state = serializer.serialize_struct("FailingItem", 1)?;
+            state.serialize_field("id", &self.id)?;
+            state.end()
+        }
+    }
+
+    /// Items `0..n` where the item `fail_at` fails to serialize
+    fn failing_items(n: usize, fail_at: usize, failure: Failure) -> Vec<FailingItem> {
+        (0..n)
+            .map(|id| FailingItem {
+                id,
+                failure: (id == fail_at).then_some(failure),
+            })
+            .collect()
+    }
+
+    /// Writes items failing at `fail_at` keeping the partial csv, returns its lines
+    async fn write_partial_csv(name: &str, fail_at: usize, failure: Failure) -> Vec<String> {
+        let dir = temp_dir(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("mock_{}.csv", DATE));
+        let mut output = OutputOptions::new(&test_settings(&dir));
+        output.keep_partial_csv = true;
+        let res = write_items(path.clone(), failing_items(10, fail_at, failure), output).await;
+        assert!(res.is_err());
+        assert!(!path.exists());
+        let partial = std::fs::read_to_string(dir.join(format!("mock_{}.csv.partial", DATE)));
+        std::fs::remove_dir_all(dir).unwrap();
+        partial.unwrap().lines().map(String::from).collect()
+    }
+
+    #[tokio::test]
+    async fn partial_csv_keeps_rows_before_error() {
+        let lines = write_partial_csv("partial-error", 6, Failure::Error).await;
+        assert_eq!(lines, ["id", "0", "1", "2", "3", "4", "5"]);
+    }
+
+    #[tokio::test]
+    async fn partial_csv_keeps_rows_before_panic() {
+        let lines = write_partial_csv("partial-panic", 4, Failure::Panic).await;
+        assert_eq!(lines, ["id", "0", "1", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn write_items_failure_leaves_no_files() {
+        let dir = temp_dir("write-failure");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(format!("mock_{}.csv", DATE));
+        let output = OutputOptions::new(&test_settings(&dir));
+        let res = write_items(path.clone(), failing_items(10, 5, Failure::Error), output).await;
+        assert!(res.is_err());
+        assert!(!path.exists());
+        assert!(!dir.join(format!("mock_{}.csv.tmp", DATE)).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_previous_prices_uses_the_output_format() {
+        let dir = temp_dir("previous-prices");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("mock_20231230.csv"), "id,price\n1,1.0\n").unwrap();
+        std::fs::write(
+            dir.join("mock_20231231.jsonl"),
+            concat!(
+                "{\"id\":\"1\",\"price\":4.2}\n",
+                "{\"id\":\"2\",\"price\":null}\n",
+                "{\"id\":\"3\",\"price\":{\"amount\":3.5,\"currency\":\"PEN\"}}\n",
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("mock_20231231.json"),
+            r#"[{"id":"1","price":"5.5"}]"#,
+        )
+        .unwrap();
+        let load = |format| {
+            load_previous_prices::<MockItem>(&dir, "mock", DATE, format, &HashMap::new())
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            load(OutputFormat::Csv),
+            HashMap::from([("1".into(), Some(1.0))])
+        );
+        assert_eq!(
+            load(OutputFormat::Jsonl),
+            HashMap::from([
+                ("1".into(), Some(4.2)),
+                ("2".into(), None),
+                ("3".into(), Some(3.5))
+            ])
+        );
+        assert_eq!(
+            load(OutputFormat::Json),
+            HashMap::from([("1".into(), Some(5.5))])
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_all_fail_fast_aborts_running_crawlers() {
+        let dir = temp_dir("fail-fast");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_out_path = dir.join("out");
+        std::fs::write(&file_out_path, "").unwrap();
+        let slow_out_path = dir.join("slow");
+        let slow_spider = MockSpider::new(
+            "slow",
+            "http://mock",
+            vec![(
+                "a",
+                MockResponse::ok(vec![MockItem::new(1, Some("x"))])
+                    .with_delay(Duration::from_millis(500)),
+            )],
+            0,
+        );
+        let cancel = CancellationToken::new();
+        let crawlers: Vec<Box<dyn BoxedCrawler>> = vec![
+            Box::new(Crawler::new(
+                slow_spider,
+                &test_settings(&slow_out_path),
+                DATE,
+                cancel.clone(),
+            )),
+            Box::new(Crawler::new(
+                mock_spider(),
+                &test_settings(&file_out_path),
+                DATE,
+                cancel.clone(),
+            )),
+        ];
+        let start = Instant::now();
+        let res = process_all(crawlers, 2, Duration::ZERO, true, &cancel).await;
+        assert!(matches!(res, Err(CrawlerError::OutPathNoDir(_))));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        sleep(Duration::from_millis(1_000)).await;
+        assert!(!slow_out_path.join(format!("slow_{}.csv", DATE)).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_spider_report_keeps_its_name_and_cancellation() {
+        let dir = temp_dir("failed-report");
+        // A non-empty directory where the output file goes makes the write fail
+        let blocker = dir.join(format!("mock_{}.csv", DATE));
+        std::fs::create_dir_all(&blocker).unwrap();
+        std::fs::write(blocker.join("blocker"), "").unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let crawler = Crawler::new(mock_spider(), &test_settings(&dir), DATE, cancel);
+        let report = crawler.process().await.unwrap();
+        assert_eq!(report.spider, "mock");
+        assert!(report.cancelled);
+        assert_eq!(report.items, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_all_rejects_an_empty_buffer() {
+        let cancel = CancellationToken::new();
+        let crawlers: Vec<Box<dyn BoxedCrawler>> = vec![Box::new(Crawler::new(
+            mock_spider(),
+            &test_settings(&temp_dir("empty-buffer")),
+            DATE,
+            cancel.clone(),
+        ))];
+        let res = process_all(crawlers, 0, Duration::ZERO, false, &cancel).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn save_items_incremental_counts_appended_and_updated_rows() {
+        let dir = temp_dir("incremental");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mock.csv");
+        // The mock items have no price, their name stands in for it
+        let column_names = HashMap::from([("price".to_string(), "name".to_string())]);
+        let item = |id, name| MockItem::new(id, Some(name));
+        let items = vec![item(1, "x"), item(2, "y")];
+        let n = save_items_incremental(&path, "20231231", items, &column_names).unwrap();
+        assert_eq!(n, 2);
+        let items = vec![item(1, "x"), item(2, "z"), item(3, "w")];
+        let n = save_items_incremental(&path, DATE, items, &column_names).unwrap();
+        assert_eq!(n, 3);
+        let rows = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert_eq!(rows, 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_creates_nested_out_path() {
+        let dir = temp_dir("nested-out-path");
+        let out_path = dir.join("data").join("prices").join("2024");
+        let crawler = Crawler::new(
+            mock_spider(),
+            &test_settings(&out_path),
+            DATE,
+            CancellationToken::new(),
+        );
+        let report = crawler.process().await.unwrap();
+        assert_eq!(report.items, 2);
+        assert!(out_path.join(format!("mock_{}.csv", DATE)).is_file());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn process_rejects_file_out_path() {
+        let dir = temp_dir("file-out-path");
+        std::fs::create_dir_all(&dir).unwrap();
+        let out_path = dir.join("out");
+        std::fs::write(&out_path, "").unwrap();
+        let crawler = Crawler::new(
+            mock_spider(),
+            &test_settings(&out_path),
+            DATE,
+            CancellationToken::new(),
+        );
+        let res = crawler.process().await;
+        assert!(matches!(res, Err(CrawlerError::OutPathNoDir(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
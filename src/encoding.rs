@@ -0,0 +1,59 @@
+use encoding_rs::{EncoderResult, Encoding};
+use std::io::{self, Write};
+
+/// Writer that transcodes the received UTF-8 bytes into `encoding`
+/// Characters that can't be represented are replaced by `?`
+pub struct TranscodingWriter<W: Write> {
+    inner: W,
+    encoder: encoding_rs::Encoder,
+    /// Incomplete UTF-8 sequence from the previous write
+    pending: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> TranscodingWriter<W> {
+    pub fn new(inner: W, encoding: &'static Encoding) -> Self {
+        Self {
+            inner,
+            encoder: encoding.new_encoder(),
+            pending: Vec::new(),
+            buffer: vec![0; 4096],
+        }
+    }
+
+    fn encode(&mut self, mut text: &str) -> io::Result<()> {
+        loop {
+            let (result, read, written) =
+                self.encoder
+                    .encode_from_utf8_without_replacement(text, &mut self.buffer, false);
+            self.inner.write_all(&self.buffer[..written])?;
+            text = &text[read..];
+            match result {
+                EncoderResult::InputEmpty => return Ok(()),
+                EncoderResult::OutputFull => {}
+                EncoderResult::Unmappable(_) => self.inner.write_all(b"?")?,
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for TranscodingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        let valid_up_to = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        let pending = std::mem::take(&mut self.pending);
+        let (valid, rest) = pending.split_at(valid_up_to);
+        // Safe to unwrap: validated above
+        self.encode(std::str::from_utf8(valid).unwrap())?;
+        self.pending = rest.to_vec();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,194 @@
+use crate::configuration::{HttpConfig, Settings};
+use anyhow::Context;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ACCEPT_LANGUAGE},
+    Client, Proxy, Response,
+};
+use std::time::Duration;
+
+/// Builds a http client using the settings on `config`, sending `accept_language` if given
+pub fn build_client(config: &HttpConfig, accept_language: Option<&str>) -> anyhow::Result<Client> {
+    let mut builder = Client::builder().danger_accept_invalid_certs(config.accept_invalid_certs);
+    if let Some(accept_language) = accept_language {
+        let value = HeaderValue::from_str(accept_language).context("Invalid accept_language")?;
+        builder = builder.default_headers(HeaderMap::from_iter([(ACCEPT_LANGUAGE, value)]));
+    }
+    if let Some(user_agent) = &config.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    if let Some(timeout_secs) = config.timeout_secs {
+        builder = builder.timeout(Duration::from_secs(timeout_secs));
+    }
+    if let Some(proxy) = &config.proxy {
+        let mut proxy = Proxy::all(proxy).context("Invalid proxy")?;
+        if let Some(credentials) = &config.proxy_credentials {
+            let password = credentials
+                .password
+                .resolve()
+                .context("Failed to get the proxy password")?;
+            proxy = proxy.basic_auth(&credentials.username, &password);
+        }
+        builder = builder.proxy(proxy);
+    }
+    builder.build().context("Failed to build http client")
+}
+
+/// Gets the http client shared by all the features using `settings`, built from them on the
+/// first call. Other `Settings` get their own client
+pub fn shared_client(settings: &Settings) -> anyhow::Result<Client> {
+    if let Some(client) = settings.http_client.get() {
+        return Ok(client.clone());
+    }
+    let client = build_client(&settings.http, settings.accept_language.as_deref())?;
+    Ok(settings.http_client.get_or_init(|| client).clone())
+}
+
+/// Reads the body of `response` as text, failing as soon as it exceeds `max_bytes`
+/// (see [`HttpConfig::max_body_bytes`]) instead of downloading the rest
+pub async fn read_text(mut response: Response, max_bytes: Option<usize>) -> anyhow::Result<String> {
+    let max_bytes = match max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => {
+            return response
+                .text()
+                .await
+                .context("Failed to read response body")
+        }
+    };
+    if let Some(length) = response.content_length() {
+        if length > max_bytes as u64 {
+            anyhow::bail!(
+                "Response body of {} bytes exceeds {} bytes",
+                length,
+                max_bytes
+            );
+        }
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("Failed to read response body")?
+    {
+        body.extend_from_slice(&chunk);
+        if body.len() > max_bytes {
+            anyhow::bail!("Response body exceeds {} bytes", max_bytes);
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::tests::test_settings;
+    use std::path::Path;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        time::sleep,
+    };
+
+    /// Serves every connection with the `head` of a response followed by `body_len` bytes,
+    /// after waiting `delay`. Returns the server url
+    async fn serve(head: &'static str, body_len: usize, delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request).await;
+                    sleep(delay).await;
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let chunk = [b'a'; 1024];
+                    let mut written = 0;
+                    while written < body_len {
+                        let n = chunk.len().min(body_len - written);
+                        if stream.write_all(&chunk[..n]).await.is_err() {
+                            return;
+                        }
+                        written += n;
+                    }
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn client(timeout_secs: Option<u64>) -> Client {
+        let config = HttpConfig {
+            timeout_secs,
+            ..Default::default()
+        };
+        build_client(&config, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn read_text_within_limit() {
+        let url = serve(
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n",
+            100,
+            Duration::ZERO,
+        )
+        .await;
+        let response = client(None).get(url).send().await.unwrap();
+        assert_eq!(read_text(response, Some(100)).await.unwrap().len(), 100);
+    }
+
+    #[tokio::test]
+    async fn read_text_rejects_large_content_length() {
+        let url = serve(
+            "HTTP/1.1 200 OK\r\nContent-Length: 1048576\r\nConnection: close\r\n\r\n",
+            1 << 20,
+            Duration::ZERO,
+        )
+        .await;
+        let response = client(None).get(url).send().await.unwrap();
+        let e = read_text(response, Some(1024)).await.unwrap_err();
+        assert!(e.to_string().contains("exceeds 1024 bytes"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn read_text_stops_large_body_without_content_length() {
+        let url = serve(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n",
+            1 << 20,
+            Duration::ZERO,
+        )
+        .await;
+        let response = client(None).get(url).send().await.unwrap();
+        let e = read_text(response, Some(1024)).await.unwrap_err();
+        assert!(e.to_string().contains("exceeds 1024 bytes"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn slow_server_times_out() {
+        let url = serve(
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            0,
+            Duration::from_secs(3),
+        )
+        .await;
+        let e = client(Some(1)).get(url).send().await.unwrap_err();
+        assert!(e.is_timeout(), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn shared_client_follows_its_settings() {
+        let url = serve(
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            0,
+            Duration::from_secs(3),
+        )
+        .await;
+        let settings = test_settings(Path::new("output_test"));
+        let mut timeout_settings = test_settings(Path::new("output_test"));
+        timeout_settings.http.timeout_secs = Some(1);
+        shared_client(&settings).unwrap();
+        let client = shared_client(&timeout_settings).unwrap();
+        let e = client.get(url).send().await.unwrap_err();
+        assert!(e.is_timeout(), "{}", e);
+    }
+}
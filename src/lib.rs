@@ -1,5 +1,9 @@
 pub mod configuration;
 pub mod crawler;
+pub mod encoding;
+pub mod http;
+pub mod merge;
+pub mod sheets;
 pub mod spiders;
 
 use chrono::{FixedOffset, Utc};
@@ -21,11 +25,24 @@ fn error_chain_fmt(
 /// Gets current date on "%Y%m%d" format for Peru timezone
 pub fn get_peru_date() -> String {
     Utc::now()
-        .with_timezone(&FixedOffset::west(5 * 3600))
+        .with_timezone(&FixedOffset::west_opt(5 * 3600).unwrap())
         .format("%Y%m%d")
         .to_string()
 }
 
+/// Gets a file name friendly version of the last non-empty `/` segment of `x`
+/// (eg: "https://www.metro.pe/Frutas y Verduras/" -> "frutas-y-verduras")
+pub fn last_segment_slug(x: &str) -> String {
+    x.split('/')
+        .rfind(|x| !x.trim().is_empty())
+        .unwrap_or("")
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
 pub fn spawn_blocking_with_tracing<F, R>(f: F) -> JoinHandle<R>
 where
     F: FnOnce() -> R + Send + 'static,
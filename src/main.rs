@@ -1,16 +1,61 @@
-use futures::future::join_all;
+use anyhow::Context;
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
 use peru_prices::{
-    configuration::get_configuration,
-    crawler::Crawler,
-    spiders::{InfiniteScrollingSpider, MultipageSpider},
+    configuration::{get_configuration, OutputFormat, Settings},
+    crawler::{
+        process_all, save_combined, save_merged, save_spider_items, BoxedCrawler, CrawlReport,
+        Crawler,
+    },
+    get_peru_date,
+    merge::merge_catalog_group,
+    spiders::{
+        join_url, CommonRow, InfiniteScrollingSpider, MultipageSpider, Phase, Spider, SpiderError,
+        Warnings,
+    },
+};
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
 };
 use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::{
     prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
 
+#[derive(Parser)]
+#[command(about = "Scrape product prices from peruvian supermarkets")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Date used on the output files (YYYYMMDD), defaults to today in Peru
+    #[arg(long, value_parser = parse_date)]
+    date: Option<String>,
+    /// Overrides the configured output format (csv, json or jsonl)
+    #[arg(long)]
+    output_format: Option<OutputFormat>,
+    /// Print the time spent on each phase of the crawl per spider at the end of the run
+    #[arg(long)]
+    profile: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print how many elements each selector of a spider matches on `url`
+    CheckSelectors { spider: String, url: String },
+    /// Load and validate the configuration without crawling
+    ValidateConfig,
+    /// Extract the items of the html files saved on `html_dir` without a browser, each file
+    /// stem is taken as the subroute it was saved from
+    Reparse { spider: String, html_dir: PathBuf },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
@@ -19,33 +64,375 @@ async fn main() -> anyhow::Result<()> {
         )
         .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
         .init();
-    let configuration = get_configuration().expect("Failed to get configuration");
+    if let Some(Command::ValidateConfig) = cli.command {
+        return validate_config();
+    }
+    let mut configuration = get_configuration().expect("Failed to get configuration");
+    if let Some(output_format) = cli.output_format {
+        configuration.output_format = output_format;
+    }
+    if cli.profile {
+        configuration.profile = true;
+    }
+    if let Err(errors) = configuration.validate() {
+        anyhow::bail!("Invalid configuration:\n- {}", errors.join("\n- "));
+    }
+    let date = cli.date.unwrap_or_else(get_peru_date);
+    match cli.command {
+        Some(Command::CheckSelectors { spider, url }) => {
+            return check_selectors(&configuration, &spider, &url).await;
+        }
+        Some(Command::Reparse { spider, html_dir }) => {
+            return reparse(&configuration, &spider, &html_dir, &date).await;
+        }
+        _ => {}
+    }
     tracing::info!("Initializing scrappers...");
     tracing::debug!("{:#?}", configuration);
     let now = Instant::now();
 
-    let metro_spider =
-        InfiniteScrollingSpider::from_settings(&configuration, &configuration.metro).await?;
-    let wong_spider =
-        InfiniteScrollingSpider::from_settings(&configuration, &configuration.wong).await?;
-    let plaza_vea_spider =
-        MultipageSpider::from_settings(&configuration, &configuration.plaza_vea).await?;
-
-    let tasks = vec![
-        tokio::spawn(Crawler::new(metro_spider, &configuration).process()),
-        tokio::spawn(Crawler::new(wong_spider, &configuration).process()),
-        tokio::spawn(Crawler::new(plaza_vea_spider, &configuration).process()),
-    ];
-
-    let n: usize = join_all(tasks).await.into_iter().map(|res| match res {
-        Ok(Ok(n)) => n,
+    let webdriver_urls = (0..3)
+        .map(|i| configuration.webdriver_endpoint(i))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                tracing::warn!("Received Ctrl-C, saving partial results...");
+                cancel.cancel();
+            }
+        }
+    });
+
+    let crawlers = [
+        if is_enabled(
+            &configuration.metro.common.name,
+            configuration.metro.common.enabled,
+        ) {
+            build_crawler(
+                InfiniteScrollingSpider::from_settings(
+                    &configuration,
+                    &configuration.metro,
+                    Some(&webdriver_urls[0]),
+                )
+                .await,
+                &configuration.metro.common.name,
+                &configuration,
+                &date,
+                &cancel,
+            )
+        } else {
+            None
+        },
+        if is_enabled(
+            &configuration.wong.common.name,
+            configuration.wong.common.enabled,
+        ) {
+            build_crawler(
+                InfiniteScrollingSpider::from_settings(
+                    &configuration,
+                    &configuration.wong,
+                    Some(&webdriver_urls[1]),
+                )
+                .await,
+                &configuration.wong.common.name,
+                &configuration,
+                &date,
+                &cancel,
+            )
+        } else {
+            None
+        },
+        if is_enabled(
+            &configuration.plaza_vea.common.name,
+            configuration.plaza_vea.common.enabled,
+        ) {
+            build_crawler(
+                MultipageSpider::from_settings(
+                    &configuration,
+                    &configuration.plaza_vea,
+                    Some(&webdriver_urls[2]),
+                )
+                .await,
+                &configuration.plaza_vea.common.name,
+                &configuration,
+                &date,
+                &cancel,
+            )
+        } else {
+            None
+        },
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+    if crawlers.is_empty() {
+        anyhow::bail!("No spider was enabled or could be built");
+    }
+
+    let reports = process_all(
+        crawlers,
+        configuration.crawlers_buffer_size,
+        Duration::from_millis(configuration.inter_spider_delay_milis),
+        configuration.fail_fast,
+        &cancel,
+    )
+    .await?;
+    let n: usize = reports.iter().map(|report| report.items).sum();
+    let count_anomalies: usize = reports.iter().map(|report| report.count_anomalies).sum();
+    let rejected: usize = reports.iter().map(|report| report.rejected).sum();
+    if configuration.profile {
+        print_profile(&reports);
+    }
+
+    let rows = reports
+        .into_iter()
+        .flat_map(|report| report.combined)
+        .collect::<Vec<_>>();
+    merge_catalog_groups(&configuration, &date, &rows).await;
+    if configuration.write_combined {
+        match save_combined(&configuration, &date, rows).await {
+            Ok(n) => tracing::info!("Wrote {} items to the combined output", n),
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to write combined output.")
+            }
+        }
+    }
+
+    tracing::info!(
+        "Finished in {:?} ({} items, {} rejected)",
+        now.elapsed(),
+        n,
+        rejected
+    );
+    if configuration.fail_on_count_anomaly && count_anomalies > 0 {
+        anyhow::bail!(
+            "{} subroutes with an item count out of the expected range",
+            count_anomalies
+        );
+    }
+    Ok(())
+}
+
+/// Prints the seconds spent on each phase by every spider
+fn print_profile(reports: &[CrawlReport]) {
+    print!("{:<20}", "spider");
+    Phase::ALL.iter().for_each(|phase| print!(" {:>8}", phase));
+    println!();
+    for report in reports.iter().filter(|report| !report.profile.is_empty()) {
+        print!("{:<20}", report.spider);
+        Phase::ALL.iter().for_each(|phase| {
+            let secs = report
+                .profile
+                .iter()
+                .find(|(x, _)| x == phase)
+                .map(|(_, duration)| duration.as_secs_f64())
+                .unwrap_or_default();
+            print!(" {:>8.2}", secs);
+        });
+        println!();
+    }
+}
+
+/// Validates that `x` is a date on YYYYMMDD format
+fn parse_date(x: &str) -> Result<String, String> {
+    NaiveDate::parse_from_str(x, "%Y%m%d")
+        .map(|_| x.to_string())
+        .map_err(|e| format!("Invalid date {:?}, expected YYYYMMDD: {}", x, e))
+}
+
+/// Builds the crawler of a successfully built spider, logs the error otherwise
+fn build_crawler<T>(
+    spider: Result<T, SpiderError>,
+    name: &str,
+    configuration: &Settings,
+    date: &str,
+    cancel: &CancellationToken,
+) -> Option<Box<dyn BoxedCrawler>>
+where
+    T: Spider + Send + Sync + Display + 'static,
+{
+    match spider {
+        Ok(spider) => Some(Box::new(Crawler::new(
+            spider,
+            configuration,
+            date,
+            cancel.clone(),
+        ))),
         Err(e) => {
-            tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to execute task.");
-            0
+            tracing::error!(error.cause_chain = ?e, error.message = %e, spider = %name, "Failed to build spider.");
+            None
         }
-        _ => 0,
-    }).sum();
+    }
+}
 
-    tracing::info!("Finished in {:?} ({} items)", now.elapsed(), n);
+/// Returns `enabled`, logging when the spider is skipped
+fn is_enabled(name: &str, enabled: bool) -> bool {
+    if !enabled {
+        tracing::info!(spider = %name, "Spider disabled, skipping.");
+    }
+    enabled
+}
+
+/// Merges the common `rows` of each group in `catalog_groups` and writes them
+async fn merge_catalog_groups(configuration: &Settings, date: &str, rows: &[CommonRow]) {
+    for group in &configuration.catalog_groups {
+        let merged = match merge_catalog_group(rows, group) {
+            Ok(merged) => merged,
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to merge catalog group.");
+                continue;
+            }
+        };
+        match save_merged(configuration, group, date, merged).await {
+            Ok(n) => tracing::info!("Merged {} unique items of {:?}", n, group),
+            Err(e) => {
+                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to write catalog group.")
+            }
+        }
+    }
+}
+
+/// Loads and validates the configuration, printing a summary or the problems found
+fn validate_config() -> anyhow::Result<()> {
+    let configuration = get_configuration().context("Failed to load configuration")?;
+    if let Err(errors) = configuration.validate() {
+        errors.iter().for_each(|e| println!("- {}", e));
+        anyhow::bail!("Invalid configuration ({} errors)", errors.len());
+    }
+    let subroutes = configuration.metro.common.subroutes.len()
+        + configuration.wong.common.subroutes.len()
+        + configuration.plaza_vea.common.subroutes.len();
+    println!(
+        "\x1b[32mOK\x1b[0m: {} spiders, {} subroutes",
+        configuration.spider_names().len(),
+        subroutes
+    );
+    Ok(())
+}
+
+/// Prints the number of elements matched by each selector of `spider` on `url`
+async fn check_selectors(configuration: &Settings, spider: &str, url: &str) -> anyhow::Result<()> {
+    let counts = if spider == configuration.metro.common.name {
+        InfiniteScrollingSpider::from_settings(
+            configuration,
+            &configuration.metro,
+            Some(&configuration.webdriver_endpoint(0)?),
+        )
+        .await?
+        .validate_selectors_against(url)
+        .await?
+    } else if spider == configuration.wong.common.name {
+        InfiniteScrollingSpider::from_settings(
+            configuration,
+            &configuration.wong,
+            Some(&configuration.webdriver_endpoint(0)?),
+        )
+        .await?
+        .validate_selectors_against(url)
+        .await?
+    } else if spider == configuration.plaza_vea.common.name {
+        MultipageSpider::from_settings(
+            configuration,
+            &configuration.plaza_vea,
+            Some(&configuration.webdriver_endpoint(0)?),
+        )
+        .await?
+        .validate_selectors_against(url)
+        .await?
+    } else {
+        anyhow::bail!("Unknown spider: {}", spider);
+    };
+    println!("{:<40} {:>8}", "selector", "matches");
+    counts
+        .into_iter()
+        .for_each(|(selector, n)| println!("{:<40} {:>8}", selector, n));
     Ok(())
 }
+
+/// Extracts the items of `spider` from the html files on `html_dir` and saves them
+async fn reparse(
+    configuration: &Settings,
+    spider: &str,
+    html_dir: &Path,
+    date: &str,
+) -> anyhow::Result<()> {
+    let n = if spider == configuration.metro.common.name {
+        let spider =
+            InfiniteScrollingSpider::from_settings(configuration, &configuration.metro, None)
+                .await?;
+        reparse_spider(configuration, &spider, html_dir, date).await?
+    } else if spider == configuration.wong.common.name {
+        let spider =
+            InfiniteScrollingSpider::from_settings(configuration, &configuration.wong, None)
+                .await?;
+        reparse_spider(configuration, &spider, html_dir, date).await?
+    } else if spider == configuration.plaza_vea.common.name {
+        let spider =
+            MultipageSpider::from_settings(configuration, &configuration.plaza_vea, None).await?;
+        reparse_spider(configuration, &spider, html_dir, date).await?
+    } else {
+        anyhow::bail!("Unknown spider: {}", spider);
+    };
+    tracing::info!("Extracted {} items from {:?}", n, html_dir);
+    Ok(())
+}
+
+async fn reparse_spider<T: Spider>(
+    configuration: &Settings,
+    spider: &T,
+    html_dir: &Path,
+    date: &str,
+) -> anyhow::Result<usize> {
+    let mut paths = std::fs::read_dir(html_dir)
+        .with_context(|| format!("Failed to read {:?}", html_dir))?
+        .map(|entry| entry.map(|x| x.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to read dir entry")?;
+    paths.retain(|path| path.extension().is_some_and(|x| x == "html"));
+    paths.sort();
+    let warnings = Warnings::default();
+    let mut items = HashSet::new();
+    for path in paths {
+        let document = tokio::fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        let subroute = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .context("Invalid file name")?;
+        let url = join_url(spider.base_url(), subroute);
+        let found = spider.extract_items(&document, &url, &warnings);
+        tracing::info!("Found {} items on {:?}", found.len(), path);
+        items.extend(found);
+    }
+    let items = items.into_iter().collect::<Vec<_>>();
+    Ok(save_spider_items(configuration, spider.name(), date, items).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_accepts_valid_dates() {
+        assert_eq!(parse_date("20240131").unwrap(), "20240131");
+        assert_eq!(parse_date("20240229").unwrap(), "20240229");
+    }
+
+    #[test]
+    fn parse_date_rejects_malformed_dates() {
+        for x in [
+            "",
+            "2024-01-31",
+            "240131",
+            "20240230",
+            "20241301",
+            "2024013a",
+        ] {
+            assert!(parse_date(x).is_err(), "{:?} should be invalid", x);
+        }
+    }
+}
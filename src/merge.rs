@@ -0,0 +1,86 @@
+use crate::{error_chain_fmt, spiders::CommonRow};
+use std::collections::HashSet;
+
+#[derive(thiserror::Error)]
+pub enum MergeError {
+    #[error("No items found for the spiders of the catalog group {0:?}")]
+    NoItems(Vec<String>),
+}
+
+impl std::fmt::Debug for MergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Merges the common rows (see [`CommonRow`]) of a group of spiders sharing the same
+/// catalog, keeping the first row of each id and zone, in the order of `spiders`
+/// The rows of the spiders outside the group are ignored
+pub fn merge_catalog_group(
+    rows: &[CommonRow],
+    spiders: &[String],
+) -> Result<Vec<CommonRow>, MergeError> {
+    let mut seen = HashSet::new();
+    let merged = spiders
+        .iter()
+        .flat_map(|spider| rows.iter().filter(move |row| &row.store == spider))
+        .filter(|row| seen.insert((&row.zone, &row.id)))
+        .cloned()
+        .collect::<Vec<_>>();
+    if merged.is_empty() {
+        return Err(MergeError::NoItems(spiders.to_vec()));
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(store: &str, zone: Option<&str>, id: &str, price: f64) -> CommonRow {
+        CommonRow {
+            store: store.to_string(),
+            zone: zone.map(String::from),
+            id: id.to_string(),
+            price: Some(price),
+            ..Default::default()
+        }
+    }
+
+    fn group(spiders: &[&str]) -> Vec<String> {
+        spiders.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_the_first_row_of_each_id_in_group_order() {
+        let rows = [
+            row("wong", None, "1", 4.5),
+            row("metro", None, "1", 4.2),
+            row("plaza_vea", None, "2", 9.9),
+            row("wong", None, "3", 5.5),
+        ];
+        let merged = merge_catalog_group(&rows, &group(&["metro", "wong"])).unwrap();
+        let merged = merged
+            .iter()
+            .map(|row| (row.store.as_str(), row.id.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(merged, [("metro", "1"), ("wong", "3")]);
+    }
+
+    #[test]
+    fn keeps_the_same_id_on_different_zones() {
+        let rows = [
+            row("metro", Some("lima"), "1", 4.2),
+            row("wong", Some("arequipa"), "1", 4.5),
+        ];
+        let merged = merge_catalog_group(&rows, &group(&["metro", "wong"])).unwrap();
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn fails_without_items_of_the_group() {
+        let rows = [row("plaza_vea", None, "1", 4.2)];
+        let res = merge_catalog_group(&rows, &group(&["metro", "wong"]));
+        assert!(matches!(res, Err(MergeError::NoItems(_))));
+    }
+}
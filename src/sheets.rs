@@ -0,0 +1,223 @@
+use crate::{configuration::GoogleSheetsSettings, crawler::get_headers};
+use anyhow::{anyhow, Context};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use reqwest::Client;
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use url::Url;
+
+const SHEETS_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
+const SHEETS_API_URL: &str = "https://sheets.googleapis.com/v4/spreadsheets/";
+
+/// Fields used from a service account json key
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    /// PKCS#8 key in PEM format
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// Writes rows to the tabs of a Google Sheet, authenticating as a service account
+#[derive(Debug, Clone)]
+pub struct SheetsExporter {
+    client: Client,
+    settings: GoogleSheetsSettings,
+}
+
+impl SheetsExporter {
+    pub fn new(client: Client, settings: GoogleSheetsSettings) -> Self {
+        Self { client, settings }
+    }
+
+    /// Replaces the content of the `sheet` tab (which must already exist) with `rows`,
+    /// the first one being the header
+    #[tracing::instrument(skip(self, rows), fields(rows = rows.len()))]
+    pub async fn replace_sheet(&self, sheet: &str, rows: Vec<Vec<String>>) -> anyhow::Result<()> {
+        let token = self.access_token().await?;
+        let range = format!("'{}'", sheet.replace('\'', "''"));
+        self.client
+            .post(self.values_url(&format!("{}:clear", range))?)
+            .bearer_auth(&token)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .context("Failed to clear sheet")?;
+        let mut url = self.values_url(&format!("{}:append", range))?;
+        url.query_pairs_mut().append_pair("valueInputOption", "RAW");
+        self.client
+            .post(url)
+            .bearer_auth(&token)
+            .json(&serde_json::json!({ "values": rows }))
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .context("Failed to append rows")?;
+        tracing::info!("Wrote {} rows to sheet {:?}", rows.len(), sheet);
+        Ok(())
+    }
+
+    /// `{SHEETS_API_URL}/{spreadsheet_id}/values/{range}`
+    fn values_url(&self, range: &str) -> anyhow::Result<Url> {
+        let mut url = Url::parse(SHEETS_API_URL).context("Invalid sheets api url")?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Invalid sheets api url"))?
+            .pop_if_empty()
+            .extend([self.settings.spreadsheet_id.as_str(), "values", range]);
+        Ok(url)
+    }
+
+    /// Exchanges a JWT signed with the service account key for an access token
+    async fn access_token(&self) -> anyhow::Result<String> {
+        let key = self
+            .settings
+            .service_account_key
+            .resolve()
+            .context("Failed to get the service account key")?;
+        let key = serde_json::from_str::<ServiceAccountKey>(&key)
+            .context("Invalid service account key")?;
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            iss: &key.client_email,
+            scope: SHEETS_SCOPE,
+            aud: &key.token_uri,
+            iat: now,
+            exp: now + 3600,
+        };
+        let assertion = sign_jwt(&claims, &key.private_key)?;
+        let response = self
+            .client
+            .post(&key.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .context("Failed to get access token")?
+            .json::<TokenResponse>()
+            .await
+            .context("Invalid access token response")?;
+        Ok(response.access_token)
+    }
+}
+
+/// Builds a RS256 JWT with `claims`, signed with the PEM `private_key`
+fn sign_jwt(claims: &Claims, private_key: &str) -> anyhow::Result<String> {
+    let der = private_key
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>();
+    let der = STANDARD
+        .decode(der.trim())
+        .context("Invalid private key encoding")?;
+    let key_pair =
+        RsaKeyPair::from_pkcs8(&der).map_err(|e| anyhow!("Invalid private key: {}", e))?;
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = URL_SAFE_NO_PAD
+        .encode(serde_json::to_vec(claims).context("Failed to serialize jwt claims")?);
+    let message = format!("{}.{}", header, claims);
+    let mut signature = vec![0; key_pair.public().modulus_len()];
+    key_pair
+        .sign(
+            &RSA_PKCS1_SHA256,
+            &SystemRandom::new(),
+            message.as_bytes(),
+            &mut signature,
+        )
+        .map_err(|_| anyhow!("Failed to sign jwt"))?;
+    Ok(format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature)))
+}
+
+/// Converts `items` to sheet rows: a header with their field names (renamed with
+/// `column_names`, in the same order as the csv columns) followed by one row per item,
+/// nested values are written as json
+pub fn sheet_rows<I: Serialize>(
+    items: &[I],
+    column_names: &HashMap<String, String>,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    let fields = match items.first() {
+        Some(item) => get_headers(item).context("Failed to get headers")?,
+        None => return Ok(Vec::new()),
+    };
+    let mut rows = Vec::with_capacity(items.len() + 1);
+    rows.push(
+        fields
+            .iter()
+            .map(|k| column_names.get(k).unwrap_or(k).clone())
+            .collect(),
+    );
+    for item in items {
+        let mut values = match serde_json::to_value(item).context("Failed to serialize item")? {
+            serde_json::Value::Object(values) => values,
+            value => anyhow::bail!("Expected an object item, got: {}", value),
+        };
+        rows.push(
+            fields
+                .iter()
+                .map(|k| match values.remove(k) {
+                    None | Some(serde_json::Value::Null) => String::new(),
+                    Some(serde_json::Value::String(x)) => x,
+                    Some(value) => value.to_string(),
+                })
+                .collect(),
+        );
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Item {
+        sku: &'static str,
+        name: &'static str,
+        brand: Option<&'static str>,
+        #[serde(serialize_with = "crate::spiders::serialize_list")]
+        promotions: Vec<String>,
+    }
+
+    #[test]
+    fn sheet_columns_follow_the_field_order() {
+        let items = [Item {
+            sku: "1",
+            name: "Leche Gloria 400g",
+            brand: None,
+            promotions: vec!["2x1".to_string(), "-30%".to_string()],
+        }];
+        let column_names = HashMap::from([("sku".to_string(), "id".to_string())]);
+        let rows = sheet_rows(&items, &column_names).unwrap();
+        assert_eq!(
+            rows,
+            [
+                vec!["id", "name", "brand", "promotions"],
+                vec!["1", "Leche Gloria 400g", "", r#"["2x1","-30%"]"#],
+            ]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use super::{SpiderError, SpiderItem, WarningKind, Warnings};
+use crate::{
+    configuration::{DetailsSettings, Settings},
+    http::{read_text, shared_client},
+};
+use anyhow::Context;
+use futures::{stream, StreamExt};
+use reqwest::Client;
+use scraper::{Html, Selector};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::sleep;
+
+/// Fetches the product detail pages of the items to extract their attributes
+/// (eg: a nutritional table), using plain http requests instead of the webdriver
+#[derive(Debug)]
+pub struct DetailScraper {
+    client: Client,
+    row_selector: Selector,
+    key_selector: Selector,
+    value_selector: Selector,
+    /// Detail pages fetched at the same time
+    concurrency: usize,
+    /// Wait after each detail page
+    delay: Duration,
+    /// Detail pages with a bigger body fail
+    max_body_bytes: Option<usize>,
+}
+
+impl DetailScraper {
+    pub fn new(
+        settings: &DetailsSettings,
+        client: Client,
+        delay: Duration,
+        max_body_bytes: Option<usize>,
+    ) -> Result<Self, SpiderError> {
+        let parse =
+            |x: &str| Selector::parse(x).map_err(|_| SpiderError::InvalidSelector(x.to_string()));
+        Ok(Self {
+            client,
+            row_selector: parse(&settings.row_selector)?,
+            key_selector: parse(&settings.key_selector)?,
+            value_selector: parse(&settings.value_selector)?,
+            concurrency: settings.concurrency.max(1),
+            delay,
+            max_body_bytes,
+        })
+    }
+
+    /// Fetches `uri` and extracts the key/value pairs of each attribute row
+    #[tracing::instrument(skip(self))]
+    pub async fn scrape_detail(&self, uri: &str) -> Result<HashMap<String, String>, SpiderError> {
+        let response = self
+            .client
+            .get(uri)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .context("Failed to fetch detail page")?;
+        let document = read_text(response, self.max_body_bytes)
+            .await
+            .context("Failed to read detail page")?;
+        let html = Html::parse_document(&document);
+        let text = |element: scraper::ElementRef, selector: &Selector| {
+            element
+                .select(selector)
+                .next()
+                .map(|x| x.text().collect::<Vec<_>>().join(" "))
+                .map(|x| x.split_whitespace().collect::<Vec<_>>().join(" "))
+                .filter(|x| !x.is_empty())
+        };
+        let attributes = html
+            .select(&self.row_selector)
+            .filter_map(|row| {
+                Some((
+                    text(row, &self.key_selector)?,
+                    text(row, &self.value_selector)?,
+                ))
+            })
+            .collect();
+        Ok(attributes)
+    }
+
+    /// Sets the attributes of the `items` with an uri, failed pages are logged and reported
+    /// on `warnings` leaving the item without attributes
+    pub async fn add_attributes<T: SpiderItem>(
+        &self,
+        items: &mut [T],
+        url: &str,
+        warnings: &Warnings,
+    ) {
+        let uris = items
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, item)| {
+                let uri = item.optional_field_mut("uri")?.clone()?;
+                Some((i, uri))
+            })
+            .collect::<Vec<_>>();
+        tracing::info!("Fetching {} detail pages", uris.len());
+        let results = stream::iter(uris)
+            .map(|(i, uri)| async move {
+                let res = self.scrape_detail(&uri).await;
+                sleep(self.delay).await;
+                (i, uri, res)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        for (i, uri, res) in results {
+            match res {
+                Ok(attributes) => items[i].set_attributes(attributes),
+                Err(e) => {
+                    tracing::error!(error.cause_chain = ?e, error.message = %e, uri = %uri, "Failed to scrape detail page.");
+                    warnings.push(url, WarningKind::ScrapeFailure, format!("{}: {}", uri, e));
+                }
+            }
+        }
+    }
+}
+
+/// Builds the [`DetailScraper`] of a spider if `fetch_details` is set, using the shared
+/// http client
+pub fn detail_scraper(
+    settings: &Settings,
+    fetch_details: bool,
+    details: Option<&DetailsSettings>,
+) -> Result<Option<DetailScraper>, SpiderError> {
+    let details = match (fetch_details, details) {
+        (true, Some(details)) => details,
+        (true, None) => {
+            tracing::warn!("fetch_details is set without details settings, ignoring it");
+            return Ok(None);
+        }
+        (false, _) => return Ok(None),
+    };
+    let client = shared_client(settings)?;
+    DetailScraper::new(
+        details,
+        client,
+        Duration::from_millis(settings.delay_milis),
+        settings.http.max_body_bytes,
+    )
+    .map(Some)
+}
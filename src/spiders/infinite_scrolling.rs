@@ -1,20 +1,141 @@
-use super::{Spider, SpiderError};
+use super::{
+    allowed_hosts, apply_zone, check_blocked, check_host, check_item_count, close_client,
+    compose_price, connect_client, count_matches, detail_scraper, embedded_json_items, extract_ean,
+    field_extractor, html_preprocessor, is_excluded, item_count_estimate, leave_frame,
+    limit_source, lock_client, log_item_error, navigate, parse_optional_selector,
+    parse_prefixed_price, parse_quantity, parse_rating, parse_reviews, parse_selectors,
+    preprocess_html, save_screenshot, select_texts, select_with_fallback, serialize_json_map,
+    serialize_list, split_brand_from_name, strip_price_prefix, CommonRow, DetailScraper,
+    ExtractionMode, FieldExtractor, LatencyTracker, Phase, Profiler, SchemaTracker, SourceLimit,
+    Spider, SpiderError, SpiderItem, WaitCondition, WarningKind, Warnings,
+};
 use crate::{
-    configuration::{InfiniteScrollingSpiderSettings, Settings},
-    spiders::parse_price,
+    configuration::{
+        BrandFromName, ExpectedCount, InfiniteScrollingSpiderSettings, Settings, ZoneConfig,
+    },
+    spiders::{filter_attrs, has_text, parse_price, synthesize_id, Price, PriceBounds},
 };
 use anyhow::{anyhow, Context};
 use async_trait::async_trait;
-use fantoccini::{Client, ClientBuilder, Locator};
-use scraper::{Html, Selector};
-use serde::Serialize;
+use fantoccini::Client;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fmt,
     hash::Hash,
+    path::{Path, PathBuf},
     time::Duration,
 };
-use tokio::{sync::Mutex, time::sleep};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Instant},
+};
+
+/// Wait between scrolls while fast-forwarding to a saved scroll position
+const FAST_FORWARD_DELAY: Duration = Duration::from_millis(200);
+/// Wait after each click of a `reveal_price_selector` button
+const REVEAL_PRICE_DELAY: Duration = Duration::from_millis(100);
+/// Finds the first `reveal_price_selector` button (arguments[0]) inside an item (arguments[1])
+/// not clicked yet, clicking it if arguments[2] is set, returns if there was one
+const REVEAL_PRICE_JS: &str = "const el = [...document.querySelectorAll(arguments[0])].find(
+         el => el.closest(arguments[1]) !== null && !el.dataset.priceRevealed
+     );
+     if (el === undefined) { return false; }
+     if (arguments[2]) {
+         el.dataset.priceRevealed = 'true';
+         el.click();
+     }
+     return true;";
+
+/// Whether the `clicks` made stopped at a non-zero `max_clicks`, so buttons may be left
+fn max_clicks_reached(clicks: usize, max_clicks: usize) -> bool {
+    max_clicks > 0 && clicks == max_clicks
+}
+
+/// Strategies to detect the end of an infinite scrolling page
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrollStrategy {
+    /// Stop after the scroll extent (page height or width) stays the same for
+    /// `scroll_checks` scrolls
+    #[default]
+    Height,
+    /// Stop after no new nodes are added to the items container for `quiet_ms`
+    MutationQuiet { quiet_ms: u64 },
+}
+
+/// Direction in which the page loads more items
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScrollAxis {
+    #[default]
+    Vertical,
+    /// Carousels loading more items when scrolled to the right
+    Horizontal,
+}
+
+/// Js statement declaring `container`, the element matching the selector on `arguments[0]`,
+/// or `null` to scroll the page
+const SCROLL_CONTAINER_JS: &str =
+    "const container = arguments[0] === null ? null : document.querySelector(arguments[0]);";
+
+impl ScrollAxis {
+    /// Js expression with the scrollable size of `container` (or the page) along the axis
+    pub fn extent_js(&self) -> &'static str {
+        match self {
+            Self::Vertical => {
+                "(container === null ? document.body.scrollHeight : container.scrollHeight)"
+            }
+            Self::Horizontal => {
+                "(container === null ? document.body.scrollWidth : container.scrollWidth)"
+            }
+        }
+    }
+
+    /// Js expression with the offset of `el` from the start of `container` (or the page)
+    /// along the axis
+    pub fn offset_js(&self) -> &'static str {
+        match self {
+            Self::Vertical => {
+                "Math.round(el.getBoundingClientRect().top + (container === null
+                    ? window.scrollY
+                    : container.scrollTop - container.getBoundingClientRect().top))"
+            }
+            Self::Horizontal => {
+                "Math.round(el.getBoundingClientRect().left + (container === null
+                    ? window.scrollX
+                    : container.scrollLeft - container.getBoundingClientRect().left))"
+            }
+        }
+    }
+
+    /// Js statement scrolling to the end of `container` (or the page) along the axis
+    pub fn scroll_to_end_js(&self) -> &'static str {
+        match self {
+            Self::Vertical => {
+                "if (container === null) { window.scrollTo(0, document.body.scrollHeight); }
+                 else { container.scrollTop = container.scrollHeight; }"
+            }
+            Self::Horizontal => {
+                "if (container === null) { window.scrollTo(document.body.scrollWidth, 0); }
+                 else { container.scrollLeft = container.scrollWidth; }"
+            }
+        }
+    }
+}
+
+/// How scrolling a page finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScrollEnd {
+    /// No more items were loaded, the end of the page was reached
+    Ended,
+    /// The scroll chunk deadline was reached before the end of the page
+    Deadline,
+    /// The scroll extent couldn't be read, the items loaded so far are kept
+    ReadFailed,
+}
 
 pub struct InfiniteScrollingSpider {
     name: String,
@@ -22,13 +143,114 @@ pub struct InfiniteScrollingSpider {
     subroutes: Vec<String>,
     css_locator: String,
     selector: Selector,
-    /// Mutex is used to lock multiple access to the webdriver
-    client: Mutex<Client>,
+    /// Mutex is used to lock multiple access to the webdriver, `None` on offline spiders
+    client: Mutex<Option<Client>>,
     delay: Duration,
     /// Delay after scroll down
     scroll_delay: Duration,
     /// Number of checks before finishing to scroll down
     scroll_checks: usize,
+    /// Wait after scrolling before reading the page
+    post_scroll_settle: Duration,
+    /// Extra wait after each subroute
+    cooldown_after_scroll: Duration,
+    /// Skip scrolling, only the items of the initial render are extracted
+    no_scroll: bool,
+    /// Retries of a failed height read, and the wait between them
+    height_retries: usize,
+    height_retry_delay: Duration,
+    scroll_strategy: ScrollStrategy,
+    scroll_axis: ScrollAxis,
+    /// If set, scroll this element instead of the page
+    scroll_container_selector: Option<String>,
+    /// If set, scroll this element into view instead of jumping to the bottom
+    sentinel_selector: Option<String>,
+    /// "Load more" button to click when present after scrolling
+    load_more_selector: Option<String>,
+    /// Button revealing the price of an item, clicked on every item before reading the page
+    reveal_price_selector: Option<String>,
+    reveal_price_max_clicks: usize,
+    /// Additional prices to extract as pairs of (attribute, label)
+    price_attrs: Vec<(String, String)>,
+    /// Attributes to collect from the matched elements, all if `None`
+    collect_attrs: Option<Vec<String>>,
+    zones: Vec<ZoneConfig>,
+    expected_counts: Vec<ExpectedCount>,
+    /// Element with the expected number of items
+    count_selector: Option<Selector>,
+    /// JavaScript to run after loading a page
+    pre_scrape_js: Option<String>,
+    /// Frame holding the items
+    iframe_selector: Option<String>,
+    price: Option<FieldExtractor>,
+    rating: Option<FieldExtractor>,
+    reviews: Option<FieldExtractor>,
+    seller: Option<FieldExtractor>,
+    time_budget: Option<Duration>,
+    /// Selectors to try in order when `selector` doesn't match any element
+    fallback_selectors: Vec<(String, Selector)>,
+    /// Regex replacements applied to the page sources before parsing
+    html_preprocess: Vec<(Regex, String)>,
+    /// Where to read the items from
+    extraction: ExtractionMode,
+    /// Where to save screenshots of subroutes without items
+    screenshot_dir: Option<PathBuf>,
+    /// Element only present on captcha/anti-bot pages
+    blocked_indicator_selector: Option<String>,
+    blocked_backoff: Option<Duration>,
+    /// Hosts the pages must be on, if verified
+    allowed_hosts: Option<Vec<String>>,
+    wait_condition: WaitCondition,
+    /// Query params removed from the item uris
+    strip_query_params: Vec<String>,
+    strip_all_query: bool,
+    /// Serialize prices with their currency
+    structured_price: bool,
+    /// Prices outside these bounds are dropped
+    price_bounds: PriceBounds,
+    /// Build an id for the items without one
+    synthesize_id: bool,
+    /// Take the missing brands from the start of the item names
+    brand_from_name: Option<BrandFromName>,
+    /// Values for the missing item fields
+    field_defaults: HashMap<String, String>,
+    /// Limit on the size of the page sources
+    source_limit: Option<SourceLimit>,
+    /// Page load latency, to scale the delay between subroutes
+    latency_tracker: Option<LatencyTracker>,
+    /// Time spent on each phase of the scrapes
+    profiler: Profiler,
+    /// Attribute names found on the item elements
+    schema_tracker: SchemaTracker,
+    /// Item attribute with the EAN barcode
+    ean_attr: String,
+    /// Promotion badges inside the items
+    promotions_selector: Option<Selector>,
+    /// Items to skip, matching it themselves or inside a match
+    exclude_selector: Option<Selector>,
+    /// Words before the price of variable-price items
+    price_prefixes: Vec<String>,
+    /// Integer and decimal parts of the price, used when it's missing
+    price_int_selector: Option<Selector>,
+    price_dec_selector: Option<Selector>,
+    /// Fetches the item attributes from their detail pages
+    details: Option<DetailScraper>,
+    /// Subroutes failing in a row before skipping the rest
+    max_consecutive_failures: Option<usize>,
+    /// Items across all subroutes before skipping the rest
+    max_total_items: Option<usize>,
+    /// Sidecar file with the scroll positions to resume from, if resumable
+    scroll_positions_path: Option<PathBuf>,
+    /// Time spent scrolling each subroute per run, if resumable
+    scroll_chunk: Option<Duration>,
+}
+
+/// Scroll position reached on a subroute, saved to resume scrolling on the next run
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ScrollPosition {
+    /// Extent reached along the scroll axis (the height on vertical pages)
+    height: i64,
+    items: usize,
 }
 
 impl fmt::Display for InfiniteScrollingSpider {
@@ -44,105 +266,668 @@ impl fmt::Display for InfiniteScrollingSpider {
 }
 
 impl InfiniteScrollingSpider {
-    #[allow(clippy::too_many_arguments)]
-    pub async fn new(
-        name: impl ToString,
-        base_url: impl ToString,
-        subroutes: Vec<impl ToString>,
-        css_selector: &str,
-        delay_milis: u64,
-        scroll_delay_milis: u64,
-        scroll_checks: usize,
-        headless: bool,
+    /// Builds the spider from the global `settings` and its `spider_settings`, connecting to
+    /// `webdriver_url` (offline if `None`), see [`MultipageSpider::from_settings`]
+    ///
+    /// [`MultipageSpider::from_settings`]: crate::spiders::MultipageSpider::from_settings
+    pub async fn from_settings(
+        settings: &Settings,
+        spider_settings: &InfiniteScrollingSpiderSettings,
+        webdriver_url: Option<&str>,
     ) -> Result<Self, SpiderError> {
-        let subroutes = subroutes.into_iter().map(|x| x.to_string()).collect();
+        let scrolling = &settings.infinite_scrolling;
+        let common = &spider_settings.common;
+        let css_selector = &common.selector;
         let selector = Selector::parse(css_selector)
             .map_err(|_| SpiderError::InvalidSelector(css_selector.to_string()))?;
+        let count_selector = parse_optional_selector(common.count_selector.as_deref())?;
+        let fallback_selectors = parse_selectors(&common.fallback_selectors)?;
+        let html_preprocess = html_preprocessor(&common.html_preprocess)?;
+        let price = field_extractor(common.price.as_ref())?;
+        let rating = field_extractor(common.rating.as_ref())?;
+        let reviews = field_extractor(common.reviews.as_ref())?;
+        let seller = field_extractor(common.seller.as_ref())?;
+        let promotions_selector = parse_optional_selector(common.promotions_selector.as_deref())?;
+        let exclude_selector = parse_optional_selector(common.exclude_selector.as_deref())?;
+        let price_int_selector = parse_optional_selector(common.price_int_selector.as_deref())?;
+        let price_dec_selector = parse_optional_selector(common.price_dec_selector.as_deref())?;
+        let details = detail_scraper(settings, common.fetch_details, common.details.as_ref())?;
+        let client = connect_client(settings, webdriver_url).await?;
 
-        let mut client = ClientBuilder::rustls();
-        if headless {
-            let mut caps = serde_json::map::Map::new();
-            let chrome_opts = serde_json::json!({ "args": ["--headless", "--disable-gpu"] });
-            caps.insert("goog:chromeOptions".to_string(), chrome_opts);
-            client.capabilities(caps);
-        }
-
-        let client = client
-            .connect("http://localhost:4444")
-            .await
-            .context("Error connecting to webdriver")?;
         Ok(Self {
-            name: name.to_string(),
-            base_url: base_url.to_string(),
-            subroutes,
-            css_locator: css_selector.to_string(),
+            name: common.name.clone(),
+            base_url: common.base_url.clone(),
+            subroutes: common.subroutes.clone(),
+            css_locator: css_selector.clone(),
             selector,
             client: Mutex::new(client),
-            delay: Duration::from_millis(delay_milis),
-            scroll_delay: Duration::from_millis(scroll_delay_milis),
-            scroll_checks,
+            delay: Duration::from_millis(settings.delay_milis),
+            scroll_delay: Duration::from_millis(scrolling.scroll_delay_milis),
+            scroll_checks: scrolling.scroll_checks,
+            post_scroll_settle: Duration::from_millis(scrolling.post_scroll_settle_milis),
+            no_scroll: scrolling.no_scroll,
+            height_retries: scrolling.height_retries,
+            height_retry_delay: Duration::from_millis(scrolling.height_retry_delay_milis),
+            cooldown_after_scroll: Duration::from_millis(scrolling.cooldown_after_scroll_milis),
+            scroll_strategy: spider_settings.scroll_strategy.clone(),
+            scroll_axis: spider_settings.scroll_axis,
+            scroll_container_selector: spider_settings.scroll_container_selector.clone(),
+            sentinel_selector: spider_settings.sentinel_selector.clone(),
+            load_more_selector: spider_settings.load_more_selector.clone(),
+            reveal_price_selector: spider_settings.reveal_price_selector.clone(),
+            reveal_price_max_clicks: spider_settings.reveal_price_max_clicks,
+            price_attrs: spider_settings.price_attrs.clone(),
+            collect_attrs: common.collect_attrs.clone(),
+            zones: common.zones.clone(),
+            expected_counts: common.expected_counts.clone(),
+            count_selector,
+            pre_scrape_js: common.pre_scrape_js.clone(),
+            iframe_selector: common.iframe_selector.clone(),
+            price,
+            rating,
+            reviews,
+            seller,
+            time_budget: common.time_budget_secs.map(Duration::from_secs),
+            fallback_selectors,
+            html_preprocess,
+            extraction: common.extraction.clone(),
+            screenshot_dir: settings
+                .screenshot_on_empty
+                .then(|| settings.out_path.join("screenshots")),
+            blocked_indicator_selector: common.blocked_indicator_selector.clone(),
+            blocked_backoff: common.blocked_backoff_secs.map(Duration::from_secs),
+            allowed_hosts: common
+                .verify_host
+                .then(|| allowed_hosts(&common.base_url, &common.extra_hosts)),
+            wait_condition: common.wait_condition,
+            strip_query_params: common.strip_query_params.clone(),
+            strip_all_query: common.strip_all_query,
+            structured_price: settings.structured_price,
+            price_bounds: settings.price_bounds,
+            synthesize_id: settings.synthesize_id,
+            brand_from_name: common.brand_from_name.clone(),
+            field_defaults: settings.field_defaults.clone(),
+            source_limit: settings.source_limit(),
+            latency_tracker: settings.latency_tracker(),
+            profiler: Profiler::new(settings.profile),
+            schema_tracker: SchemaTracker::new(settings.track_schema),
+            ean_attr: common.ean_attr.clone(),
+            promotions_selector,
+            exclude_selector,
+            price_prefixes: common.price_prefixes.clone(),
+            price_int_selector,
+            price_dec_selector,
+            details,
+            max_consecutive_failures: common.max_consecutive_failures,
+            max_total_items: common.max_total_items,
+            scroll_positions_path: spider_settings.resumable_scroll.then(|| {
+                settings
+                    .out_path
+                    .join(format!("{}_scroll_positions.json", common.name))
+            }),
+            scroll_chunk: spider_settings
+                .scroll_chunk_secs
+                .filter(|_| spider_settings.resumable_scroll)
+                .map(Duration::from_secs),
         })
     }
 
-    pub async fn from_settings(
-        settings: &Settings,
-        spider_settings: &InfiniteScrollingSpiderSettings,
-    ) -> Result<Self, SpiderError> {
-        Self::new(
-            spider_settings.name.clone(),
-            spider_settings.base_url.clone(),
-            spider_settings.subroutes.clone(),
-            &spider_settings.selector,
-            settings.delay_milis,
-            settings.infinite_scrolling.scroll_delay_milis,
-            settings.infinite_scrolling.scroll_checks,
-            settings.headless,
-        )
-        .await
+    /// Gets the extent with [`Self::read_scroll_extent`], retrying up to `height_retries`
+    /// times on failure
+    async fn get_scroll_extent(&self, client: &Client) -> Result<i64, SpiderError> {
+        let mut retries = 0;
+        loop {
+            match self.read_scroll_extent(client).await {
+                Ok(extent) => return Ok(extent),
+                Err(e) if retries < self.height_retries => {
+                    retries += 1;
+                    tracing::warn!(
+                        error.message = %e,
+                        "Failed to get scroll extent, retrying ({}/{}).",
+                        retries,
+                        self.height_retries
+                    );
+                    sleep(self.height_retry_delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    async fn get_height(&self, client: &Client) -> Result<i64, SpiderError> {
+    /// Reads the extent of the page (or `scroll_container_selector`) along `scroll_axis`, or
+    /// the sentinel offset when `sentinel_selector` is set (falling back to the extent once
+    /// the sentinel is gone)
+    async fn read_scroll_extent(&self, client: &Client) -> Result<i64, SpiderError> {
+        let axis = self.scroll_axis;
         let value = client
-            .execute("return document.body.scrollHeight", vec![])
+            .execute(
+                &format!(
+                    "{}
+                     const el = arguments[1] === null ? null : document.querySelector(arguments[1]);
+                     if (el === null) {{ return {}; }}
+                     return {};",
+                    SCROLL_CONTAINER_JS,
+                    axis.extent_js(),
+                    axis.offset_js()
+                ),
+                self.scroll_args(),
+            )
             .await
-            .context("Failed to get height")?;
-        let current_height = value
+            .context("Failed to get scroll extent")?;
+        let extent = value
             .as_i64()
             .ok_or_else(|| anyhow!("No number found: {}", value))?;
-        Ok(current_height)
+        Ok(extent)
     }
 
+    /// Scrolls to the end of the page (or `scroll_container_selector`) along `scroll_axis`,
+    /// or to the sentinel when set
     #[tracing::instrument(skip_all)]
     async fn scroll_down(&self, client: &Client) -> Result<(), SpiderError> {
         tracing::debug!("Scrolling down");
         client
-            .execute("window.scrollTo(0, document.body.scrollHeight);", vec![])
+            .execute(
+                &format!(
+                    "{}
+                     const el = arguments[1] === null ? null : document.querySelector(arguments[1]);
+                     if (el === null) {{ {} }}
+                     else {{ el.scrollIntoView(); }}",
+                    SCROLL_CONTAINER_JS,
+                    self.scroll_axis.scroll_to_end_js()
+                ),
+                self.scroll_args(),
+            )
             .await
             .context("Failed to scroll down")?;
         Ok(())
     }
 
+    /// Arguments of the scroll scripts: the container and sentinel selectors
+    fn scroll_args(&self) -> Vec<serde_json::Value> {
+        vec![
+            serde_json::json!(self.scroll_container_selector),
+            serde_json::json!(self.sentinel_selector),
+        ]
+    }
+
+    /// Clicks the "load more" button if it's present and enabled
+    /// Returns whether the button was clicked
+    #[tracing::instrument(skip_all)]
+    async fn click_load_more(&self, client: &Client) -> Result<bool, SpiderError> {
+        let load_more_selector = match &self.load_more_selector {
+            Some(load_more_selector) => load_more_selector,
+            None => return Ok(false),
+        };
+        let value = client
+            .execute(
+                "const el = document.querySelector(arguments[0]);
+                 if (el === null || el.disabled || el.offsetParent === null) { return false; }
+                 el.click();
+                 return true;",
+                vec![serde_json::json!(load_more_selector)],
+            )
+            .await
+            .context("Failed to click load more")?;
+        let clicked = value.as_bool().unwrap_or(false);
+        if clicked {
+            tracing::debug!("Clicked load more");
+            sleep(self.scroll_delay).await;
+        }
+        Ok(clicked)
+    }
+
+    /// Clicks the `reveal_price_selector` buttons inside the items, one at a time up to
+    /// `reveal_price_max_clicks`, returns the number of buttons clicked
+    #[tracing::instrument(skip_all)]
+    async fn reveal_prices(&self, client: &Client) -> Result<usize, SpiderError> {
+        let reveal_price_selector = match &self.reveal_price_selector {
+            Some(reveal_price_selector) => reveal_price_selector,
+            None => return Ok(0),
+        };
+        let reveal_button = |click: bool| async move {
+            let value = client
+                .execute(
+                    REVEAL_PRICE_JS,
+                    vec![
+                        serde_json::json!(reveal_price_selector),
+                        serde_json::json!(self.css_locator),
+                        serde_json::json!(click),
+                    ],
+                )
+                .await
+                .context("Failed to click reveal price")?;
+            Ok::<_, SpiderError>(value.as_bool().unwrap_or(false))
+        };
+        let mut clicks = 0;
+        while clicks < self.reveal_price_max_clicks {
+            if !reveal_button(true).await? {
+                break;
+            }
+            clicks += 1;
+            sleep(REVEAL_PRICE_DELAY).await;
+        }
+        if max_clicks_reached(clicks, self.reveal_price_max_clicks) && reveal_button(false).await? {
+            tracing::warn!(
+                "Reached reveal_price_max_clicks={} with prices left to reveal",
+                clicks
+            );
+        }
+        tracing::info!("Clicked {} reveal price buttons", clicks);
+        Ok(clicks)
+    }
+
+    /// Scrolls until no more items are loaded or the `deadline` is reached
+    #[tracing::instrument(skip_all)]
+    async fn scroll_to_end(
+        &self,
+        client: &Client,
+        deadline: Option<Instant>,
+    ) -> Result<ScrollEnd, SpiderError> {
+        match self.scroll_strategy {
+            ScrollStrategy::Height => self.scroll_until_same_height(client, deadline).await,
+            ScrollStrategy::MutationQuiet { quiet_ms } => {
+                self.scroll_until_mutation_quiet(client, quiet_ms, deadline)
+                    .await
+            }
+        }
+    }
+
     #[tracing::instrument(skip_all)]
-    async fn scroll_to_end(&self, client: &Client) -> Result<(), SpiderError> {
-        let mut height = self.get_height(client).await?;
-        tracing::debug!("height={}", height);
+    async fn scroll_until_same_height(
+        &self,
+        client: &Client,
+        deadline: Option<Instant>,
+    ) -> Result<ScrollEnd, SpiderError> {
+        let mut extent = match self.get_scroll_extent(client).await {
+            Ok(extent) => extent,
+            Err(e) => return Ok(stop_scroll(e)),
+        };
+        tracing::debug!("extent={}", extent);
         let mut i = 0;
         loop {
+            if is_past(deadline) {
+                tracing::info!("Scroll chunk finished at extent={}", extent);
+                return Ok(ScrollEnd::Deadline);
+            }
             self.scroll_down(client).await?;
             sleep(self.scroll_delay).await;
-            let new_height = self.get_height(client).await?;
-            tracing::debug!("new_height={}", new_height);
-            if new_height == height {
+            self.click_load_more(client).await?;
+            let new_extent = match self.get_scroll_extent(client).await {
+                Ok(new_extent) => new_extent,
+                Err(e) => return Ok(stop_scroll(e)),
+            };
+            tracing::debug!("new_extent={}", new_extent);
+            if new_extent == extent {
                 i += 1;
             }
             if i >= self.scroll_checks {
                 tracing::debug!("scroll_checks={}", i);
                 break;
             }
-            height = new_height;
+            extent = new_extent;
         }
+        Ok(ScrollEnd::Ended)
+    }
+
+    /// Scrolls quickly until the page reaches `target` extent, waiting `scroll_delay` only
+    /// while the page doesn't grow, gives up after `scroll_checks` scrolls without growing
+    #[tracing::instrument(skip(self, client))]
+    async fn fast_forward(&self, client: &Client, target: i64) -> Result<(), SpiderError> {
+        let mut extent = self.get_scroll_extent(client).await?;
+        let mut i = 0;
+        while extent < target && i < self.scroll_checks {
+            self.scroll_down(client).await?;
+            sleep(FAST_FORWARD_DELAY).await;
+            self.click_load_more(client).await?;
+            let new_extent = self.get_scroll_extent(client).await?;
+            if new_extent == extent {
+                i += 1;
+                sleep(self.scroll_delay).await;
+            } else {
+                i = 0;
+            }
+            extent = new_extent;
+        }
+        tracing::info!("Fast-forwarded to extent={}", extent);
         Ok(())
     }
+
+    /// Scrolls to the end of the page, or resuming from the saved position until the
+    /// scroll chunk ends when `scroll_positions_path` is set
+    async fn scroll(&self, client: &Client, url: &str) -> Result<ScrollEnd, SpiderError> {
+        let path = match &self.scroll_positions_path {
+            Some(path) => path,
+            None => return self.scroll_to_end(client, None).await,
+        };
+        if let Some(position) = load_scroll_positions(path).await.get(url) {
+            tracing::info!(
+                "Resuming scroll from height={} ({} items)",
+                position.height,
+                position.items
+            );
+            self.fast_forward(client, position.height).await?;
+        }
+        let deadline = self.scroll_chunk.map(|x| Instant::now() + x);
+        self.scroll_to_end(client, deadline).await
+    }
+
+    /// Saves the scroll `position` reached on `url`, or clears it when `None`
+    async fn save_scroll_position(&self, url: &str, position: Option<ScrollPosition>) {
+        let path = match &self.scroll_positions_path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut positions = load_scroll_positions(path).await;
+        match position {
+            Some(position) => positions.insert(url.to_string(), position),
+            None => positions.remove(url),
+        };
+        let res = match serde_json::to_string_pretty(&positions) {
+            Ok(content) => tokio::fs::write(path, content)
+                .await
+                .context("Failed to write scroll positions"),
+            Err(e) => Err(e).context("Failed to serialize scroll positions"),
+        };
+        if let Err(e) = res {
+            tracing::error!(error.cause_chain = ?e, error.message = %e, path = ?path, "Failed to save scroll position.");
+        }
+    }
+
+    /// Injects a MutationObserver on the items container that keeps track of the
+    /// last time a node was added
+    async fn observe_mutations(&self, client: &Client) -> Result<(), SpiderError> {
+        client
+            .execute(
+                "const item = document.querySelector(arguments[0]);
+                 const target = item === null ? document.body : item.parentElement;
+                 if (window.__lastMutationObserver) { window.__lastMutationObserver.disconnect(); }
+                 window.__lastMutation = Date.now();
+                 window.__lastMutationObserver = new MutationObserver(() => {
+                     window.__lastMutation = Date.now();
+                 });
+                 window.__lastMutationObserver.observe(target, { childList: true, subtree: true });",
+                vec![serde_json::json!(self.css_locator)],
+            )
+            .await
+            .context("Failed to inject MutationObserver")?;
+        Ok(())
+    }
+
+    /// Milliseconds since the last observed mutation
+    async fn get_quiet_time(&self, client: &Client) -> Result<u64, SpiderError> {
+        let value = client
+            .execute("return Date.now() - window.__lastMutation;", vec![])
+            .await
+            .context("Failed to get last mutation")?;
+        let quiet_ms = value
+            .as_u64()
+            .ok_or_else(|| anyhow!("No number found: {}", value))?;
+        Ok(quiet_ms)
+    }
+
+    #[tracing::instrument(skip(self, client))]
+    async fn scroll_until_mutation_quiet(
+        &self,
+        client: &Client,
+        quiet_ms: u64,
+        deadline: Option<Instant>,
+    ) -> Result<ScrollEnd, SpiderError> {
+        self.observe_mutations(client).await?;
+        loop {
+            if is_past(deadline) {
+                tracing::info!("Scroll chunk finished");
+                return Ok(ScrollEnd::Deadline);
+            }
+            self.scroll_down(client).await?;
+            sleep(self.scroll_delay).await;
+            self.click_load_more(client).await?;
+            let quiet_time = self.get_quiet_time(client).await?;
+            tracing::debug!("quiet_time={}", quiet_time);
+            if quiet_time >= quiet_ms {
+                break;
+            }
+        }
+        Ok(ScrollEnd::Ended)
+    }
+}
+
+impl InfiniteScrollingSpider {
+    /// Extracts the items from the page source
+    fn parse_items(
+        &self,
+        document: &str,
+        url: &str,
+        warnings: &Warnings,
+    ) -> Vec<InfiniteScrollingItem> {
+        let document = preprocess_html(document, &self.html_preprocess);
+        let html = Html::parse_document(&document);
+        let expected = self
+            .count_selector
+            .as_ref()
+            .and_then(|selector| item_count_estimate(&html, selector));
+        let elements = match &self.extraction {
+            ExtractionMode::Css => self.select_items(&html, url, warnings),
+            ExtractionMode::EmbeddedJson {
+                script_id,
+                items_path,
+                field_map,
+            } => {
+                let maps = log_item_error(
+                    embedded_json_items(&html, script_id, items_path, field_map),
+                    url,
+                    warnings,
+                )
+                .unwrap_or_default();
+                self.embedded_items(&maps, url, warnings)
+            }
+        }
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+        tracing::info!("Found {} elements", elements.len());
+        if let Some(expected) = expected {
+            check_item_count(expected, elements.len());
+        }
+        elements
+    }
+
+    /// Extracts the items from the elements matching the selector
+    fn select_items(
+        &self,
+        html: &Html,
+        url: &str,
+        warnings: &Warnings,
+    ) -> Vec<InfiniteScrollingItem> {
+        select_with_fallback(html, &self.selector, &self.fallback_selectors)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, element)| !is_excluded(*element, self.exclude_selector.as_ref()))
+            .filter_map(|(i, element)| {
+                self.schema_tracker.record(element);
+                let mut map =
+                    filter_attrs(element, self.collect_attrs.as_deref()).collect::<HashMap<_, _>>();
+                let id = self.missing_id(&map);
+                if let Some(id) = &id {
+                    map.insert("data-id", id);
+                }
+                let prefixed = self.remove_price_prefix(&mut map);
+                let raw_price = map.get("data-price").copied();
+                let mut item = log_item_error(
+                    InfiniteScrollingItem::try_from_attrs(map, &self.price_attrs)
+                        .map_err(|e| e.with_element_html(element)),
+                    url,
+                    warnings,
+                )?;
+                item.rank = Some(i + 1);
+                item.price_is_from = prefixed;
+                if let (None, Some(raw_price)) = (&item.price, raw_price) {
+                    warnings.push(
+                        url,
+                        WarningKind::PriceFallback,
+                        format!("Invalid price {:?} for item {:?}", raw_price, item.id),
+                    );
+                }
+                Some(self.transform(self.finalize_item(
+                    self.add_element_fields(item, element),
+                    url,
+                    warnings,
+                )))
+            })
+            .collect()
+    }
+
+    /// Builds the items from the attributes read from the json state embedded on the page,
+    /// see [`ExtractionMode`]
+    fn embedded_items(
+        &self,
+        maps: &[HashMap<String, String>],
+        url: &str,
+        warnings: &Warnings,
+    ) -> Vec<InfiniteScrollingItem> {
+        maps.iter()
+            .enumerate()
+            .filter_map(|(i, map)| {
+                let mut map = map
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect::<HashMap<_, _>>();
+                let id = self.missing_id(&map);
+                if let Some(id) = &id {
+                    map.insert("data-id", id);
+                }
+                let prefixed = self.remove_price_prefix(&mut map);
+                let mut item = log_item_error(
+                    InfiniteScrollingItem::try_from_attrs(map, &self.price_attrs),
+                    url,
+                    warnings,
+                )?;
+                item.rank = Some(i + 1);
+                item.price_is_from = prefixed;
+                item.seller = Some(self.name.clone());
+                Some(self.transform(self.finalize_item(item, url, warnings)))
+            })
+            .collect()
+    }
+
+    /// Checks if the current page is a captcha/anti-bot page, saving a screenshot if enabled
+    async fn check_blocked(&self, client: &Client, url: &str) -> Result<(), SpiderError> {
+        let blocked_indicator = match &self.blocked_indicator_selector {
+            Some(blocked_indicator) => blocked_indicator,
+            None => return Ok(()),
+        };
+        let res = check_blocked(client, blocked_indicator, url).await;
+        if res.is_err() {
+            if let Some(screenshot_dir) = &self.screenshot_dir {
+                save_screenshot(client, screenshot_dir, &self.name, url).await;
+            }
+        }
+        res
+    }
+
+    /// Normalizes the uri of `item` (see [`Spider::normalize_url`]), sets its price format
+    /// (dropping it if out of bounds), splits its missing brand from the name if enabled and
+    /// fills its missing fields with the configured defaults
+    fn finalize_item(
+        &self,
+        mut item: InfiniteScrollingItem,
+        url: &str,
+        warnings: &Warnings,
+    ) -> InfiniteScrollingItem {
+        item.source_url = Some(url.to_string());
+        item.uri = item.uri.map(|uri| self.normalize_url(&uri));
+        self.price_bounds
+            .apply(&mut item.price, &item.id, url, warnings);
+        if let Some(price) = &mut item.price {
+            price.structured = self.structured_price;
+        }
+        if let (None, Some(name), Some(brand_from_name)) =
+            (&item.brand, &item.name, &self.brand_from_name)
+        {
+            let known_brands = match brand_from_name {
+                BrandFromName::Uppercase => &[][..],
+                BrandFromName::KnownBrands(brands) => brands,
+            };
+            let (brand, name) = split_brand_from_name(name, known_brands);
+            if brand.is_some() {
+                item.brand = brand;
+                item.name = Some(name);
+            }
+        }
+        item.apply_field_defaults(&self.field_defaults);
+        item
+    }
+
+    /// Removes the configured prefix of the `data-price` on `map`, returning whether it had one
+    fn remove_price_prefix(&self, map: &mut HashMap<&str, &str>) -> bool {
+        match map.get_mut("data-price") {
+            Some(price) => {
+                let (stripped, prefixed) = strip_price_prefix(price, &self.price_prefixes);
+                *price = stripped;
+                prefixed
+            }
+            None => false,
+        }
+    }
+
+    /// Synthesizes an id for the items without `data-id`, if enabled
+    fn missing_id(&self, map: &HashMap<&str, &str>) -> Option<String> {
+        if !self.synthesize_id || map.contains_key("data-id") {
+            return None;
+        }
+        let uri = map.get("data-uri").map(|x| self.normalize_url(x));
+        let id = synthesize_id(
+            uri.as_deref(),
+            map.get("data-name").copied(),
+            map.get("data-brand").copied(),
+        )?;
+        tracing::debug!("Synthesized id {:?}", id);
+        Some(id)
+    }
+
+    /// Adds the rating, review count, seller, ean, promotions and missing price from `element`
+    /// to `item`
+    fn add_element_fields(
+        &self,
+        mut item: InfiniteScrollingItem,
+        element: ElementRef,
+    ) -> InfiniteScrollingItem {
+        item.rating = self
+            .rating
+            .as_ref()
+            .and_then(|x| x.extract_with(element, parse_rating));
+        item.reviews = self
+            .reviews
+            .as_ref()
+            .and_then(|x| x.extract_with(element, parse_reviews));
+        item.seller = self
+            .seller
+            .as_ref()
+            .and_then(|x| x.extract(element))
+            .or_else(|| Some(self.name.clone()));
+        item.ean = extract_ean(element, &self.ean_attr);
+        if let Some(selector) = &self.promotions_selector {
+            item.promotions = select_texts(element, selector);
+        }
+        if item.price.is_none() {
+            let price = self.price.as_ref().and_then(|x| {
+                x.extract_with(element, |x| parse_prefixed_price(x, &self.price_prefixes))
+            });
+            if let Some((price, prefixed)) = price {
+                item.price = Some(price);
+                item.price_is_from = prefixed;
+            }
+        }
+        if let (None, Some(int_selector)) = (&item.price, &self.price_int_selector) {
+            let int_txt = select_texts(element, int_selector).into_iter().next();
+            let dec_txt = self
+                .price_dec_selector
+                .as_ref()
+                .and_then(|x| select_texts(element, x).into_iter().next());
+            item.price = int_txt.and_then(|x| compose_price(&x, dec_txt.as_deref()));
+        }
+        item
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -151,8 +936,33 @@ pub struct InfiniteScrollingItem {
     pub brand: Option<String>,
     pub uri: Option<String>,
     pub name: Option<String>,
-    pub price: Option<f64>,
+    pub price: Option<Price>,
+    /// The price had a prefix (eg: "Desde S/ 9.90"), it's the lowest of a variable price
+    pub price_is_from: bool,
     pub category: Option<String>,
+    /// Url of the listing the item was scraped from
+    pub source_url: Option<String>,
+    /// Position on the listing of the subroute (starting at 1), counting the excluded items and
+    /// before removing duplicates
+    pub rank: Option<usize>,
+    pub zone: Option<String>,
+    /// Marketplace seller, the store itself for first-party items
+    pub seller: Option<String>,
+    /// Additional prices by label
+    #[serde(serialize_with = "serialize_json_map")]
+    pub prices: HashMap<String, f64>,
+    pub rating: Option<f64>,
+    pub reviews: Option<u32>,
+    /// Pack size parsed from the name
+    pub quantity: Option<f64>,
+    pub quantity_unit: Option<String>,
+    pub ean: Option<String>,
+    /// Promotion badges (eg: "2x1", "-30%")
+    #[serde(serialize_with = "serialize_list")]
+    pub promotions: Vec<String>,
+    /// Attributes from the detail page
+    #[serde(serialize_with = "serialize_json_map")]
+    pub attributes: HashMap<String, String>,
 }
 
 impl PartialEq for InfiniteScrollingItem {
@@ -161,8 +971,74 @@ impl PartialEq for InfiniteScrollingItem {
     }
 }
 
-impl Eq for InfiniteScrollingItem {
-    fn assert_receiver_is_total_eq(&self) {}
+impl Eq for InfiniteScrollingItem {}
+
+impl SpiderItem for InfiniteScrollingItem {
+    const ID_FIELD: &'static str = "id";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn price(&self) -> Option<f64> {
+        self.price.as_ref().map(|x| x.amount)
+    }
+
+    fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    fn set_zone(&mut self, zone: &str) {
+        self.zone = Some(zone.to_string());
+    }
+
+    fn common_row(&self) -> CommonRow {
+        CommonRow::from(self)
+    }
+
+    fn set_attributes(&mut self, attributes: HashMap<String, String>) {
+        self.attributes = attributes;
+    }
+
+    fn optional_field_mut(&mut self, field: &str) -> Option<&mut Option<String>> {
+        match field {
+            "brand" => Some(&mut self.brand),
+            "name" => Some(&mut self.name),
+            "category" => Some(&mut self.category),
+            "uri" => Some(&mut self.uri),
+            _ => None,
+        }
+    }
+
+    fn has_field(&self, field: &str) -> bool {
+        match field {
+            "id" => !self.id.trim().is_empty(),
+            "price" => self.price.is_some(),
+            "name" => has_text(&self.name),
+            "brand" => has_text(&self.brand),
+            "category" => has_text(&self.category),
+            "uri" => has_text(&self.uri),
+            "seller" => has_text(&self.seller),
+            "ean" => has_text(&self.ean),
+            _ => false,
+        }
+    }
+}
+
+impl From<&InfiniteScrollingItem> for CommonRow {
+    fn from(item: &InfiniteScrollingItem) -> Self {
+        Self {
+            store: String::new(),
+            zone: item.zone.clone(),
+            id: item.id.clone(),
+            name: item.name.clone(),
+            brand: item.brand.clone(),
+            category: item.category.clone(),
+            price: item.price(),
+            uri: item.uri.clone(),
+            seller: item.seller.clone(),
+        }
+    }
 }
 
 impl Hash for InfiniteScrollingItem {
@@ -171,11 +1047,14 @@ impl Hash for InfiniteScrollingItem {
     }
 }
 
-impl TryFrom<HashMap<&str, &str>> for InfiniteScrollingItem {
-    type Error = SpiderError;
-
-    #[tracing::instrument(err(Debug))]
-    fn try_from(mut map: HashMap<&str, &str>) -> Result<Self, Self::Error> {
+impl InfiniteScrollingItem {
+    /// Builds the item from the element attributes, extracting the additional prices
+    /// from `price_attrs` (pairs of attribute and label)
+    #[tracing::instrument]
+    pub fn try_from_attrs(
+        mut map: HashMap<&str, &str>,
+        price_attrs: &[(String, String)],
+    ) -> Result<Self, SpiderError> {
         tracing::debug!("Received data: {:#?}", map);
         let id = map
             .remove("data-id")
@@ -184,8 +1063,24 @@ impl TryFrom<HashMap<&str, &str>> for InfiniteScrollingItem {
         let brand = map.remove("data-brand").map(String::from);
         let uri = map.remove("data-uri").map(String::from);
         let name = map.remove("data-name").map(String::from);
-        let price = map.remove("data-price").map(parse_price).transpose()?;
+        let price = map.remove("data-price").and_then(|x| match parse_price(x) {
+            Ok(price) => Some(price),
+            Err(e) => {
+                tracing::debug!(error.message = %e, "Ignoring invalid price.");
+                None
+            }
+        });
         let category = map.remove("data-category").map(String::from);
+        let prices = price_attrs
+            .iter()
+            .filter_map(|(attr, label)| match parse_price(map.get(attr.as_str())?) {
+                Ok(price) => Some((label.clone(), price.amount)),
+                Err(e) => {
+                    tracing::debug!(error.message = %e, label = %label, "Ignoring invalid price.");
+                    None
+                }
+            })
+            .collect::<HashMap<_, _>>();
         if brand.is_none()
             && uri.is_none()
             && name.is_none()
@@ -194,18 +1089,40 @@ impl TryFrom<HashMap<&str, &str>> for InfiniteScrollingItem {
         {
             Err(SpiderError::NoDataExtracted(format!("{:?}", map)))
         } else {
+            let (quantity, quantity_unit) = name.as_deref().and_then(parse_quantity).unzip();
             Ok(Self {
                 id,
                 brand,
                 uri,
                 name,
                 price,
+                price_is_from: false,
+                source_url: None,
                 category,
+                rank: None,
+                zone: None,
+                seller: None,
+                rating: None,
+                reviews: None,
+                quantity,
+                quantity_unit,
+                ean: None,
+                promotions: Vec::new(),
+                attributes: HashMap::new(),
+                prices,
             })
         }
     }
 }
 
+impl TryFrom<HashMap<&str, &str>> for InfiniteScrollingItem {
+    type Error = SpiderError;
+
+    fn try_from(map: HashMap<&str, &str>) -> Result<Self, Self::Error> {
+        Self::try_from_attrs(map, &[])
+    }
+}
+
 #[async_trait]
 impl Spider for InfiniteScrollingSpider {
     type Item = InfiniteScrollingItem;
@@ -222,39 +1139,340 @@ impl Spider for InfiniteScrollingSpider {
         self.subroutes.as_slice()
     }
 
+    fn strip_query_params(&self) -> &[String] {
+        &self.strip_query_params
+    }
+
+    fn strip_all_query(&self) -> bool {
+        self.strip_all_query
+    }
+
+    fn cooldown(&self) -> Duration {
+        self.cooldown_after_scroll
+    }
+
+    fn latency_tracker(&self) -> Option<&LatencyTracker> {
+        self.latency_tracker.as_ref()
+    }
+
+    fn profiler(&self) -> Option<&Profiler> {
+        Some(&self.profiler)
+    }
+
+    fn schema_tracker(&self) -> Option<&SchemaTracker> {
+        Some(&self.schema_tracker)
+    }
+
+    async fn close(&self) -> Result<(), SpiderError> {
+        close_client(&self.client).await
+    }
+
+    fn max_consecutive_failures(&self) -> Option<usize> {
+        self.max_consecutive_failures
+    }
+
+    fn max_total_items(&self) -> Option<usize> {
+        self.max_total_items
+    }
+
+    fn max_concurrency(&self) -> usize {
+        // All subroutes share the same browser session
+        1
+    }
+
+    fn blocked_backoff(&self) -> Option<Duration> {
+        self.blocked_backoff
+    }
+
+    fn time_budget(&self) -> Option<Duration> {
+        self.time_budget
+    }
+
+    fn zones(&self) -> &[ZoneConfig] {
+        self.zones.as_slice()
+    }
+
+    fn expected_counts(&self) -> &[ExpectedCount] {
+        self.expected_counts.as_slice()
+    }
+
+    async fn set_zone(&self, zone: &ZoneConfig) -> Result<(), SpiderError> {
+        let client = lock_client(&self.client).await?;
+        apply_zone(&client, &self.base_url, zone, self.delay).await
+    }
+
     fn delay(&self) -> Duration {
         self.delay
     }
 
+    #[tracing::instrument(skip(self, warnings))]
+    async fn scrape(&self, url: &str, warnings: &Warnings) -> Result<Vec<Self::Item>, SpiderError> {
+        let client = lock_client(&self.client).await?;
+        let load_start = Instant::now();
+        let found = navigate(
+            &client,
+            url,
+            self.pre_scrape_js.as_deref(),
+            self.iframe_selector.as_deref(),
+            &self.css_locator,
+            self.wait_condition,
+            &self.profiler,
+        )
+        .await?;
+        if let Some(tracker) = &self.latency_tracker {
+            tracker.record(load_start.elapsed());
+        }
+        if !found {
+            warnings.push(url, WarningKind::Timeout, "Timed out waiting for the items");
+        }
+        if let Some(hosts) = &self.allowed_hosts {
+            check_host(&client, hosts, url).await?;
+        }
+        self.check_blocked(&client, url).await?;
+        let mut scroll_end = None;
+        if self.no_scroll {
+            tracing::info!("Scrolling skipped, extracting the initial render only");
+        } else {
+            let start = self.profiler.start();
+            match self.scroll(&client, url).await {
+                Ok(x) => scroll_end = Some(x),
+                Err(e) => {
+                    tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to scroll to end.")
+                }
+            }
+            sleep(self.post_scroll_settle).await;
+            self.profiler.record(Phase::Scroll, start);
+        }
+        if let Err(e) = self.reveal_prices(&client).await {
+            tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to reveal prices.");
+        }
+        let start = self.profiler.start();
+        let document = client
+            .source()
+            .await
+            .context("Failed to obtain html content")?;
+        self.profiler.record(Phase::Source, start);
+        let document = limit_source(document, self.source_limit, url)?;
+        let start = self.profiler.start();
+        let mut elements = self.parse_items(&document, url, warnings);
+        self.profiler.record(Phase::Parse, start);
+        match scroll_end {
+            Some(ScrollEnd::Ended) => self.save_scroll_position(url, None).await,
+            Some(ScrollEnd::Deadline) if self.scroll_positions_path.is_some() => {
+                let position = self
+                    .get_scroll_extent(&client)
+                    .await
+                    .map(|height| ScrollPosition {
+                        height,
+                        items: elements.len(),
+                    });
+                match position {
+                    Ok(position) => self.save_scroll_position(url, Some(position)).await,
+                    Err(e) => {
+                        tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to get scroll position.")
+                    }
+                }
+            }
+            Some(ScrollEnd::Deadline | ScrollEnd::ReadFailed) | None => {}
+        }
+        if elements.is_empty() {
+            if let Some(screenshot_dir) = &self.screenshot_dir {
+                save_screenshot(&client, screenshot_dir, &self.name, url).await;
+            }
+        }
+        leave_frame(&client, self.iframe_selector.as_deref()).await;
+        // The detail pages don't use the webdriver
+        drop(client);
+        if let Some(details) = &self.details {
+            details.add_attributes(&mut elements, url, warnings).await;
+        }
+        Ok(elements)
+    }
+
     #[tracing::instrument(skip(self))]
-    async fn scrape(&self, url: &str) -> Result<Vec<Self::Item>, SpiderError> {
+    fn extract_items(&self, document: &str, url: &str, warnings: &Warnings) -> Vec<Self::Item> {
+        self.parse_items(document, url, warnings)
+    }
+
+    async fn validate_selectors_against(
+        &self,
+        url: &str,
+    ) -> Result<Vec<(String, usize)>, SpiderError> {
         let document = {
-            let client = self.client.lock().await;
-            client.goto(url).await.context("Failed to go to url")?;
-            let _ = client
-                .wait()
-                .at_most(Duration::from_secs(5))
-                .for_element(Locator::Css(&self.css_locator))
-                .await;
-            if let Err(e) = self.scroll_to_end(&client).await {
-                tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to scroll to end.");
-            }
-            client
+            let client = lock_client(&self.client).await?;
+            navigate(
+                &client,
+                url,
+                self.pre_scrape_js.as_deref(),
+                self.iframe_selector.as_deref(),
+                &self.css_locator,
+                self.wait_condition,
+                &self.profiler,
+            )
+            .await?;
+            let document = client
                 .source()
                 .await
-                .context("Failed to obtain html content")?
+                .context("Failed to obtain html content")?;
+            leave_frame(&client, self.iframe_selector.as_deref()).await;
+            document
         };
         let html = Html::parse_document(&document);
-        let elements = html
-            .select(&self.selector)
-            .filter_map(|element| {
-                let map = element.value().attrs().collect::<HashMap<_, _>>();
-                InfiniteScrollingItem::try_from(map).ok()
-            })
-            .collect::<HashSet<_>>()
+        Ok(count_matches(
+            &html,
+            &self.selector,
+            &[
+                ("price", self.price.as_ref()),
+                ("rating", self.rating.as_ref()),
+                ("reviews", self.reviews.as_ref()),
+                ("seller", self.seller.as_ref()),
+            ],
+            &[
+                ("promotions", self.promotions_selector.as_ref()),
+                ("price_int", self.price_int_selector.as_ref()),
+                ("price_dec", self.price_dec_selector.as_ref()),
+            ],
+        ))
+    }
+}
+
+/// Whether the `deadline` (if any) has passed
+fn is_past(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|x| Instant::now() >= x)
+}
+
+/// Logs a persistent scroll extent read failure, the scroll stops there keeping the items
+/// already loaded
+fn stop_scroll(e: SpiderError) -> ScrollEnd {
+    tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to get scroll extent, stopping scroll.");
+    ScrollEnd::ReadFailed
+}
+
+/// Loads the saved scroll positions by url, empty if the file is missing or invalid
+async fn load_scroll_positions(path: &Path) -> HashMap<String, ScrollPosition> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return HashMap::new(),
+        Err(e) => {
+            tracing::error!(error.message = %e, path = ?path, "Failed to read scroll positions.");
+            return HashMap::new();
+        }
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        tracing::error!(error.message = %e, path = ?path, "Failed to parse scroll positions.");
+        HashMap::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::tests::test_settings;
+
+    #[test]
+    fn scroll_axis_defaults_to_vertical() {
+        assert_eq!(ScrollAxis::default(), ScrollAxis::Vertical);
+        let axis = serde_json::from_str::<ScrollAxis>(r#""horizontal""#).unwrap();
+        assert_eq!(axis, ScrollAxis::Horizontal);
+    }
+
+    #[test]
+    fn vertical_axis_scrolls_top() {
+        let axis = ScrollAxis::Vertical;
+        assert!(axis.extent_js().contains("container.scrollHeight"));
+        assert!(axis.offset_js().contains("container.scrollTop"));
+        assert!(axis.scroll_to_end_js().contains("container.scrollTop"));
+        assert!(!axis.scroll_to_end_js().contains("scrollLeft"));
+    }
+
+    #[test]
+    fn horizontal_axis_scrolls_left() {
+        let axis = ScrollAxis::Horizontal;
+        assert!(axis.extent_js().contains("container.scrollWidth"));
+        assert!(axis.offset_js().contains("container.scrollLeft"));
+        assert!(axis
+            .scroll_to_end_js()
+            .contains("container.scrollLeft = container.scrollWidth"));
+        assert!(axis
+            .scroll_to_end_js()
+            .contains("window.scrollTo(document.body.scrollWidth, 0)"));
+    }
+
+    #[test]
+    fn invalid_price_is_ignored() {
+        let item = InfiniteScrollingItem::try_from(HashMap::from([
+            ("data-id", "1"),
+            ("data-name", "Leche Gloria 400g"),
+            ("data-price", "Consultar"),
+        ]))
+        .unwrap();
+        assert!(item.price.is_none());
+        assert_eq!(item.name.as_deref(), Some("Leche Gloria 400g"));
+    }
+
+    #[test]
+    fn invalid_labelled_price_is_ignored() {
+        let map = HashMap::from([
+            ("data-id", "1"),
+            ("data-name", "Leche Gloria 400g"),
+            ("data-price", "S/ 4.50"),
+            ("data-loyalty-price", "S/ 4.20"),
+            ("data-card-price", "Consultar"),
+        ]);
+        let price_attrs = [
+            ("data-loyalty-price".to_string(), "loyalty".to_string()),
+            ("data-card-price".to_string(), "card".to_string()),
+        ];
+        let item = InfiniteScrollingItem::try_from_attrs(map, &price_attrs).unwrap();
+        assert_eq!(item.prices, HashMap::from([("loyalty".to_string(), 4.2)]));
+    }
+
+    #[test]
+    fn max_clicks_reached_only_when_clicks_are_enabled() {
+        assert!(max_clicks_reached(3, 3));
+        assert!(!max_clicks_reached(2, 3));
+        assert!(!max_clicks_reached(0, 0));
+    }
+
+    #[tokio::test]
+    async fn spider_scrolls_the_configured_container() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.metro.scroll_axis = ScrollAxis::Horizontal;
+        settings.metro.scroll_container_selector = Some(".carousel__track".to_string());
+        let spider = InfiniteScrollingSpider::from_settings(&settings, &settings.metro, None)
+            .await
+            .unwrap();
+        assert_eq!(spider.scroll_axis, ScrollAxis::Horizontal);
+        assert_eq!(
+            spider.scroll_args(),
+            [
+                serde_json::json!(".carousel__track"),
+                serde_json::Value::Null
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rank_counts_the_excluded_items() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.metro.common.exclude_selector = Some(".product-item--sponsored".to_string());
+        let spider = InfiniteScrollingSpider::from_settings(&settings, &settings.metro, None)
+            .await
+            .unwrap();
+        let document = r#"
+            <div class="product-item" data-id="1" data-name="Leche Gloria 400g"></div>
+            <div class="product-item product-item--sponsored" data-id="2" data-name="Aceite Primor 1L"></div>
+            <div class="product-item" data-id="3" data-name="Arroz Costeño 750g"></div>
+        "#;
+        let mut ranks = spider
+            .extract_items(document, "http://mock/a", &Warnings::default())
             .into_iter()
+            .map(|x| (x.id, x.rank))
             .collect::<Vec<_>>();
-        tracing::info!("Found {} elements", elements.len());
-        Ok(elements)
+        ranks.sort();
+        assert_eq!(
+            ranks,
+            [("1".to_string(), Some(1)), ("3".to_string(), Some(3))]
+        );
     }
 }
@@ -0,0 +1,479 @@
+use super::{has_text, CommonRow, ExpectedCount, Spider, SpiderError, SpiderItem, Warnings};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::Serialize;
+use std::{collections::HashMap, fmt, hash::Hash, time::Duration};
+use tokio::time::sleep;
+
+/// Spider returning canned responses per subroute, used to test crawling logic
+/// without a webdriver.
+pub struct MockSpider {
+    name: String,
+    base_url: String,
+    subroutes: Vec<String>,
+    delay: Duration,
+    responses: HashMap<String, MockResponse>,
+    /// Overrides [`Spider::transform`]
+    transform: Option<fn(MockItem) -> MockItem>,
+    /// Overrides [`Spider::max_consecutive_failures`]
+    max_consecutive_failures: Option<usize>,
+    /// Overrides [`Spider::max_total_items`]
+    max_total_items: Option<usize>,
+    /// Overrides [`Spider::expected_counts`]
+    expected_counts: Vec<ExpectedCount>,
+}
+
+/// Canned response for a subroute
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    /// Items to return, or an error message to fail with
+    pub result: Result<Vec<MockItem>, String>,
+    /// Time to wait before responding
+    pub delay: Duration,
+}
+
+impl MockResponse {
+    pub fn ok(items: Vec<MockItem>) -> Self {
+        Self {
+            result: Ok(items),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn err(message: impl ToString) -> Self {
+        Self {
+            result: Err(message.to_string()),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MockItem {
+    pub id: String,
+    pub name: Option<String>,
+    pub zone: Option<String>,
+    /// Subroute url the item was returned for
+    pub source_url: Option<String>,
+}
+
+// `source_url` is left out so the items returned for several subroutes are deduplicated
+impl PartialEq for MockItem {
+    fn eq(&self, other: &Self) -> bool {
+        (&self.id, &self.name, &self.zone) == (&other.id, &other.name, &other.zone)
+    }
+}
+
+impl Eq for MockItem {}
+
+impl Hash for MockItem {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (&self.id, &self.name, &self.zone).hash(state);
+    }
+}
+
+impl MockItem {
+    pub fn new(id: impl ToString, name: Option<&str>) -> Self {
+        Self {
+            id: id.to_string(),
+            name: name.map(String::from),
+            zone: None,
+            source_url: None,
+        }
+    }
+}
+
+impl From<&MockItem> for CommonRow {
+    fn from(item: &MockItem) -> Self {
+        Self {
+            id: item.id.clone(),
+            name: item.name.clone(),
+            zone: item.zone.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl SpiderItem for MockItem {
+    const ID_FIELD: &'static str = "id";
+
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn price(&self) -> Option<f64> {
+        None
+    }
+
+    fn category(&self) -> Option<&str> {
+        None
+    }
+
+    fn set_zone(&mut self, zone: &str) {
+        self.zone = Some(zone.to_string());
+    }
+
+    fn common_row(&self) -> CommonRow {
+        CommonRow::from(self)
+    }
+
+    fn optional_field_mut(&mut self, field: &str) -> Option<&mut Option<String>> {
+        match field {
+            "name" => Some(&mut self.name),
+            _ => None,
+        }
+    }
+
+    fn has_field(&self, field: &str) -> bool {
+        match field {
+            "id" => !self.id.trim().is_empty(),
+            "name" => has_text(&self.name),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MockSpider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (url={}, subroutes={})",
+            self.name,
+            self.base_url,
+            self.subroutes.len()
+        )
+    }
+}
+
+impl MockSpider {
+    /// Subroutes are taken from `responses`, in the given order.
+    pub fn new(
+        name: impl ToString,
+        base_url: impl ToString,
+        responses: Vec<(impl ToString, MockResponse)>,
+        delay_milis: u64,
+    ) -> Self {
+        let responses = responses
+            .into_iter()
+            .map(|(subroute, response)| (subroute.to_string(), response))
+            .collect::<Vec<_>>();
+        Self {
+            name: name.to_string(),
+            base_url: base_url.to_string(),
+            subroutes: responses.iter().map(|(x, _)| x.clone()).collect(),
+            delay: Duration::from_millis(delay_milis),
+            responses: responses.into_iter().collect(),
+            transform: None,
+            max_consecutive_failures: None,
+            max_total_items: None,
+            expected_counts: Vec::new(),
+        }
+    }
+
+    fn response(&self, url: &str) -> Result<&MockResponse, SpiderError> {
+        let subroute = url
+            .strip_prefix(&self.base_url)
+            .unwrap_or(url)
+            .trim_start_matches('/');
+        self.responses
+            .get(subroute)
+            .ok_or_else(|| anyhow!("No mock response for: {}", url).into())
+    }
+
+    pub fn with_max_total_items(mut self, max_total_items: usize) -> Self {
+        self.max_total_items = Some(max_total_items);
+        self
+    }
+
+    pub fn with_transform(mut self, transform: fn(MockItem) -> MockItem) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    pub fn with_expected_counts(mut self, expected_counts: Vec<ExpectedCount>) -> Self {
+        self.expected_counts = expected_counts;
+        self
+    }
+
+    pub fn with_max_consecutive_failures(mut self, max_consecutive_failures: usize) -> Self {
+        self.max_consecutive_failures = Some(max_consecutive_failures);
+        self
+    }
+}
+
+#[async_trait]
+impl Spider for MockSpider {
+    type Item = MockItem;
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    fn subroutes(&self) -> &[String] {
+        self.subroutes.as_slice()
+    }
+
+    fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    #[tracing::instrument(skip(self, _warnings))]
+    async fn scrape(
+        &self,
+        url: &str,
+        _warnings: &Warnings,
+    ) -> Result<Vec<Self::Item>, SpiderError> {
+        let response = self.response(url)?;
+        sleep(response.delay).await;
+        let items = response
+            .result
+            .clone()
+            .map_err(|e| anyhow!(e))?
+            .into_iter()
+            .map(|item| {
+                self.transform(MockItem {
+                    source_url: Some(url.to_string()),
+                    ..item
+                })
+            })
+            .collect::<Vec<_>>();
+        tracing::info!("Found {} elements", items.len());
+        Ok(items)
+    }
+
+    /// Returns the items of the canned response of `url`, the document is ignored
+    fn extract_items(&self, _document: &str, url: &str, _warnings: &Warnings) -> Vec<Self::Item> {
+        self.response(url)
+            .ok()
+            .and_then(|response| response.result.clone().ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|item| {
+                self.transform(MockItem {
+                    source_url: Some(url.to_string()),
+                    ..item
+                })
+            })
+            .collect()
+    }
+
+    fn max_consecutive_failures(&self) -> Option<usize> {
+        self.max_consecutive_failures
+    }
+
+    fn max_total_items(&self) -> Option<usize> {
+        self.max_total_items
+    }
+
+    fn expected_counts(&self) -> &[ExpectedCount] {
+        &self.expected_counts
+    }
+
+    fn transform(&self, item: Self::Item) -> Self::Item {
+        match self.transform {
+            Some(transform) => transform(item),
+            None => item,
+        }
+    }
+
+    async fn validate_selectors_against(
+        &self,
+        url: &str,
+    ) -> Result<Vec<(String, usize)>, SpiderError> {
+        let items = self.scrape(url, &Warnings::default()).await?;
+        Ok(vec![("items".to_string(), items.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spiders::ScrapeSummary;
+    use tokio_util::sync::CancellationToken;
+
+    async fn scrape_all(
+        spider: &MockSpider,
+        fail_fast: bool,
+    ) -> Result<Vec<MockItem>, SpiderError> {
+        let mut items = spider
+            .scrape_all(
+                2,
+                fail_fast,
+                true,
+                &Warnings::default(),
+                &CancellationToken::new(),
+            )
+            .await?
+            .items;
+        items.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(items)
+    }
+
+    fn ids(items: &[MockItem]) -> Vec<&str> {
+        items.iter().map(|x| x.id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn scrape_all_removes_duplicates() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![
+                (
+                    "a",
+                    MockResponse::ok(vec![
+                        MockItem::new(1, Some("x")),
+                        MockItem::new(2, Some("y")),
+                    ]),
+                ),
+                (
+                    "b",
+                    MockResponse::ok(vec![
+                        MockItem::new(2, Some("y")),
+                        MockItem::new(3, Some("z")),
+                    ]),
+                ),
+            ],
+            0,
+        );
+        let items = scrape_all(&spider, false).await.unwrap();
+        assert_eq!(ids(&items), ["1", "2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn scrape_all_drops_failed_subroutes() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![
+                ("a", MockResponse::ok(vec![MockItem::new(1, Some("x"))])),
+                ("b", MockResponse::err("boom")),
+                ("c", MockResponse::ok(vec![MockItem::new(2, Some("y"))])),
+            ],
+            0,
+        );
+        let items = scrape_all(&spider, false).await.unwrap();
+        assert_eq!(ids(&items), ["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn scrape_all_fails_fast() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![
+                ("a", MockResponse::ok(vec![MockItem::new(1, Some("x"))])),
+                ("b", MockResponse::err("boom")),
+            ],
+            0,
+        );
+        assert!(scrape_all(&spider, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn empty_buffer_is_rejected() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![("a", MockResponse::ok(vec![MockItem::new(1, Some("x"))]))],
+            0,
+        );
+        let res = spider
+            .scrape_all(
+                0,
+                false,
+                false,
+                &Warnings::default(),
+                &CancellationToken::new(),
+            )
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn transform_runs_before_dedup() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![
+                ("a", MockResponse::ok(vec![MockItem::new(1, Some("leche"))])),
+                ("b", MockResponse::ok(vec![MockItem::new(1, Some("LECHE"))])),
+            ],
+            0,
+        );
+        assert_eq!(scrape_all(&spider, false).await.unwrap().len(), 2);
+        let spider = spider.with_transform(|mut item| {
+            item.name = item.name.map(|x| x.to_uppercase());
+            item
+        });
+        let items = scrape_all(&spider, false).await.unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name.as_deref(), Some("LECHE"));
+    }
+
+    /// Scrapes one subroute at a time, in order
+    async fn scrape_all_sequential(spider: &MockSpider) -> ScrapeSummary<MockItem> {
+        spider
+            .scrape_all(
+                1,
+                false,
+                true,
+                &Warnings::default(),
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_consecutive_failures() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![
+                ("a", MockResponse::err("blocked")),
+                ("b", MockResponse::err("blocked")),
+                ("c", MockResponse::ok(vec![MockItem::new(1, Some("x"))])),
+                ("d", MockResponse::ok(vec![MockItem::new(2, Some("y"))])),
+            ],
+            0,
+        )
+        .with_max_consecutive_failures(2);
+        let summary = scrape_all_sequential(&spider).await;
+        assert!(summary.items.is_empty());
+        let skipped = summary
+            .statuses
+            .iter()
+            .filter(|x| matches!(&x.result, Err(e) if e == "Skipped, circuit open"))
+            .map(|x| x.subroute.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, ["c", "d"]);
+    }
+
+    #[tokio::test]
+    async fn circuit_failures_reset_on_success() {
+        let spider = MockSpider::new(
+            "mock",
+            "http://mock",
+            vec![
+                ("a", MockResponse::err("blocked")),
+                ("b", MockResponse::ok(vec![MockItem::new(1, Some("x"))])),
+                ("c", MockResponse::err("blocked")),
+                ("d", MockResponse::ok(vec![MockItem::new(2, Some("y"))])),
+            ],
+            0,
+        )
+        .with_max_consecutive_failures(2);
+        let summary = scrape_all_sequential(&spider).await;
+        assert_eq!(ids(&summary.items), ["1", "2"]);
+    }
+}
@@ -1,83 +1,2193 @@
+mod details;
 mod infinite_scrolling;
+#[cfg(any(test, feature = "test-support"))]
+mod mock;
 mod multipage;
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+pub use details::*;
 pub use infinite_scrolling::*;
+#[cfg(any(test, feature = "test-support"))]
+pub use mock::*;
 pub use multipage::*;
 
-use crate::error_chain_fmt;
+use crate::{
+    configuration::{ExpectedCount, FieldSelector, HtmlReplacement, Settings, ZoneConfig},
+    error_chain_fmt, last_segment_slug,
+};
 use async_trait::async_trait;
+use fantoccini::{cookies::Cookie, Client, ClientBuilder, Locator};
 use futures::{stream, StreamExt};
-use serde::Serialize;
-use std::{collections::HashSet, hash::Hash, time::Duration};
-use tokio::time::sleep;
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::Hash,
+    io::Write,
+    path::Path,
+    pin::pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    fs::create_dir_all,
+    sync::{MappedMutexGuard, Mutex, MutexGuard},
+    time::{sleep, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use url::{Position, Url};
 
 #[derive(thiserror::Error)]
 pub enum SpiderError {
     #[error("Invalid selector: {0}")]
     InvalidSelector(String),
+    #[error("Invalid regex: {0}")]
+    InvalidRegex(String),
+    #[error("Blocked by an anti-bot page: {0}")]
+    Blocked(String),
+    #[error("Landed on unexpected host {host:?} when loading: {url}")]
+    UnexpectedHost { url: String, host: String },
+    #[error("Page source too large ({size} bytes): {url}")]
+    SourceTooLarge { url: String, size: usize },
     #[error("No data found to be extracted: {0}")]
     NoDataExtracted(String),
     #[error("Something went wrong.")]
     UnexpectedError(#[from] anyhow::Error),
 }
 
+impl SpiderError {
+    /// Adds the (truncated) outer html of `element` to `NoDataExtracted` errors
+    pub fn with_element_html(self, element: ElementRef) -> Self {
+        match self {
+            Self::NoDataExtracted(details) => Self::NoDataExtracted(format!(
+                "{} (element: {})",
+                details,
+                truncate(&element.html(), MAX_ERROR_HTML_LEN)
+            )),
+            e => e,
+        }
+    }
+}
+
 impl std::fmt::Debug for SpiderError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         error_chain_fmt(self, f)
     }
 }
 
+/// Common behaviour of the items scraped by a spider
+pub trait SpiderItem {
+    /// Name of the field that identifies the item
+    const ID_FIELD: &'static str;
+    /// Name of the price field
+    const PRICE_FIELD: &'static str = "price";
+
+    fn id(&self) -> &str;
+    fn price(&self) -> Option<f64>;
+    fn category(&self) -> Option<&str>;
+    /// Tags the item with the delivery zone it was scraped from
+    fn set_zone(&mut self, zone: &str);
+    /// Flattened row written on the combined output
+    fn common_row(&self) -> CommonRow;
+    /// Sets the attributes read from the item detail page, see [`DetailScraper`]
+    fn set_attributes(&mut self, _attributes: HashMap<String, String>) {}
+    /// Optional text field named `field`, one of [`DEFAULTABLE_FIELDS`]
+    fn optional_field_mut(&mut self, _field: &str) -> Option<&mut Option<String>> {
+        None
+    }
+    /// Whether `field` (one of [`REQUIRABLE_FIELDS`]) has a non-empty value
+    fn has_field(&self, field: &str) -> bool;
+
+    /// Fills the missing fields with the values on `defaults` (field -> value)
+    fn apply_field_defaults(&mut self, defaults: &HashMap<String, String>) {
+        for (field, default) in defaults {
+            if let Some(value @ None) = self.optional_field_mut(field) {
+                *value = Some(default.clone());
+            }
+        }
+    }
+}
+
+/// Columns shared by the items of every spider, used on the combined output.
+/// `store` is left empty on conversion and set by the crawler
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CommonRow {
+    pub store: String,
+    pub zone: Option<String>,
+    pub id: String,
+    pub name: Option<String>,
+    pub brand: Option<String>,
+    pub category: Option<String>,
+    pub price: Option<f64>,
+    pub uri: Option<String>,
+    pub seller: Option<String>,
+}
+
+/// Fields that can be configured on `field_defaults`
+pub const DEFAULTABLE_FIELDS: [&str; 4] = ["brand", "name", "category", "uri"];
+
+/// Fields that can be configured on `required_fields`
+pub const REQUIRABLE_FIELDS: [&str; 8] = [
+    "id", "name", "brand", "category", "price", "uri", "seller", "ean",
+];
+
 #[async_trait]
 pub trait Spider {
-    type Item: std::fmt::Debug + Eq + Hash + Send + Sync + Serialize + 'static;
+    type Item: std::fmt::Debug + Eq + Hash + Send + Sync + Serialize + SpiderItem + 'static;
 
     fn name(&self) -> &str;
     fn base_url(&self) -> &str;
     fn subroutes(&self) -> &[String];
-    /// Delay to scrap between subroutes
+    /// Delay between the end of a subroute and the start of the next one
     fn delay(&self) -> Duration;
-    async fn scrape(&self, url: &str) -> Result<Vec<Self::Item>, SpiderError>;
-
-    #[tracing::instrument(skip(self))]
-    async fn scrape_all(&self, spiders_buffer_size: usize) -> Vec<Self::Item> {
-        stream::iter(self.subroutes().iter().cloned())
-            .enumerate()
-            .map(|(i, subroute)| async move {
-                if i > 0 {
-                    sleep(self.delay()).await;
+    /// Scrapes the items on `url`, reporting non fatal problems on `warnings`
+    async fn scrape(&self, url: &str, warnings: &Warnings) -> Result<Vec<Self::Item>, SpiderError>;
+    /// Extracts the items from the page source of `url`, without a browser
+    fn extract_items(&self, document: &str, url: &str, warnings: &Warnings) -> Vec<Self::Item>;
+    /// Counts the elements matched by each selector on `url`, without extracting items
+    async fn validate_selectors_against(
+        &self,
+        url: &str,
+    ) -> Result<Vec<(String, usize)>, SpiderError>;
+
+    /// Subroutes to scrape, fetched at the start of the crawl by spiders that discover them
+    /// dynamically (eg: from a categories API), the configured ones by default
+    async fn discover_subroutes(&self) -> Result<Vec<String>, SpiderError> {
+        Ok(self.subroutes().to_vec())
+    }
+
+    /// Delivery zones to scrape, an empty slice means no zone is selected
+    fn zones(&self) -> &[ZoneConfig] {
+        &[]
+    }
+
+    /// Expected item count ranges of the subroutes, checked after the crawl
+    fn expected_counts(&self) -> &[ExpectedCount] {
+        &[]
+    }
+
+    /// Ends the browser session of the spider, called by the crawler once it's done
+    async fn close(&self) -> Result<(), SpiderError> {
+        Ok(())
+    }
+
+    /// Selects the delivery zone for the following scrapes
+    async fn set_zone(&self, _zone: &ZoneConfig) -> Result<(), SpiderError> {
+        Ok(())
+    }
+
+    /// Post-processes each item after it's extracted (before deduplication)
+    fn transform(&self, item: Self::Item) -> Self::Item {
+        item
+    }
+
+    /// Query params removed from the item uris
+    fn strip_query_params(&self) -> &[String] {
+        &[]
+    }
+
+    /// Whether to remove all the query params from the item uris
+    fn strip_all_query(&self) -> bool {
+        false
+    }
+
+    /// Normalizes an item uri, removing the query params set to be stripped
+    fn normalize_url(&self, url: &str) -> String {
+        strip_query_params(url, self.strip_query_params(), self.strip_all_query())
+    }
+
+    /// Maximum number of subroutes to scrape concurrently, spiders sharing a single browser
+    /// session should return 1
+    fn max_concurrency(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Pause after being blocked before continuing with the next subroutes
+    fn blocked_backoff(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Wall-clock budget to scrape all subroutes, no new subroutes are started after it
+    fn time_budget(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Number of items across all subroutes after which no new subroutes are started
+    fn max_total_items(&self) -> Option<usize> {
+        None
+    }
+
+    /// Stop scraping the remaining subroutes after this many failures in a row
+    fn max_consecutive_failures(&self) -> Option<usize> {
+        None
+    }
+
+    /// Extra wait added to [`Spider::delay`] after each subroute (eg: after scrolling)
+    fn cooldown(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Page load latency of the spider, replaces [`Spider::delay`] when set
+    fn latency_tracker(&self) -> Option<&LatencyTracker> {
+        None
+    }
+
+    /// Time spent on each phase of the scrapes, see [`Phase`]
+    fn profiler(&self) -> Option<&Profiler> {
+        None
+    }
+
+    /// Attribute names found on the item elements, see [`SchemaTracker`]
+    fn schema_tracker(&self) -> Option<&SchemaTracker> {
+        None
+    }
+
+    /// Scrapes all subroutes, stops early when `cancel` is triggered returning the items
+    /// scraped so far
+    /// Failed subroutes are logged and skipped, unless `fail_fast` is set in which case the
+    /// first error is returned
+    /// Timing: a subroute starts `delay + cooldown` after the last subroute finished (or right
+    /// away for the first ones), so the pacing doesn't depend on how long a subroute takes.
+    /// With a [`LatencyTracker`] the delay follows the page load latency instead
+    /// With `deterministic` the subroutes are collected in order (a slow subroute holds back
+    /// the following ones), duplicated ids keep the item with the lowest category and the
+    /// items are sorted by id, trading throughput for reproducible outputs
+    #[tracing::instrument(skip(self, warnings, cancel))]
+    #[allow(clippy::too_many_arguments)]
+    async fn scrape_all(
+        &self,
+        spiders_buffer_size: usize,
+        fail_fast: bool,
+        deterministic: bool,
+        warnings: &Warnings,
+        cancel: &CancellationToken,
+    ) -> Result<ScrapeSummary<Self::Item>, SpiderError> {
+        let start = Instant::now();
+        let time_budget = self.time_budget();
+        let last_end = std::sync::Mutex::new(None::<Instant>);
+        let last_end = &last_end;
+        let circuit_open = AtomicBool::new(false);
+        let circuit_open = &circuit_open;
+        let capped = AtomicBool::new(false);
+        let capped = &capped;
+        if spiders_buffer_size == 0 {
+            return Err(anyhow!("spiders_buffer_size must be greater than 0").into());
+        }
+        let subroutes = self.discover_subroutes().await?;
+        tracing::info!("Scraping {} subroutes", subroutes.len());
+        let buffer_size = spiders_buffer_size.min(self.max_concurrency());
+        let results = stream::iter(subroutes).map(|subroute| async move {
+            if circuit_open.load(Ordering::Relaxed) {
+                tracing::warn!("Circuit open, skipping subroute: {}", subroute);
+                warnings.push(&subroute, WarningKind::SkippedSubroute, "Circuit open");
+                return (subroute, SubrouteOutcome::CircuitOpen);
+            }
+            if capped.load(Ordering::Relaxed) {
+                tracing::warn!("Item cap reached, skipping subroute: {}", subroute);
+                warnings.push(&subroute, WarningKind::SkippedSubroute, "Item cap reached");
+                return (subroute, SubrouteOutcome::Capped);
+            }
+            let last = *last_end.lock().unwrap();
+            if let Some(last) = last {
+                let delay = match self.latency_tracker() {
+                    Some(tracker) => tracker.delay(self.delay()),
+                    None => self.delay(),
+                };
+                let pause = delay + self.cooldown();
+                sleep((last + pause).saturating_duration_since(Instant::now())).await;
+            }
+            if time_budget.map(|x| start.elapsed() > x).unwrap_or(false) {
+                tracing::warn!("Time budget exceeded, skipping subroute: {}", subroute);
+                warnings.push(
+                    &subroute,
+                    WarningKind::SkippedSubroute,
+                    "Time budget exceeded",
+                );
+                return (subroute, SubrouteOutcome::BudgetExceeded);
+            }
+            let url = join_url(self.base_url(), &subroute);
+            let res = self.scrape(&url, warnings).await;
+            *last_end.lock().unwrap() = Some(Instant::now());
+            if let Err(e) = &res {
+                warnings.push(&url, WarningKind::ScrapeFailure, e);
+            }
+            if let (Err(SpiderError::Blocked(_)), Some(backoff)) = (&res, self.blocked_backoff()) {
+                tracing::warn!("Blocked, pausing for {:?}", backoff);
+                sleep(backoff).await;
+            }
+            (subroute, SubrouteOutcome::Scraped(res))
+        });
+        let results = if deterministic {
+            results.buffered(buffer_size).left_stream()
+        } else {
+            results.buffer_unordered(buffer_size).right_stream()
+        };
+        let mut results = pin!(results.take_until(cancel.cancelled()));
+        let mut items = Dedup::new(deterministic);
+        let mut budget_exceeded = false;
+        let mut statuses = Vec::new();
+        let mut consecutive_failures = 0;
+        while let Some((subroute, outcome)) = results.next().await {
+            let result = match outcome {
+                SubrouteOutcome::BudgetExceeded => {
+                    budget_exceeded = true;
+                    Err("Skipped, time budget exceeded".to_string())
                 }
-                let subroute = format!("{}/{}", self.base_url(), subroute);
-                self.scrape(&subroute).await
-            })
-            .buffer_unordered(spiders_buffer_size)
-            .filter_map(|res| async {
-                match res {
-                    Ok(items) => Some(items),
-                    Err(e) => {
-                        tracing::error!(error.cause_chain = ?e,
-                                        error.message = %e,
-                                        "Failed to scrape subroute.");
-                        None
+                SubrouteOutcome::CircuitOpen => Err("Skipped, circuit open".to_string()),
+                SubrouteOutcome::Capped => Err("Skipped, item cap reached".to_string()),
+                SubrouteOutcome::Scraped(Ok(subroute_items)) => {
+                    consecutive_failures = 0;
+                    let n = subroute_items.len();
+                    items.extend(subroute_items);
+                    if matches!(self.max_total_items(), Some(max) if items.len() >= max)
+                        && !capped.swap(true, Ordering::Relaxed)
+                    {
+                        tracing::warn!(
+                            "Reached {} items, skipping the remaining subroutes",
+                            items.len()
+                        );
+                    }
+                    Ok(n)
+                }
+                SubrouteOutcome::Scraped(Err(e)) if fail_fast => return Err(e),
+                SubrouteOutcome::Scraped(Err(e)) => {
+                    tracing::error!(error.cause_chain = ?e,
+                                    error.message = %e,
+                                    "Failed to scrape subroute.");
+                    consecutive_failures += 1;
+                    if matches!(self.max_consecutive_failures(), Some(max) if consecutive_failures >= max)
+                        && !circuit_open.swap(true, Ordering::Relaxed)
+                    {
+                        tracing::error!(
+                            "Circuit opened after {} consecutive failures, skipping the remaining subroutes",
+                            consecutive_failures
+                        );
                     }
+                    Err(e.to_string())
+                }
+            };
+            statuses.push(SubrouteStatus { subroute, result });
+        }
+        let mut items = items.into_vec();
+        if deterministic {
+            items.sort_by(|a, b| a.id().cmp(b.id()));
+        }
+        Ok(ScrapeSummary {
+            items,
+            budget_exceeded,
+            capped: capped.load(Ordering::Relaxed),
+            statuses,
+        })
+    }
+}
+
+/// Weight of each new sample on the [`LatencyTracker`] moving average
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+/// Exponential moving average of the page load latency (`goto` and waiting for the items)
+/// of a spider, used as the delay between subroutes: fast responses shorten it and
+/// growing latencies (eg: an overloaded or throttling store) lengthen it
+#[derive(Debug)]
+pub struct LatencyTracker {
+    min: Duration,
+    max: Duration,
+    average: std::sync::Mutex<Option<Duration>>,
+}
+
+impl LatencyTracker {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            average: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let mut average = self.average.lock().unwrap();
+        let new = match *average {
+            Some(x) => x.mul_f64(1.0 - LATENCY_SMOOTHING) + latency.mul_f64(LATENCY_SMOOTHING),
+            None => latency,
+        };
+        tracing::debug!("latency={:?} average={:?}", latency, new);
+        *average = Some(new);
+    }
+
+    /// The average latency clamped between the configured bounds, `default` (also clamped)
+    /// until the first sample
+    pub fn delay(&self, default: Duration) -> Duration {
+        self.average
+            .lock()
+            .unwrap()
+            .unwrap_or(default)
+            .clamp(self.min, self.max)
+    }
+}
+
+/// Items without duplicates, keeping the first item of each id (or the one with the
+/// lowest category when `lowest_category` is set)
+struct Dedup<T> {
+    items: HashSet<T>,
+    lowest_category: bool,
+}
+
+impl<T: Eq + Hash + SpiderItem> Dedup<T> {
+    fn new(lowest_category: bool) -> Self {
+        Self {
+            items: HashSet::new(),
+            lowest_category,
+        }
+    }
+
+    fn extend(&mut self, new_items: impl IntoIterator<Item = T>) {
+        if !self.lowest_category {
+            return self.items.extend(new_items);
+        }
+        for item in new_items {
+            if self
+                .items
+                .get(&item)
+                .is_none_or(|seen| item.category() < seen.category())
+            {
+                self.items.replace(item);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    fn into_vec(self) -> Vec<T> {
+        self.items.into_iter().collect()
+    }
+}
+
+/// Outcome of a subroute on [`Spider::scrape_all`]
+enum SubrouteOutcome<T> {
+    Scraped(Result<Vec<T>, SpiderError>),
+    BudgetExceeded,
+    CircuitOpen,
+    Capped,
+}
+
+/// Kinds of [`Warning`]
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningKind {
+    /// An item couldn't be extracted
+    ParseFailure,
+    /// The subroute wasn't scraped
+    SkippedSubroute,
+    /// The subroute failed to be scraped
+    ScrapeFailure,
+    /// The items didn't show up on time
+    Timeout,
+    /// The price was missing or invalid on the main source
+    PriceFallback,
+    /// The price was outside the configured bounds and dropped
+    PriceOutOfBounds,
+}
+
+/// Non fatal problem found while scraping
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    pub subroute: String,
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+/// Collector of [`Warning`]s, pushing does nothing unless created with [`Warnings::enabled`]
+#[derive(Debug, Clone, Default)]
+pub struct Warnings(Option<Arc<std::sync::Mutex<Vec<Warning>>>>);
+
+impl Warnings {
+    pub fn enabled() -> Self {
+        Self(Some(Default::default()))
+    }
+
+    pub fn push(&self, subroute: &str, kind: WarningKind, message: impl ToString) {
+        if let Some(warnings) = &self.0 {
+            warnings.lock().unwrap().push(Warning {
+                subroute: subroute.to_string(),
+                kind,
+                message: message.to_string(),
+            });
+        }
+    }
+
+    /// Removes and returns the collected warnings
+    pub fn take(&self) -> Vec<Warning> {
+        self.0
+            .as_ref()
+            .map(|warnings| std::mem::take(&mut *warnings.lock().unwrap()))
+            .unwrap_or_default()
+    }
+}
+
+/// Phases of a crawl timed by the [`Profiler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Loading the page
+    Goto,
+    /// Waiting for the items to show up
+    Wait,
+    /// Scrolling to load all the items
+    Scroll,
+    /// Reading the page source
+    Source,
+    /// Extracting the items from the page source
+    Parse,
+    /// Writing the output files
+    Write,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 6] = [
+        Phase::Goto,
+        Phase::Wait,
+        Phase::Scroll,
+        Phase::Source,
+        Phase::Parse,
+        Phase::Write,
+    ];
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Phase::Goto => "goto",
+            Phase::Wait => "wait",
+            Phase::Scroll => "scroll",
+            Phase::Source => "source",
+            Phase::Parse => "parse",
+            Phase::Write => "write",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Collector of the time spent on each [`Phase`], recording does nothing (not even reading
+/// the clock) unless created with [`Profiler::enabled`]
+#[derive(Debug, Clone, Default)]
+pub struct Profiler(Option<Arc<std::sync::Mutex<BTreeMap<Phase, Duration>>>>);
+
+impl Profiler {
+    pub fn enabled() -> Self {
+        Self(Some(Default::default()))
+    }
+
+    pub fn new(enabled: bool) -> Self {
+        if enabled {
+            Self::enabled()
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Starts timing a phase, to be passed to [`Profiler::record`]
+    pub fn start(&self) -> Option<Instant> {
+        self.0.as_ref().map(|_| Instant::now())
+    }
+
+    /// Adds the time elapsed since `start` to `phase`
+    pub fn record(&self, phase: Phase, start: Option<Instant>) {
+        if let (Some(totals), Some(start)) = (&self.0, start) {
+            *totals.lock().unwrap().entry(phase).or_default() += start.elapsed();
+        }
+    }
+
+    /// Time spent on each phase, empty if disabled
+    pub fn totals(&self) -> Vec<(Phase, Duration)> {
+        self.0
+            .as_ref()
+            .map(|totals| totals.lock().unwrap().clone().into_iter().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Collector of the attribute names found on the item elements, recording does nothing
+/// unless created with [`SchemaTracker::enabled`]
+#[derive(Debug, Clone, Default)]
+pub struct SchemaTracker(Option<Arc<std::sync::Mutex<BTreeSet<String>>>>);
+
+impl SchemaTracker {
+    pub fn enabled() -> Self {
+        Self(Some(Default::default()))
+    }
+
+    pub fn new(enabled: bool) -> Self {
+        if enabled {
+            Self::enabled()
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Adds the attribute names of `element`
+    pub fn record(&self, element: ElementRef) {
+        if let Some(keys) = &self.0 {
+            let mut keys = keys.lock().unwrap();
+            for (key, _) in element.value().attrs() {
+                if !keys.contains(key) {
+                    keys.insert(key.to_string());
                 }
+            }
+        }
+    }
+
+    /// Union of the attribute names recorded, `None` if disabled
+    pub fn keys(&self) -> Option<BTreeSet<String>> {
+        self.0.as_ref().map(|keys| keys.lock().unwrap().clone())
+    }
+}
+
+/// Result of scraping all the subroutes of a spider
+#[derive(Debug)]
+pub struct ScrapeSummary<T> {
+    pub items: Vec<T>,
+    /// Whether some subroutes were skipped because the time budget was exceeded
+    pub budget_exceeded: bool,
+    /// Whether some subroutes were skipped because [`Spider::max_total_items`] was reached
+    pub capped: bool,
+    /// Outcome of each scraped subroute, in completion order
+    pub statuses: Vec<SubrouteStatus>,
+}
+
+/// Outcome of scraping a subroute: the number of items found or the error message
+#[derive(Debug, Clone, Serialize)]
+pub struct SubrouteStatus {
+    pub subroute: String,
+    pub result: Result<usize, String>,
+}
+
+/// Joins `base` and `sub` with a single `/`, regardless of trailing/leading slashes
+pub fn join_url(base: &str, sub: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        sub.trim_start_matches('/')
+    )
+}
+
+/// Extracts a value from an item element, see [`FieldSelector`]
+#[derive(Debug)]
+pub struct FieldExtractor {
+    selector: Option<Selector>,
+    attr: Option<String>,
+}
+
+impl TryFrom<&FieldSelector> for FieldExtractor {
+    type Error = SpiderError;
+
+    fn try_from(field: &FieldSelector) -> Result<Self, Self::Error> {
+        let selector = field
+            .selector
+            .as_deref()
+            .map(|x| Selector::parse(x).map_err(|_| SpiderError::InvalidSelector(x.to_string())))
+            .transpose()?;
+        Ok(Self {
+            selector,
+            attr: field.attr.clone(),
+        })
+    }
+}
+
+impl FieldExtractor {
+    pub fn extract(&self, element: ElementRef) -> Option<String> {
+        let element = match &self.selector {
+            Some(selector) => element.select(selector).next()?,
+            None => element,
+        };
+        let value = match &self.attr {
+            Some(attr) => element.value().attr(attr)?.trim().to_string(),
+            None => element.text().collect::<String>().trim().to_string(),
+        };
+        (!value.is_empty()).then_some(value)
+    }
+
+    /// Extracts and parses the value, parsing errors are logged and ignored
+    pub fn extract_with<T>(
+        &self,
+        element: ElementRef,
+        parse: impl Fn(&str) -> Result<T, SpiderError>,
+    ) -> Option<T> {
+        let value = self.extract(element)?;
+        match parse(&value) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                tracing::debug!(error.cause_chain = ?e, error.message = %e, "Failed to parse field.");
+                None
+            }
+        }
+    }
+}
+
+/// Builds a [`FieldExtractor`] from `field` (if given)
+pub fn field_extractor(
+    field: Option<&FieldSelector>,
+) -> Result<Option<FieldExtractor>, SpiderError> {
+    field.map(FieldExtractor::try_from).transpose()
+}
+
+/// Parses the `selectors` keeping their source to be logged
+pub fn parse_selectors(selectors: &[String]) -> Result<Vec<(String, Selector)>, SpiderError> {
+    selectors
+        .iter()
+        .map(|x| {
+            Selector::parse(x)
+                .map(|selector| (x.clone(), selector))
+                .map_err(|_| SpiderError::InvalidSelector(x.clone()))
+        })
+        .collect()
+}
+
+/// Compiles an optional css `selector`
+pub fn parse_optional_selector(selector: Option<&str>) -> Result<Option<Selector>, SpiderError> {
+    selector
+        .map(|x| Selector::parse(x).map_err(|_| SpiderError::InvalidSelector(x.to_string())))
+        .transpose()
+}
+
+/// Compiles the `replacements` applied to the page sources
+pub fn html_preprocessor(
+    replacements: &[HtmlReplacement],
+) -> Result<Vec<(Regex, String)>, SpiderError> {
+    replacements
+        .iter()
+        .map(|x| {
+            Regex::new(&x.pattern)
+                .map(|re| (re, x.replacement.clone()))
+                .map_err(|_| SpiderError::InvalidRegex(x.pattern.clone()))
+        })
+        .collect()
+}
+
+/// Applies the `replacements` in order to the page `source`, only copying it when a
+/// pattern matches
+pub fn preprocess_html<'a>(source: &'a str, replacements: &[(Regex, String)]) -> Cow<'a, str> {
+    replacements
+        .iter()
+        .fold(Cow::Borrowed(source), |source, (re, replacement)| match re
+            .replace_all(&source, replacement.as_str())
+        {
+            Cow::Borrowed(_) => source,
+            Cow::Owned(x) => Cow::Owned(x),
+        })
+}
+
+/// Selects the elements matching `selector`, if there are none, tries the `fallbacks` in
+/// order and uses the first one with matches
+pub fn select_with_fallback<'a>(
+    html: &'a Html,
+    selector: &Selector,
+    fallbacks: &[(String, Selector)],
+) -> Vec<ElementRef<'a>> {
+    let elements = html.select(selector).collect::<Vec<_>>();
+    if !elements.is_empty() {
+        return elements;
+    }
+    fallbacks
+        .iter()
+        .find_map(|(source, selector)| {
+            let elements = html.select(selector).collect::<Vec<_>>();
+            (!elements.is_empty()).then(|| {
+                tracing::warn!("Using fallback selector: {:?}", source);
+                elements
             })
-            .collect::<Vec<_>>()
+        })
+        .unwrap_or_default()
+}
+
+/// How the items are extracted from the page source
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtractionMode {
+    /// Read the attributes of the elements matching the item selector
+    #[default]
+    Css,
+    /// Read the items from the json state embedded on a `<script>` (eg: `__NEXT_DATA__`)
+    EmbeddedJson {
+        /// Id of the `<script>` with the json
+        script_id: String,
+        /// Dot separated path to the items array (eg: `props.pageProps.products`),
+        /// numeric segments index arrays
+        items_path: String,
+        /// Item attribute to the dot separated path of its value inside each item
+        /// (eg: `data-price: price.amount`)
+        field_map: HashMap<String, String>,
+    },
+}
+
+/// Reads the items of the json embedded on the `<script>` with id `script_id`, mapping each
+/// entry of the `items_path` array to the attributes on `field_map`. Missing and null values
+/// are skipped
+pub fn embedded_json_items(
+    html: &Html,
+    script_id: &str,
+    items_path: &str,
+    field_map: &HashMap<String, String>,
+) -> Result<Vec<HashMap<String, String>>, SpiderError> {
+    static SELECTOR: OnceLock<Selector> = OnceLock::new();
+    let selector =
+        SELECTOR.get_or_init(|| Selector::parse("script[id]").expect("Invalid script selector"));
+    let script = html
+        .select(selector)
+        .find(|x| x.value().id() == Some(script_id))
+        .with_context(|| format!("Failed to find script with id {:?}", script_id))?;
+    let value = serde_json::from_str::<serde_json::Value>(&script.text().collect::<String>())
+        .with_context(|| format!("Failed to parse json of script {:?}", script_id))?;
+    let items = json_path(&value, items_path)
+        .and_then(serde_json::Value::as_array)
+        .with_context(|| format!("Failed to find items array at {:?}", items_path))?;
+    let items = items
+        .iter()
+        .map(|item| {
+            field_map
+                .iter()
+                .filter_map(|(attr, path)| {
+                    let value = match json_path(item, path)? {
+                        serde_json::Value::Null => return None,
+                        serde_json::Value::String(x) => x.clone(),
+                        x => x.to_string(),
+                    };
+                    Some((attr.clone(), value))
+                })
+                .collect()
+        })
+        .collect();
+    Ok(items)
+}
+
+/// Walks the dot separated `path` on `value`, numeric segments index arrays
+fn json_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    path.split('.')
+        .filter(|x| !x.is_empty())
+        .try_fold(value, |value, key| match value {
+            serde_json::Value::Array(values) => values.get(key.parse::<usize>().ok()?),
+            value => value.get(key),
+        })
+}
+
+/// Condition to wait for on the items locator after loading a page
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitCondition {
+    /// The element is in the DOM
+    #[default]
+    Present,
+    /// The element is in the DOM and displayed
+    Visible,
+}
+
+/// Goes to `url`, runs `pre_scrape_js` and waits for `css_locator` to meet `wait_condition`
+/// If `iframe_selector` is given the client enters that frame before waiting, so the
+/// following commands (and the page source) apply to it until [`leave_frame`]
+/// Returns `false` if the wait timed out
+pub async fn navigate(
+    client: &Client,
+    url: &str,
+    pre_scrape_js: Option<&str>,
+    iframe_selector: Option<&str>,
+    css_locator: &str,
+    wait_condition: WaitCondition,
+    profiler: &Profiler,
+) -> Result<bool, SpiderError> {
+    let start = profiler.start();
+    client.goto(url).await.context("Failed to go to url")?;
+    if let Some(js) = pre_scrape_js {
+        run_pre_scrape_js(client, js).await?;
+    }
+    profiler.record(Phase::Goto, start);
+    let start = profiler.start();
+    if let Some(iframe_selector) = iframe_selector {
+        client
+            .wait()
+            .at_most(Duration::from_secs(5))
+            .for_element(Locator::Css(iframe_selector))
+            .await
+            .with_context(|| format!("Failed to find iframe {:?}", iframe_selector))?
+            .enter_frame()
+            .await
+            .context("Failed to enter iframe")?;
+    }
+    let found = wait_for_element(client, css_locator, wait_condition, Duration::from_secs(5)).await;
+    profiler.record(Phase::Wait, start);
+    Ok(found)
+}
+
+/// Switches the client back to the top-level document after [`navigate`] entered an iframe,
+/// failures are only logged since the next navigation resets the frame anyway
+pub async fn leave_frame(client: &Client, iframe_selector: Option<&str>) {
+    if iframe_selector.is_none() {
+        return;
+    }
+    if let Err(e) = client.enter_frame(None).await {
+        tracing::warn!(error.message = %e, "Failed to leave iframe.");
+    }
+}
+
+/// Waits at most `timeout` for `css_locator` to meet `wait_condition`
+/// Returns `false` if it timed out
+async fn wait_for_element(
+    client: &Client,
+    css_locator: &str,
+    wait_condition: WaitCondition,
+    timeout: Duration,
+) -> bool {
+    match wait_condition {
+        WaitCondition::Present => client
+            .wait()
+            .at_most(timeout)
+            .for_element(Locator::Css(css_locator))
+            .await
+            .is_ok(),
+        WaitCondition::Visible => {
+            let start = Instant::now();
+            while start.elapsed() < timeout {
+                if let Ok(element) = client.find(Locator::Css(css_locator)).await {
+                    if element.is_displayed().await.unwrap_or(false) {
+                        return true;
+                    }
+                }
+                sleep(Duration::from_millis(250)).await;
+            }
+            tracing::debug!("Timed out waiting for {:?} to be visible", css_locator);
+            false
+        }
+    }
+}
+
+/// Removes the query params in `params` (or all of them if `all` is set) from `url`,
+/// relative urls are supported
+pub fn strip_query_params(url: &str, params: &[String], all: bool) -> String {
+    if params.is_empty() && !all {
+        return url.to_string();
+    }
+    static RELATIVE_BASE: OnceLock<Url> = OnceLock::new();
+    let relative_base =
+        RELATIVE_BASE.get_or_init(|| Url::parse("http://localhost").expect("Invalid base url"));
+    let (mut parsed, relative) = match Url::parse(url) {
+        Ok(parsed) => (parsed, false),
+        Err(url::ParseError::RelativeUrlWithoutBase) => match relative_base.join(url) {
+            Ok(parsed) => (parsed, true),
+            Err(_) => return url.to_string(),
+        },
+        Err(_) => return url.to_string(),
+    };
+    let pairs = parsed
+        .query_pairs()
+        .into_owned()
+        .filter(|(k, _)| !all && !params.contains(k))
+        .collect::<Vec<_>>();
+    if pairs.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    if relative {
+        parsed[Position::BeforePath..].to_string()
+    } else {
+        parsed.to_string()
+    }
+}
+
+/// What to do with a page source bigger than the limit
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedSource {
+    /// Parse only the first `max_bytes`
+    #[default]
+    Truncate,
+    /// Skip the subroute
+    Skip,
+}
+
+/// Limit on the size of the page sources
+#[derive(Debug, Clone, Copy)]
+pub struct SourceLimit {
+    pub max_bytes: usize,
+    pub action: OversizedSource,
+}
+
+/// Applies `limit` to the page `source` of `url`, truncating it (at a char boundary) or
+/// returning [`SpiderError::SourceTooLarge`] when it's exceeded
+pub fn limit_source(
+    mut source: String,
+    limit: Option<SourceLimit>,
+    url: &str,
+) -> Result<String, SpiderError> {
+    let limit = match limit {
+        Some(limit) if source.len() > limit.max_bytes => limit,
+        _ => return Ok(source),
+    };
+    tracing::warn!(
+        "Page source of {} bytes exceeds the limit of {} bytes",
+        source.len(),
+        limit.max_bytes
+    );
+    match limit.action {
+        OversizedSource::Truncate => {
+            let end = (0..=limit.max_bytes)
+                .rev()
+                .find(|&i| source.is_char_boundary(i))
+                .unwrap_or(0);
+            source.truncate(end);
+            Ok(source)
+        }
+        OversizedSource::Skip => Err(SpiderError::SourceTooLarge {
+            url: url.to_string(),
+            size: source.len(),
+        }),
+    }
+}
+
+/// Locks the webdriver `client`, failing if the spider is offline (no client)
+pub async fn lock_client(
+    client: &Mutex<Option<Client>>,
+) -> Result<MappedMutexGuard<'_, Client>, SpiderError> {
+    MutexGuard::try_map(client.lock().await, Option::as_mut)
+        .map_err(|_| anyhow!("The spider is offline, it can only extract saved pages").into())
+}
+
+/// Ends the webdriver session of `client`, leaving the spider offline
+pub async fn close_client(client: &Mutex<Option<Client>>) -> Result<(), SpiderError> {
+    if let Some(client) = client.lock().await.take() {
+        client
+            .close()
+            .await
+            .context("Failed to close the webdriver session")?;
+    }
+    Ok(())
+}
+
+/// Connects to the webdriver at `webdriver_url` with the chrome capabilities on `settings`,
+/// `None` for offline spiders
+pub async fn connect_client(
+    settings: &Settings,
+    webdriver_url: Option<&str>,
+) -> Result<Option<Client>, SpiderError> {
+    let webdriver_url = match webdriver_url {
+        Some(webdriver_url) => webdriver_url,
+        None => return Ok(None),
+    };
+    let mut client = ClientBuilder::rustls();
+    if let Some(caps) = chrome_capabilities(
+        settings.headless,
+        settings.accept_language.as_deref(),
+        settings.capabilities.as_ref(),
+    ) {
+        client.capabilities(caps);
+    }
+    let client = client
+        .connect(webdriver_url)
+        .await
+        .context("Error connecting to webdriver")?;
+    Ok(Some(client))
+}
+
+/// Chrome capabilities to run headless and/or with the given `accept_language`
+/// (`intl.accept_languages` pref), with the configured `capabilities` merged over them
+/// (see [`merge_json`]), `None` if there is nothing to set
+pub fn chrome_capabilities(
+    headless: bool,
+    accept_language: Option<&str>,
+    capabilities: Option<&serde_json::Value>,
+) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let mut chrome_opts = serde_json::Map::new();
+    if headless {
+        chrome_opts.insert(
+            "args".to_string(),
+            serde_json::json!(["--headless", "--disable-gpu"]),
+        );
+    }
+    if let Some(accept_language) = accept_language {
+        chrome_opts.insert(
+            "prefs".to_string(),
+            serde_json::json!({ "intl.accept_languages": accept_language }),
+        );
+    }
+    let mut caps = serde_json::Map::new();
+    if !chrome_opts.is_empty() {
+        caps.insert("goog:chromeOptions".to_string(), chrome_opts.into());
+    }
+    let mut caps = serde_json::Value::Object(caps);
+    if let Some(capabilities) = capabilities {
+        merge_json(&mut caps, capabilities);
+    }
+    match caps {
+        serde_json::Value::Object(caps) if !caps.is_empty() => Some(caps),
+        serde_json::Value::Object(_) => None,
+        _ => {
+            tracing::warn!("Ignoring capabilities, expected an object: {}", caps);
+            None
+        }
+    }
+}
+
+/// Merges `overlay` into `base` recursively, objects are merged key by key while any
+/// other value from `overlay` replaces the one on `base`
+pub fn merge_json(base: &mut serde_json::Value, overlay: &serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (k, v) in overlay {
+                merge_json(base.entry(k.clone()).or_insert(serde_json::Value::Null), v);
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+/// Returns [`SpiderError::Blocked`] if the `blocked_indicator` element is present
+pub async fn check_blocked(
+    client: &Client,
+    blocked_indicator: &str,
+    url: &str,
+) -> Result<(), SpiderError> {
+    match client.find(Locator::Css(blocked_indicator)).await {
+        Ok(_) => Err(SpiderError::Blocked(url.to_string())),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Hosts a spider is allowed to land on: the one of `base_url` and `extra_hosts`
+pub fn allowed_hosts(base_url: &str, extra_hosts: &[String]) -> Vec<String> {
+    Url::parse(base_url)
+        .ok()
+        .and_then(|x| x.host_str().map(String::from))
+        .into_iter()
+        .chain(extra_hosts.iter().cloned())
+        .collect()
+}
+
+/// Returns [`SpiderError::UnexpectedHost`] if the current page is not on one of `hosts`
+pub async fn check_host(client: &Client, hosts: &[String], url: &str) -> Result<(), SpiderError> {
+    let current_url = client
+        .current_url()
+        .await
+        .context("Failed to obtain current url")?;
+    check_url_host(&current_url, hosts, url)
+}
+
+/// Returns [`SpiderError::UnexpectedHost`] if `current_url` is not on one of `hosts`
+fn check_url_host(current_url: &Url, hosts: &[String], url: &str) -> Result<(), SpiderError> {
+    let host = current_url.host_str().unwrap_or_default();
+    if hosts.iter().any(|x| x.eq_ignore_ascii_case(host)) {
+        Ok(())
+    } else {
+        Err(SpiderError::UnexpectedHost {
+            url: url.to_string(),
+            host: host.to_string(),
+        })
+    }
+}
+
+/// Counts the elements matched by `selector` and how many of them have a value for each of
+/// the `fields`, or at least one match for each of the `children` selectors
+pub fn count_matches(
+    html: &Html,
+    selector: &Selector,
+    fields: &[(&str, Option<&FieldExtractor>)],
+    children: &[(&str, Option<&Selector>)],
+) -> Vec<(String, usize)> {
+    let elements = html.select(selector).collect::<Vec<_>>();
+    let mut counts = vec![("items".to_string(), elements.len())];
+    fields.iter().for_each(|(name, field)| {
+        if let Some(field) = field {
+            let n = elements
+                .iter()
+                .filter(|&&element| field.extract(element).is_some())
+                .count();
+            counts.push((name.to_string(), n));
+        }
+    });
+    children.iter().for_each(|(name, child)| {
+        if let Some(child) = child {
+            let n = elements
+                .iter()
+                .filter(|element| element.select(child).next().is_some())
+                .count();
+            counts.push((name.to_string(), n));
+        }
+    });
+    counts
+}
+
+/// Saves a screenshot of the current page on `{dir}/{spider}_{subroute-slug}.png`,
+/// the file is written on the background and errors are only logged
+#[tracing::instrument(skip(client))]
+pub async fn save_screenshot(client: &Client, dir: &Path, spider: &str, url: &str) {
+    let png = match client.screenshot().await {
+        Ok(png) => png,
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to take screenshot.");
+            return;
+        }
+    };
+    let dir = dir.to_path_buf();
+    let path = dir.join(format!("{}_{}.png", spider, last_segment_slug(url)));
+    tokio::spawn(
+        async move {
+            let res = async {
+                create_dir_all(&dir).await?;
+                tokio::fs::write(&path, png).await
+            }
+            .await;
+            match res {
+                Ok(()) => tracing::info!("Saved screenshot on {:?}", path),
+                Err(e) => {
+                    tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to save screenshot.")
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Runs `js` on the current page, script errors are returned as a scrape error
+#[tracing::instrument(skip(client))]
+pub async fn run_pre_scrape_js(client: &Client, js: &str) -> Result<(), SpiderError> {
+    tracing::debug!("Running pre scrape script");
+    client
+        .execute(js, vec![])
+        .await
+        .context("Failed to run pre scrape script")?;
+    Ok(())
+}
+
+/// Selects a delivery zone on `base_url` by setting its cookies and clicking its selector
+#[tracing::instrument(skip(client))]
+pub async fn apply_zone(
+    client: &Client,
+    base_url: &str,
+    zone: &ZoneConfig,
+    delay: Duration,
+) -> Result<(), SpiderError> {
+    client
+        .goto(base_url)
+        .await
+        .context("Failed to go to base url")?;
+    for (name, value) in &zone.cookies {
+        client
+            .add_cookie(Cookie::new(name.clone(), value.clone()))
+            .await
+            .with_context(|| format!("Failed to set cookie: {:?}", name))?;
+    }
+    if let Some(selector) = &zone.selector {
+        client
+            .wait()
+            .at_most(Duration::from_secs(5))
+            .for_element(Locator::Css(selector))
+            .await
+            .with_context(|| format!("Failed to find zone selector: {:?}", selector))?
+            .click()
             .await
-            .into_iter()
-            .flatten()
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect()
+            .with_context(|| format!("Failed to click zone selector: {:?}", selector))?;
+    }
+    sleep(delay).await;
+    Ok(())
+}
+
+/// Maximum length of the element html included in errors
+const MAX_ERROR_HTML_LEN: usize = 500;
+
+/// Truncates `x` to at most `max_len` bytes (on a char boundary)
+fn truncate(x: &str, max_len: usize) -> String {
+    if x.len() <= max_len {
+        return x.to_string();
+    }
+    let mut end = max_len;
+    while !x.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &x[..end])
+}
+
+/// Minimum ratio of extracted/expected items before warning
+const ITEM_COUNT_TOLERANCE: f64 = 0.9;
+
+/// Reads the expected number of items from the text of the element matching `selector`
+pub fn item_count_estimate(html: &Html, selector: &Selector) -> Option<usize> {
+    let text = html.select(selector).next()?.text().collect::<String>();
+    let count = parse_item_count(&text);
+    match count {
+        Some(count) => tracing::info!("Expected {} elements", count),
+        None => tracing::warn!("Failed to parse item count from: {:?}", text),
+    }
+    count
+}
+
+/// Parses the first number in `x`, ignoring `.` and `,` thousands separators (eg: "1.234 productos")
+pub fn parse_item_count(x: &str) -> Option<usize> {
+    x.trim_start_matches(|c: char| !c.is_ascii_digit())
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == ',')
+        .filter(char::is_ascii_digit)
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+/// Whether an optional text field has a non blank value
+pub fn has_text(value: &Option<String>) -> bool {
+    value.as_deref().is_some_and(|x| !x.trim().is_empty())
+}
+
+/// Logs a failed item extraction, returning the item if successful
+pub fn log_item_error<T>(
+    item: Result<T, SpiderError>,
+    subroute: &str,
+    warnings: &Warnings,
+) -> Option<T> {
+    match item {
+        Ok(item) => Some(item),
+        Err(e) => {
+            tracing::error!(error.cause_chain = ?e, error.message = %e, "Failed to extract item.");
+            warnings.push(subroute, WarningKind::ParseFailure, e);
+            None
+        }
+    }
+}
+
+/// Warns if the number of extracted items is significantly lower than the expected one
+pub fn check_item_count(expected: usize, found: usize) {
+    if (found as f64) < (expected as f64) * ITEM_COUNT_TOLERANCE {
+        tracing::warn!(
+            "Found {} elements but expected {}, the selector may be broken",
+            found,
+            expected
+        );
+    }
+}
+
+/// Parses a rating allowing comma decimals (eg: "4,5")
+pub fn parse_rating(x: &str) -> Result<f64, SpiderError> {
+    let rating = x
+        .replace(',', ".")
+        .trim()
+        .parse::<f64>()
+        .with_context(|| format!("Failed to parse rating from: {:?}", x))?;
+    Ok(rating)
+}
+
+/// Parses a review count ignoring parentheses and thousands separators (eg: "(1.234)")
+pub fn parse_reviews(x: &str) -> Result<u32, SpiderError> {
+    let reviews = parse_item_count(x)
+        .and_then(|x| u32::try_from(x).ok())
+        .with_context(|| format!("Failed to parse reviews from: {:?}", x))?;
+    Ok(reviews)
+}
+
+/// Currency symbols and their ISO code, longer symbols first
+const CURRENCY_SYMBOLS: &[(&str, &str)] =
+    &[("S/.", "PEN"), ("S/", "PEN"), ("US$", "USD"), ("$", "USD")];
+
+/// Price with the currency detected from its symbol
+/// Serialized as the bare amount, or as an object with the amount and currency if
+/// `structured` is set (written as json on csv cells, see [`serialize_csv_row`])
+#[derive(Debug, Clone, PartialEq)]
+pub struct Price {
+    pub amount: f64,
+    pub currency: Option<String>,
+    pub structured: bool,
+}
+
+impl Serialize for Price {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.structured {
+            return serializer.serialize_f64(self.amount);
+        }
+        if CSV_ROW.with(Cell::get) {
+            let value = serde_json::json!({ "amount": self.amount, "currency": self.currency });
+            return serializer.serialize_str(&value.to_string());
+        }
+        let mut state = serializer.serialize_struct("Price", 2)?;
+        state.serialize_field("amount", &self.amount)?;
+        state.serialize_field("currency", &self.currency)?;
+        state.end()
+    }
+}
+
+thread_local! {
+    /// Set while serializing a csv row, see [`serialize_csv_row`]
+    static CSV_ROW: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Writes `item` as a row of `wtr`. csv has no nested values (a nested struct is spread over
+/// several cells), so the structured prices are written as json on their cell instead
+pub fn serialize_csv_row<W: Write, T: Serialize>(
+    wtr: &mut csv::Writer<W>,
+    item: &T,
+) -> csv::Result<()> {
+    /// Unsets [`CSV_ROW`] when dropped, even if serializing the item panics
+    struct Reset;
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            CSV_ROW.with(|x| x.set(false));
+        }
+    }
+
+    CSV_ROW.with(|x| x.set(true));
+    let _reset = Reset;
+    wtr.serialize(item)
+}
+
+impl FromStr for Price {
+    type Err = SpiderError;
+
+    /// Parses a serialized price, either bare or structured
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        if let Ok(amount) = x.parse::<f64>() {
+            return Ok(Self {
+                amount,
+                currency: None,
+                structured: false,
+            });
+        }
+        let value = serde_json::from_str::<serde_json::Value>(x)
+            .with_context(|| format!("Failed to parse price from: {:?}", x))?;
+        let amount = value["amount"]
+            .as_f64()
+            .with_context(|| format!("Failed to parse price from: {:?}", x))?;
+        Ok(Self {
+            amount,
+            currency: value["currency"].as_str().map(String::from),
+            structured: true,
+        })
+    }
+}
+
+/// Range of the valid prices, both ends included, an unset end isn't checked
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct PriceBounds {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl PriceBounds {
+    pub fn contains(&self, amount: f64) -> bool {
+        self.min.is_none_or(|min| amount >= min) && self.max.is_none_or(|max| amount <= max)
+    }
+
+    /// Drops the `price` of the item `id` if it's out of bounds, reporting it on `warnings`
+    pub fn apply(&self, price: &mut Option<Price>, id: &str, url: &str, warnings: &Warnings) {
+        if let Some(amount) = price.as_ref().map(|x| x.amount) {
+            if !self.contains(amount) {
+                tracing::debug!(id = %id, amount, "Dropping out of bounds price.");
+                warnings.push(
+                    url,
+                    WarningKind::PriceOutOfBounds,
+                    format!("Price {} out of bounds for item {:?}", amount, id),
+                );
+                *price = None;
+            }
+        }
+    }
+}
+
+/// Composes a price rendered as separate integer and decimal parts (eg: "S/ 9" and "90"),
+/// the decimal part is ignored if missing or without digits
+pub fn compose_price(int_txt: &str, dec_txt: Option<&str>) -> Option<Price> {
+    let int_txt = int_txt.trim().trim_end_matches(['.', ',']);
+    if !int_txt.chars().any(|c| c.is_ascii_digit()) {
+        return None;
     }
+    let dec = dec_txt
+        .unwrap_or_default()
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    let price = if dec.is_empty() {
+        parse_price(int_txt)
+    } else {
+        parse_price(&format!("{}.{}", int_txt, dec))
+    };
+    price.ok()
 }
 
-pub fn parse_price(x: &str) -> Result<f64, SpiderError> {
-    let price = x
-        .replace("S/.", "")
-        .replace("S/", "")
+/// Parses a price, detecting the currency from its symbol (eg: "S/ 1,234.50")
+pub fn parse_price(x: &str) -> Result<Price, SpiderError> {
+    let currency = CURRENCY_SYMBOLS
+        .iter()
+        .find(|(symbol, _)| x.contains(symbol))
+        .map(|(_, code)| code.to_string());
+    let amount = CURRENCY_SYMBOLS
+        .iter()
+        .fold(x.to_string(), |x, (symbol, _)| x.replace(symbol, ""))
         .replace(',', "")
         .trim()
         .parse::<f64>()
         .with_context(|| format!("Failed to parse price from: {:?}", x))?;
-    Ok(price)
+    Ok(Price {
+        amount,
+        currency,
+        structured: false,
+    })
+}
+
+/// Removes the longest of `prefixes` found at the start of a price text (case insensitive,
+/// eg: "Desde S/ 9.90" -> "S/ 9.90"), returning whether there was one
+pub fn strip_price_prefix<'a>(x: &'a str, prefixes: &[String]) -> (&'a str, bool) {
+    let x = x.trim_start();
+    let prefix_len = prefixes
+        .iter()
+        .map(|prefix| prefix.trim())
+        .filter(|prefix| {
+            !prefix.is_empty()
+                && x.get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+                && !x[prefix.len()..].starts_with(char::is_alphanumeric)
+        })
+        .map(str::len)
+        .max();
+    match prefix_len {
+        Some(len) => (x[len..].trim_start(), true),
+        None => (x, false),
+    }
+}
+
+/// Parses a price without its prefix (see [`strip_price_prefix`]), returning whether it
+/// had one
+pub fn parse_prefixed_price(x: &str, prefixes: &[String]) -> Result<(Price, bool), SpiderError> {
+    let (x, prefixed) = strip_price_prefix(x, prefixes);
+    parse_price(x).map(|price| (price, prefixed))
+}
+
+/// Whether `element` or one of its ancestors matches the `exclude` selector
+pub fn is_excluded(element: ElementRef, exclude: Option<&Selector>) -> bool {
+    let exclude = match exclude {
+        Some(exclude) => exclude,
+        None => return false,
+    };
+    exclude.matches(&element)
+        || element
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .any(|ancestor| exclude.matches(&ancestor))
+}
+
+/// Builds a stable id for items without a natural one, hashing their `uri`,
+/// or their `name` and `brand` when missing
+pub fn synthesize_id(uri: Option<&str>, name: Option<&str>, brand: Option<&str>) -> Option<String> {
+    let key = match (uri, name) {
+        (Some(uri), _) => format!("uri:{}", uri),
+        (None, Some(name)) => format!("name:{}|brand:{}", name, brand.unwrap_or_default()),
+        (None, None) => return None,
+    };
+    let digest = Sha256::digest(key.as_bytes());
+    let hex = digest[..8]
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<String>();
+    Some(format!("synth-{}", hex))
+}
+
+/// json-ld keys holding a barcode, in order of preference
+const JSON_LD_EAN_KEYS: &[&str] = &["gtin13", "gtin", "gtin14", "gtin12", "gtin8", "ean"];
+
+/// Gets the EAN barcode of an item from its `ean_attr` attribute, falling back to the
+/// json-ld blocks inside the item element
+pub fn extract_ean(element: ElementRef, ean_attr: &str) -> Option<String> {
+    if let Some(ean) = element.value().attr(ean_attr) {
+        return Some(ean.trim().to_string()).filter(|x| !x.is_empty());
+    }
+    static SELECTOR: OnceLock<Selector> = OnceLock::new();
+    let selector = SELECTOR.get_or_init(|| {
+        Selector::parse(r#"script[type="application/ld+json"]"#).expect("Invalid json-ld selector")
+    });
+    element.select(selector).find_map(|script| {
+        let value = serde_json::from_str(&script.text().collect::<String>()).ok()?;
+        find_json_ld_ean(&value)
+    })
+}
+
+/// Searches recursively for a barcode on a json-ld value
+fn find_json_ld_ean(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Object(map) => JSON_LD_EAN_KEYS
+            .iter()
+            .find_map(|key| match map.get(*key)? {
+                serde_json::Value::String(x) if !x.trim().is_empty() => Some(x.trim().to_string()),
+                serde_json::Value::Number(x) => Some(x.to_string()),
+                _ => None,
+            })
+            .or_else(|| map.values().find_map(find_json_ld_ean)),
+        serde_json::Value::Array(values) => values.iter().find_map(find_json_ld_ean),
+        _ => None,
+    }
+}
+
+/// Splits the brand from the start of a product name (eg: "NESTLE Leche Ideal 400g" ->
+/// `(Some("NESTLE"), "Leche Ideal 400g")`): the longest of `known_brands` the name starts
+/// with (case insensitive), or the leading uppercase words without digits if `known_brands`
+/// is empty. At least one word is left on the name
+pub fn split_brand_from_name(name: &str, known_brands: &[String]) -> (Option<String>, String) {
+    let words = name.split_whitespace().collect::<Vec<_>>();
+    let (n, brand) = if known_brands.is_empty() {
+        let n = words
+            .iter()
+            .take_while(|word| {
+                word.chars().any(char::is_alphabetic)
+                    && !word.chars().any(|c| c.is_lowercase() || c.is_ascii_digit())
+            })
+            .count()
+            .min(words.len().saturating_sub(1));
+        (n, words[..n].join(" "))
+    } else {
+        known_brands
+            .iter()
+            .filter_map(|brand| {
+                let brand_words = brand.split_whitespace().collect::<Vec<_>>();
+                let n = brand_words.len();
+                let matches = n > 0
+                    && n < words.len()
+                    && words
+                        .iter()
+                        .zip(&brand_words)
+                        .all(|(a, b)| a.to_lowercase() == b.to_lowercase());
+                matches.then(|| (n, brand.trim().to_string()))
+            })
+            .max_by_key(|(n, _)| *n)
+            .unwrap_or_default()
+    };
+    if n == 0 {
+        return (None, name.to_string());
+    }
+    (Some(brand), words[n..].join(" "))
+}
+
+/// Parses the pack size from a product name (eg: "Leche 6x400g" -> `(2400.0, "g")`),
+/// multipacks return the total quantity
+pub fn parse_quantity(name: &str) -> Option<(f64, String)> {
+    static QUANTITY_RE: OnceLock<Regex> = OnceLock::new();
+    static PACK_RE: OnceLock<Regex> = OnceLock::new();
+    let quantity_re = QUANTITY_RE.get_or_init(|| {
+        Regex::new(
+            r"(?i)(?:(\d+)\s*x\s*)?(\d+(?:[.,]\d+)?)\s*(kg|grs|gr|g|ml|litros|litro|lts|lt|l|unidades|unid|und|un)\b",
+        )
+        .expect("Invalid quantity regex")
+    });
+    let pack_re = PACK_RE.get_or_init(|| {
+        Regex::new(r"(?i)\bpack\s*(?:x|de)?\s*(\d+)\b").expect("Invalid pack regex")
+    });
+    if let Some(caps) = quantity_re.captures_iter(name).last() {
+        let count = caps
+            .get(1)
+            .and_then(|x| x.as_str().parse::<f64>().ok())
+            .unwrap_or(1.0);
+        let quantity = caps[2].replace(',', ".").parse::<f64>().ok()?;
+        let unit = match caps[3].to_lowercase().as_str() {
+            "kg" => "kg",
+            "grs" | "gr" | "g" => "g",
+            "ml" => "ml",
+            "litros" | "litro" | "lts" | "lt" | "l" => "l",
+            _ => "un",
+        };
+        return Some((count * quantity, unit.to_string()));
+    }
+    pack_re
+        .captures(name)
+        .and_then(|caps| caps[1].parse::<f64>().ok())
+        .map(|count| (count, "un".to_string()))
+}
+
+/// Gets the attributes of `element`, keeping only the ones in `collect_attrs` if given
+pub fn filter_attrs<'a>(
+    element: ElementRef<'a>,
+    collect_attrs: Option<&'a [String]>,
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    element.value().attrs().filter(move |(k, _)| {
+        collect_attrs
+            .map(|attrs| attrs.iter().any(|x| x == k))
+            .unwrap_or(true)
+    })
+}
+
+/// Gets the text of every element matching `selector` inside `element`, with the
+/// whitespace normalized and skipping empty texts
+pub fn select_texts(element: ElementRef, selector: &Selector) -> Vec<String> {
+    element
+        .select(selector)
+        .map(|x| {
+            x.text()
+                .collect::<String>()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Serializes a list as a json column, empty lists are left blank
+pub fn serialize_list<S>(values: &[String], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if values.is_empty() {
+        return serializer.serialize_none();
+    }
+    let json = serde_json::to_string(values).map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&json)
+}
+
+/// Serializes a map (eg: prices by label) as a json column sorted by key,
+/// empty maps are left blank
+pub fn serialize_json_map<S, V>(map: &HashMap<String, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    if map.is_empty() {
+        return serializer.serialize_none();
+    }
+    let map = map.iter().collect::<BTreeMap<_, _>>();
+    let json = serde_json::to_string(&map).map_err(serde::ser::Error::custom)?;
+    serializer.serialize_str(&json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_url_with_trailing_slash_base() {
+        assert_eq!(
+            join_url("https://www.metro.pe/", "desayuno"),
+            "https://www.metro.pe/desayuno"
+        );
+    }
+
+    #[test]
+    fn join_url_with_leading_slash_subroute() {
+        assert_eq!(
+            join_url("https://www.metro.pe", "/desayuno"),
+            "https://www.metro.pe/desayuno"
+        );
+    }
+
+    #[test]
+    fn join_url_with_both_slashes() {
+        assert_eq!(
+            join_url("https://www.metro.pe//", "//desayuno/lacteos"),
+            "https://www.metro.pe/desayuno/lacteos"
+        );
+    }
+
+    #[test]
+    fn join_url_keeps_scheme_slashes() {
+        let url = join_url("https://www.metro.pe/", "/desayuno");
+        assert!(url.starts_with("https://www.metro.pe/"));
+        assert!(!url["https://".len()..].contains("//"));
+    }
+
+    #[test]
+    fn parse_rating_with_comma_decimal() {
+        assert_eq!(parse_rating("4,5").unwrap(), 4.5);
+        assert_eq!(parse_rating(" 3.8 ").unwrap(), 3.8);
+        assert!(parse_rating("sin calificar").is_err());
+    }
+
+    #[test]
+    fn parse_reviews_with_parentheses_and_thousands() {
+        assert_eq!(parse_reviews("(1.234)").unwrap(), 1234);
+        assert_eq!(parse_reviews("(12)").unwrap(), 12);
+        assert!(parse_reviews("()").is_err());
+    }
+
+    #[test]
+    fn select_with_fallback_uses_first_matching_fallback() {
+        let html = Html::parse_document(
+            r#"<div class="new-card">a</div><div class="new-card">b</div><div class="other">c</div>"#,
+        );
+        let selector = Selector::parse(".product-item").unwrap();
+        let fallbacks = parse_selectors(&[
+            ".missing".to_string(),
+            ".new-card".to_string(),
+            ".other".to_string(),
+        ])
+        .unwrap();
+        let elements = select_with_fallback(&html, &selector, &fallbacks);
+        let texts = elements.iter().map(|x| x.inner_html()).collect::<Vec<_>>();
+        assert_eq!(texts, ["a", "b"]);
+    }
+
+    #[test]
+    fn select_with_fallback_prefers_primary_selector() {
+        let html = Html::parse_document(
+            r#"<div class="product-item">a</div><div class="new-card">b</div>"#,
+        );
+        let selector = Selector::parse(".product-item").unwrap();
+        let fallbacks = parse_selectors(&[".new-card".to_string()]).unwrap();
+        let elements = select_with_fallback(&html, &selector, &fallbacks);
+        assert_eq!(elements.len(), 1);
+        assert_eq!(elements[0].inner_html(), "a");
+    }
+
+    fn some(quantity: f64, unit: &str) -> Option<(f64, String)> {
+        Some((quantity, unit.to_string()))
+    }
+
+    #[test]
+    fn parse_quantity_weights() {
+        assert_eq!(parse_quantity("Arroz Costeño Extra 750g"), some(750.0, "g"));
+        assert_eq!(parse_quantity("Azúcar Rubia 1 Kg"), some(1.0, "kg"));
+        assert_eq!(parse_quantity("Queso Fresco 0,5 kg"), some(0.5, "kg"));
+        assert_eq!(
+            parse_quantity("Fideos Don Vittorio 500 grs"),
+            some(500.0, "g")
+        );
+    }
+
+    #[test]
+    fn parse_quantity_volumes() {
+        assert_eq!(parse_quantity("Aceite Primor 900ml"), some(900.0, "ml"));
+        assert_eq!(parse_quantity("Gaseosa Inca Kola 1.5 L"), some(1.5, "l"));
+        assert_eq!(parse_quantity("Agua San Luis 2.5 Litros"), some(2.5, "l"));
+    }
+
+    #[test]
+    fn parse_quantity_counts() {
+        assert_eq!(
+            parse_quantity("Huevos Rosados 15 unidades"),
+            some(15.0, "un")
+        );
+        assert_eq!(
+            parse_quantity("Papel Higiénico Elite Pack x 24"),
+            some(24.0, "un")
+        );
+    }
+
+    #[test]
+    fn parse_quantity_multipacks() {
+        assert_eq!(parse_quantity("Cerveza Pilsen 6x355ml"), some(2130.0, "ml"));
+        assert_eq!(parse_quantity("Leche Gloria 6 x 400 g"), some(2400.0, "g"));
+    }
+
+    #[test]
+    fn parse_quantity_without_quantity() {
+        assert_eq!(parse_quantity("Palta Fuerte"), None);
+    }
+
+    #[test]
+    fn strip_query_params_makes_utm_variants_equal() {
+        let params = ["utm_source", "utm_medium", "utm_campaign"].map(String::from);
+        let a = strip_query_params(
+            "https://www.metro.pe/leche-gloria/p?utm_source=google&sc=1&utm_medium=cpc",
+            &params,
+            false,
+        );
+        let b = strip_query_params(
+            "https://www.metro.pe/leche-gloria/p?sc=1&utm_campaign=verano",
+            &params,
+            false,
+        );
+        assert_eq!(a, b);
+        assert_eq!(a, "https://www.metro.pe/leche-gloria/p?sc=1");
+    }
+
+    #[test]
+    fn strip_query_params_all_on_relative_uri() {
+        assert_eq!(
+            strip_query_params("/leche-gloria/p?utm_source=google&sc=1", &[], true),
+            "/leche-gloria/p"
+        );
+    }
+
+    /// First element matching `selector` on `html`
+    fn first<'a>(html: &'a Html, selector: &str) -> ElementRef<'a> {
+        html.select(&Selector::parse(selector).unwrap())
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn extract_ean_from_json_ld() {
+        let html = Html::parse_document(
+            r#"<div class="card">
+                <script type="application/ld+json">
+                {"@context": "https://schema.org", "@type": "Product", "name": "Leche Gloria",
+                 "offers": {"@type": "Offer", "price": "4.20"}, "gtin13": "7751271001234"}
+                </script>
+            </div>"#,
+        );
+        let card = first(&html, ".card");
+        assert_eq!(
+            extract_ean(card, "data-ean").as_deref(),
+            Some("7751271001234")
+        );
+    }
+
+    #[test]
+    fn extract_ean_prefers_attribute() {
+        let html = Html::parse_document(
+            r#"<div class="card" data-ean="7750000000001">
+                <script type="application/ld+json">{"gtin13": "7751271001234"}</script>
+            </div>"#,
+        );
+        let card = first(&html, ".card");
+        assert_eq!(
+            extract_ean(card, "data-ean").as_deref(),
+            Some("7750000000001")
+        );
+    }
+
+    #[test]
+    fn check_url_host_accepts_allowed_hosts() {
+        let hosts = allowed_hosts("https://www.metro.pe", &["cdn.metro.pe".to_string()]);
+        for current_url in ["https://www.metro.pe/desayuno", "https://CDN.metro.pe/x"] {
+            let current_url = Url::parse(current_url).unwrap();
+            assert!(check_url_host(&current_url, &hosts, "https://www.metro.pe/desayuno").is_ok());
+        }
+    }
+
+    #[test]
+    fn check_url_host_rejects_redirect_to_unexpected_host() {
+        let hosts = allowed_hosts("https://www.metro.pe", &[]);
+        let current_url = Url::parse("https://login.example.com/?next=/desayuno").unwrap();
+        let res = check_url_host(&current_url, &hosts, "https://www.metro.pe/desayuno");
+        assert!(matches!(
+            res,
+            Err(SpiderError::UnexpectedHost { host, .. }) if host == "login.example.com"
+        ));
+    }
+
+    #[test]
+    fn chrome_capabilities_merges_configured_capabilities() {
+        let capabilities = serde_json::json!({
+            "goog:chromeOptions": {
+                "args": ["--window-size=1920,1080"],
+                "prefs": { "profile.default_content_setting_values.images": 2 }
+            },
+            "proxy": { "proxyType": "manual", "httpProxy": "localhost:8080" }
+        });
+        let caps = chrome_capabilities(true, Some("es-PE"), Some(&capabilities)).unwrap();
+        assert_eq!(
+            serde_json::Value::Object(caps),
+            serde_json::json!({
+                "goog:chromeOptions": {
+                    "args": ["--window-size=1920,1080"],
+                    "prefs": {
+                        "intl.accept_languages": "es-PE",
+                        "profile.default_content_setting_values.images": 2
+                    }
+                },
+                "proxy": { "proxyType": "manual", "httpProxy": "localhost:8080" }
+            })
+        );
+    }
+
+    #[test]
+    fn chrome_capabilities_without_options() {
+        assert!(chrome_capabilities(false, None, None).is_none());
+        assert!(chrome_capabilities(false, None, Some(&serde_json::json!({}))).is_none());
+    }
+
+    #[derive(Serialize)]
+    struct PricedItem {
+        id: &'static str,
+        price: Price,
+    }
+
+    fn priced_item() -> PricedItem {
+        PricedItem {
+            id: "1",
+            price: Price {
+                amount: 4.2,
+                currency: Some("PEN".to_string()),
+                structured: true,
+            },
+        }
+    }
+
+    #[test]
+    fn structured_price_is_an_object() {
+        let value = serde_json::to_value(priced_item()).unwrap();
+        assert_eq!(
+            value["price"],
+            serde_json::json!({ "amount": 4.2, "currency": "PEN" })
+        );
+    }
+
+    #[test]
+    fn structured_price_is_json_on_csv_cells() {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        serialize_csv_row(&mut wtr, &priced_item()).unwrap();
+        let data = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        let mut rdr = csv::Reader::from_reader(data.as_bytes());
+        let row = rdr.records().next().unwrap().unwrap();
+        assert_eq!(row.len(), 2);
+        let price = row[1].parse::<Price>().unwrap();
+        assert_eq!(price.amount, 4.2);
+        assert_eq!(price.currency.as_deref(), Some("PEN"));
+        // Outside csv rows the price is an object again
+        assert!(serde_json::to_value(priced_item()).unwrap()["price"].is_object());
+    }
+
+    fn price(amount: f64) -> Option<Price> {
+        Some(Price {
+            amount,
+            currency: Some("PEN".to_string()),
+            structured: false,
+        })
+    }
+
+    #[test]
+    fn price_bounds_drop_zero_price_below_min() {
+        let bounds = PriceBounds {
+            min: Some(0.01),
+            max: None,
+        };
+        let warnings = Warnings::enabled();
+        let mut zero = price(0.0);
+        bounds.apply(&mut zero, "1", "http://mock/a", &warnings);
+        assert!(zero.is_none());
+        let mut valid = price(0.01);
+        bounds.apply(&mut valid, "2", "http://mock/a", &warnings);
+        assert_eq!(valid.map(|x| x.amount), Some(0.01));
+        let warnings = warnings.take();
+        assert_eq!(warnings.len(), 1);
+        assert!(matches!(warnings[0].kind, WarningKind::PriceOutOfBounds));
+    }
+
+    #[test]
+    fn price_bounds_drop_huge_price_above_max() {
+        let bounds = PriceBounds {
+            min: None,
+            max: Some(10_000.0),
+        };
+        let warnings = Warnings::default();
+        let mut huge = price(1_234_567.0);
+        bounds.apply(&mut huge, "1", "http://mock/a", &warnings);
+        assert!(huge.is_none());
+        let mut valid = price(10_000.0);
+        bounds.apply(&mut valid, "2", "http://mock/a", &warnings);
+        assert!(valid.is_some());
+    }
+
+    #[test]
+    fn price_bounds_default_keeps_every_price() {
+        let bounds = PriceBounds::default();
+        assert!(bounds.contains(-1.0));
+        assert!(bounds.contains(0.0));
+        assert!(bounds.contains(f64::MAX));
+    }
+
+    fn split(name: &str, known_brands: &[&str]) -> (Option<String>, String) {
+        let known_brands = known_brands
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        split_brand_from_name(name, &known_brands)
+    }
+
+    #[test]
+    fn split_brand_from_name_uppercase_heuristic() {
+        assert_eq!(
+            split("NESTLE Leche Ideal 400g", &[]),
+            (Some("NESTLE".to_string()), "Leche Ideal 400g".to_string())
+        );
+        assert_eq!(
+            split("LA FLORENCIA Aceite Vegetal 1L", &[]),
+            (
+                Some("LA FLORENCIA".to_string()),
+                "Aceite Vegetal 1L".to_string()
+            )
+        );
+        assert_eq!(
+            split("Leche Gloria 400g", &[]),
+            (None, "Leche Gloria 400g".to_string())
+        );
+        assert_eq!(split("NESTLE", &[]), (None, "NESTLE".to_string()));
+    }
+
+    #[test]
+    fn split_brand_from_name_known_brands() {
+        let known_brands = ["Gloria", "Don Vittorio", "Don"];
+        assert_eq!(
+            split("DON VITTORIO Spaghetti 500g", &known_brands),
+            (
+                Some("Don Vittorio".to_string()),
+                "Spaghetti 500g".to_string()
+            )
+        );
+        assert_eq!(
+            split("gloria Leche Evaporada 400g", &known_brands),
+            (
+                Some("Gloria".to_string()),
+                "Leche Evaporada 400g".to_string()
+            )
+        );
+        assert_eq!(
+            split("NESTLE Leche Ideal 400g", &known_brands),
+            (None, "NESTLE Leche Ideal 400g".to_string())
+        );
+        assert_eq!(split("Gloria", &known_brands), (None, "Gloria".to_string()));
+    }
+
+    fn prefixes() -> Vec<String> {
+        ["Desde", "Hasta", "Desde solo"].map(String::from).to_vec()
+    }
+
+    #[test]
+    fn parse_prefixed_price_with_desde() {
+        let (price, prefixed) = parse_prefixed_price("Desde S/ 9.90", &prefixes()).unwrap();
+        assert_eq!(price.amount, 9.9);
+        assert_eq!(price.currency.as_deref(), Some("PEN"));
+        assert!(prefixed);
+        let (price, prefixed) = parse_prefixed_price("desde solo S/ 12.50", &prefixes()).unwrap();
+        assert_eq!(price.amount, 12.5);
+        assert!(prefixed);
+    }
+
+    #[test]
+    fn parse_prefixed_price_without_prefix() {
+        let (price, prefixed) = parse_prefixed_price("S/ 9.90", &prefixes()).unwrap();
+        assert_eq!(price.amount, 9.9);
+        assert!(!prefixed);
+    }
+
+    #[test]
+    fn strip_price_prefix_needs_a_word_boundary() {
+        let prefixes = ["Des".to_string()];
+        assert_eq!(
+            strip_price_prefix("Desde S/ 9.90", &prefixes),
+            ("Desde S/ 9.90", false)
+        );
+    }
+
+    #[test]
+    fn compose_price_from_parts() {
+        let compose = |int_txt, dec_txt| compose_price(int_txt, dec_txt).map(|x| x.amount);
+        assert_eq!(compose("S/ 9", Some("90")), Some(9.9));
+        assert_eq!(compose("S/ 9.", Some("90")), Some(9.9));
+        assert_eq!(compose("S/ 1,234", Some(",50")), Some(1234.5));
+        assert_eq!(
+            compose_price("S/ 9", Some("90")).and_then(|x| x.currency),
+            Some("PEN".to_string())
+        );
+    }
+
+    #[test]
+    fn compose_price_with_missing_decimals() {
+        let compose = |int_txt, dec_txt| compose_price(int_txt, dec_txt).map(|x| x.amount);
+        assert_eq!(compose("S/ 9", None), Some(9.0));
+        assert_eq!(compose("S/ 9,", Some("")), Some(9.0));
+        assert_eq!(compose("S/ 9", Some("--")), Some(9.0));
+    }
+
+    #[test]
+    fn compose_price_without_integer_part() {
+        assert!(compose_price("S/", Some("90")).is_none());
+        assert!(compose_price("Consultar", None).is_none());
+    }
 }
@@ -1,20 +1,44 @@
-use super::{Spider, SpiderError};
+use super::{
+    allowed_hosts, apply_zone, check_blocked, check_host, check_item_count, close_client,
+    compose_price, connect_client, count_matches, detail_scraper, embedded_json_items, extract_ean,
+    field_extractor, html_preprocessor, is_excluded, item_count_estimate, leave_frame,
+    limit_source, lock_client, log_item_error, navigate, parse_optional_selector,
+    parse_prefixed_price, parse_quantity, parse_rating, parse_reviews, parse_selectors,
+    preprocess_html, save_screenshot, select_texts, select_with_fallback, serialize_json_map,
+    serialize_list, split_brand_from_name, strip_price_prefix, CommonRow, DetailScraper,
+    ExtractionMode, FieldExtractor, LatencyTracker, Phase, Profiler, SchemaTracker, SourceLimit,
+    Spider, SpiderError, SpiderItem, WaitCondition, WarningKind, Warnings,
+};
 use crate::{
-    configuration::{MultipageSpiderSettings, Settings},
-    spiders::parse_price,
+    configuration::{BrandFromName, ExpectedCount, MultipageSpiderSettings, Settings, ZoneConfig},
+    spiders::{filter_attrs, has_text, parse_price, synthesize_id, Price, PriceBounds},
 };
 use anyhow::Context;
 use async_trait::async_trait;
-use fantoccini::{Client, ClientBuilder, Locator};
+use fantoccini::Client;
+use regex::Regex;
 use scraper::{ElementRef, Html, Selector};
 use serde::Serialize;
 use std::{
     collections::{HashMap, HashSet},
     fmt,
     hash::Hash,
+    path::PathBuf,
     time::Duration,
 };
-use tokio::{sync::Mutex, time::sleep};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Instant},
+};
+
+/// Extractors used to obtain the item data, see [`add_to_map`]
+const EXTRACTORS: &[(&str, bool, &[&str])] = &[
+    (".Showcase__content", false, &["title"]),
+    (".Showcase__brand a", false, &[]),
+    (".Showcase__priceBox__title", true, &[]),
+    (".Showcase__link", false, &["href"]),
+    (".Showcase__salePrice", false, &["data-price"]),
+];
 
 pub struct MultipageSpider {
     name: String,
@@ -22,9 +46,78 @@ pub struct MultipageSpider {
     subroutes: Vec<String>,
     css_locator: String,
     selector: Selector,
-    /// Mutex is used to lock multiple access to the webdriver
-    client: Mutex<Client>,
+    /// Mutex is used to lock multiple access to the webdriver, `None` on offline spiders
+    client: Mutex<Option<Client>>,
     delay: Duration,
+    /// Fallback to extract the sku from the product `href`
+    sku_from_href_regex: Option<Regex>,
+    /// Attributes to collect from the matched elements, all if `None`
+    collect_attrs: Option<Vec<String>>,
+    zones: Vec<ZoneConfig>,
+    expected_counts: Vec<ExpectedCount>,
+    /// Element with the expected number of items
+    count_selector: Option<Selector>,
+    /// JavaScript to run after loading a page
+    pre_scrape_js: Option<String>,
+    /// Frame holding the items
+    iframe_selector: Option<String>,
+    price: Option<FieldExtractor>,
+    rating: Option<FieldExtractor>,
+    reviews: Option<FieldExtractor>,
+    seller: Option<FieldExtractor>,
+    time_budget: Option<Duration>,
+    /// Selectors to try in order when `selector` doesn't match any element
+    fallback_selectors: Vec<(String, Selector)>,
+    /// Regex replacements applied to the page sources before parsing
+    html_preprocess: Vec<(Regex, String)>,
+    /// Where to read the items from
+    extraction: ExtractionMode,
+    /// Where to save screenshots of subroutes without items
+    screenshot_dir: Option<PathBuf>,
+    /// Element only present on captcha/anti-bot pages
+    blocked_indicator_selector: Option<String>,
+    blocked_backoff: Option<Duration>,
+    /// Hosts the pages must be on, if verified
+    allowed_hosts: Option<Vec<String>>,
+    wait_condition: WaitCondition,
+    /// Query params removed from the item uris
+    strip_query_params: Vec<String>,
+    strip_all_query: bool,
+    /// Serialize prices with their currency
+    structured_price: bool,
+    /// Prices outside these bounds are dropped
+    price_bounds: PriceBounds,
+    /// Build an id for the items without one
+    synthesize_id: bool,
+    /// Take the missing brands from the start of the item names
+    brand_from_name: Option<BrandFromName>,
+    /// Values for the missing item fields
+    field_defaults: HashMap<String, String>,
+    /// Limit on the size of the page sources
+    source_limit: Option<SourceLimit>,
+    /// Page load latency, to scale the delay between subroutes
+    latency_tracker: Option<LatencyTracker>,
+    /// Time spent on each phase of the scrapes
+    profiler: Profiler,
+    /// Attribute names found on the item elements
+    schema_tracker: SchemaTracker,
+    /// Item attribute with the EAN barcode
+    ean_attr: String,
+    /// Promotion badges inside the items
+    promotions_selector: Option<Selector>,
+    /// Items to skip, matching it themselves or inside a match
+    exclude_selector: Option<Selector>,
+    /// Words before the price of variable-price items
+    price_prefixes: Vec<String>,
+    /// Integer and decimal parts of the price, used when it's missing
+    price_int_selector: Option<Selector>,
+    price_dec_selector: Option<Selector>,
+    /// Fetches the item attributes from their detail pages
+    details: Option<DetailScraper>,
+    /// Subroutes failing in a row before skipping the rest
+    max_consecutive_failures: Option<usize>,
+    /// Items across all subroutes before skipping the rest
+    max_total_items: Option<usize>,
 }
 
 impl fmt::Display for MultipageSpider {
@@ -40,110 +133,404 @@ impl fmt::Display for MultipageSpider {
 }
 
 impl MultipageSpider {
-    pub async fn new(
-        name: impl ToString,
-        base_url: impl ToString,
-        subroutes: Vec<impl ToString>,
-        css_selector: &str,
-        delay_milis: u64,
-        headless: bool,
+    /// Builds the spider from the global `settings` (headless, delays, output options...) and
+    /// its `spider_settings`, connecting to `webdriver_url` (offline if `None`, see
+    /// [`Spider::extract_items`])
+    ///
+    /// ```no_run
+    /// # use peru_prices::{configuration::get_configuration, spiders::MultipageSpider};
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let configuration = get_configuration()?;
+    /// let webdriver_url = configuration.webdriver_endpoint(2)?;
+    /// let spider = MultipageSpider::from_settings(
+    ///     &configuration,
+    ///     &configuration.plaza_vea,
+    ///     Some(&webdriver_url),
+    /// )
+    /// .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn from_settings(
+        settings: &Settings,
+        spider_settings: &MultipageSpiderSettings,
+        webdriver_url: Option<&str>,
     ) -> Result<Self, SpiderError> {
-        let subroutes = subroutes.into_iter().map(|x| x.to_string()).collect();
+        let common = &spider_settings.common;
+        let css_selector = &common.selector;
         let selector = Selector::parse(css_selector)
             .map_err(|_| SpiderError::InvalidSelector(css_selector.to_string()))?;
-        let mut client = ClientBuilder::rustls();
-        if headless {
-            let mut caps = serde_json::map::Map::new();
-            let chrome_opts = serde_json::json!({ "args": ["--headless", "--disable-gpu"] });
-            caps.insert("goog:chromeOptions".to_string(), chrome_opts);
-            client.capabilities(caps);
-        }
-
-        let client = client
-            .connect("http://localhost:4444")
-            .await
-            .context("Error connecting to webdriver")?;
+        let count_selector = parse_optional_selector(common.count_selector.as_deref())?;
+        let fallback_selectors = parse_selectors(&common.fallback_selectors)?;
+        let html_preprocess = html_preprocessor(&common.html_preprocess)?;
+        let price = field_extractor(common.price.as_ref())?;
+        let rating = field_extractor(common.rating.as_ref())?;
+        let reviews = field_extractor(common.reviews.as_ref())?;
+        let seller = field_extractor(common.seller.as_ref())?;
+        let promotions_selector = parse_optional_selector(common.promotions_selector.as_deref())?;
+        let exclude_selector = parse_optional_selector(common.exclude_selector.as_deref())?;
+        let price_int_selector = parse_optional_selector(common.price_int_selector.as_deref())?;
+        let price_dec_selector = parse_optional_selector(common.price_dec_selector.as_deref())?;
+        let sku_from_href_regex = spider_settings
+            .sku_from_href_regex
+            .as_deref()
+            .map(|x| Regex::new(x).map_err(|_| SpiderError::InvalidRegex(x.to_string())))
+            .transpose()?;
+        let details = detail_scraper(settings, common.fetch_details, common.details.as_ref())?;
+        let client = connect_client(settings, webdriver_url).await?;
 
         Ok(Self {
-            name: name.to_string(),
-            base_url: base_url.to_string(),
-            subroutes,
-            css_locator: css_selector.to_string(),
+            name: common.name.clone(),
+            base_url: common.base_url.clone(),
+            subroutes: common.subroutes.clone(),
+            css_locator: css_selector.clone(),
             selector,
             client: Mutex::new(client),
-            delay: Duration::from_millis(delay_milis),
+            delay: Duration::from_millis(settings.delay_milis),
+            sku_from_href_regex,
+            collect_attrs: common.collect_attrs.clone(),
+            zones: common.zones.clone(),
+            expected_counts: common.expected_counts.clone(),
+            count_selector,
+            pre_scrape_js: common.pre_scrape_js.clone(),
+            iframe_selector: common.iframe_selector.clone(),
+            price,
+            rating,
+            reviews,
+            seller,
+            time_budget: common.time_budget_secs.map(Duration::from_secs),
+            fallback_selectors,
+            html_preprocess,
+            extraction: common.extraction.clone(),
+            screenshot_dir: settings
+                .screenshot_on_empty
+                .then(|| settings.out_path.join("screenshots")),
+            blocked_indicator_selector: common.blocked_indicator_selector.clone(),
+            blocked_backoff: common.blocked_backoff_secs.map(Duration::from_secs),
+            allowed_hosts: common
+                .verify_host
+                .then(|| allowed_hosts(&common.base_url, &common.extra_hosts)),
+            wait_condition: common.wait_condition,
+            strip_query_params: common.strip_query_params.clone(),
+            strip_all_query: common.strip_all_query,
+            structured_price: settings.structured_price,
+            price_bounds: settings.price_bounds,
+            synthesize_id: settings.synthesize_id,
+            brand_from_name: common.brand_from_name.clone(),
+            field_defaults: settings.field_defaults.clone(),
+            source_limit: settings.source_limit(),
+            latency_tracker: settings.latency_tracker(),
+            profiler: Profiler::new(settings.profile),
+            schema_tracker: SchemaTracker::new(settings.track_schema),
+            ean_attr: common.ean_attr.clone(),
+            promotions_selector,
+            exclude_selector,
+            price_prefixes: common.price_prefixes.clone(),
+            price_int_selector,
+            price_dec_selector,
+            details,
+            max_consecutive_failures: common.max_consecutive_failures,
+            max_total_items: common.max_total_items,
         })
     }
 
-    pub async fn from_settings(
-        settings: &Settings,
-        spider_settings: &MultipageSpiderSettings,
-    ) -> Result<Self, SpiderError> {
-        Self::new(
-            spider_settings.name.clone(),
-            spider_settings.base_url.clone(),
-            spider_settings.subroutes.clone(),
-            &spider_settings.selector,
-            settings.delay_milis,
-            settings.headless,
+    /// Returns the page items and the expected number of items (only read on the first page)
+    #[tracing::instrument(skip(self, warnings))]
+    async fn scrape_page(
+        &self,
+        url: &str,
+        page: usize,
+        warnings: &Warnings,
+    ) -> Result<(Vec<MultipageItem>, Option<usize>), SpiderError> {
+        let client = lock_client(&self.client).await?;
+        let load_start = Instant::now();
+        let found = navigate(
+            &client,
+            &page_url(url, page),
+            self.pre_scrape_js.as_deref(),
+            self.iframe_selector.as_deref(),
+            &self.css_locator,
+            self.wait_condition,
+            &self.profiler,
         )
-        .await
+        .await?;
+        if let Some(tracker) = &self.latency_tracker {
+            tracker.record(load_start.elapsed());
+        }
+        // Pages after the last one have no items, so only the first one is expected to have them
+        if !found && page == 1 {
+            warnings.push(url, WarningKind::Timeout, "Timed out waiting for the items");
+        }
+        if let Some(hosts) = &self.allowed_hosts {
+            check_host(&client, hosts, url).await?;
+        }
+        self.check_blocked(&client, url).await?;
+        sleep(self.delay).await;
+        let start = self.profiler.start();
+        let document = client
+            .source()
+            .await
+            .context("Failed to obtain html content")?;
+        self.profiler.record(Phase::Source, start);
+        let document = limit_source(document, self.source_limit, url)?;
+        let start = self.profiler.start();
+        let (elements, expected) = self.parse_page(&document, url, page, warnings);
+        self.profiler.record(Phase::Parse, start);
+        // An empty page after the first one is the normal end of the pagination
+        if page == 1 && elements.is_empty() {
+            if let Some(screenshot_dir) = &self.screenshot_dir {
+                save_screenshot(&client, screenshot_dir, &self.name, url).await;
+            }
+        }
+        leave_frame(&client, self.iframe_selector.as_deref()).await;
+        Ok((elements, expected))
     }
 
-    #[tracing::instrument(skip(self))]
-    async fn scrape_page(&self, url: &str, page: usize) -> Result<Vec<MultipageItem>, SpiderError> {
-        let document = {
-            let client = self.client.lock().await;
-            client
-                .goto(&format!("{}?page={}", url, page))
-                .await
-                .context("Failed to go to url")?;
-            let _ = client
-                .wait()
-                .at_most(Duration::from_secs(5))
-                .for_element(Locator::Css(&self.css_locator))
-                .await;
-            sleep(self.delay).await;
-            client
-                .source()
-                .await
-                .context("Failed to obtain html content")?
-        };
+    /// Extracts the items from the page source
+    /// Returns the page items and the expected number of items (only read on the first page)
+    fn parse_page(
+        &self,
+        document: &str,
+        url: &str,
+        page: usize,
+        warnings: &Warnings,
+    ) -> (Vec<MultipageItem>, Option<usize>) {
+        let document = preprocess_html(document, &self.html_preprocess);
         let html = Html::parse_document(&document);
+        let expected = match (&self.count_selector, page) {
+            (Some(selector), 1) => item_count_estimate(&html, selector),
+            _ => None,
+        };
         if html
             .select(&Selector::parse(".vitrine__products__comingSoon").unwrap())
             .next()
             .is_some()
         {
-            return Ok(Vec::new());
+            return (Vec::new(), expected);
         }
-        let elements = html
-            .select(&self.selector)
-            .filter_map(|element| {
-                let mut map = element
-                    .value()
-                    .attrs()
+        let elements = match &self.extraction {
+            ExtractionMode::Css => self.select_items(&html, url, page, warnings),
+            ExtractionMode::EmbeddedJson {
+                script_id,
+                items_path,
+                field_map,
+            } => {
+                let maps = log_item_error(
+                    embedded_json_items(&html, script_id, items_path, field_map),
+                    url,
+                    warnings,
+                )
+                .unwrap_or_default();
+                self.embedded_items(maps, url, page, warnings)
+            }
+        }
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+        tracing::info!("Found {} elements", elements.len());
+        (elements, expected)
+    }
+
+    /// Extracts the items from the elements matching the selector
+    fn select_items(
+        &self,
+        html: &Html,
+        url: &str,
+        page: usize,
+        warnings: &Warnings,
+    ) -> Vec<MultipageItem> {
+        select_with_fallback(html, &self.selector, &self.fallback_selectors)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, element)| !is_excluded(*element, self.exclude_selector.as_ref()))
+            .filter_map(|(i, element)| {
+                self.schema_tracker.record(element);
+                let mut map = filter_attrs(element, self.collect_attrs.as_deref())
                     .map(|(k, v)| (k.to_string(), v.to_string()))
                     .collect::<HashMap<_, _>>();
                 map.insert("category".to_string(), url.to_string());
-                add_to_map(
-                    &mut map,
-                    element,
-                    &[
-                        (".Showcase__content", false, &["title"]),
-                        (".Showcase__brand a", false, &[]),
-                        (".Showcase__priceBox__title", true, &[]),
-                        (".Showcase__link", false, &["href"]),
-                        (".Showcase__salePrice", false, &["data-price"]),
-                    ],
-                );
-                MultipageItem::try_from(map).ok()
+                add_to_map(&mut map, element, EXTRACTORS);
+                if let Some(re) = &self.sku_from_href_regex {
+                    add_sku_from_href(&mut map, re);
+                }
+                self.add_missing_sku(&mut map);
+                let prefixed = self.remove_price_prefix(&mut map);
+                if !map.contains_key("data-price") && map.contains_key(".Showcase__salePrice") {
+                    warnings.push(
+                        url,
+                        WarningKind::PriceFallback,
+                        format!("Missing data-price for item {:?}", map.get("data-sku")),
+                    );
+                }
+                log_item_error(
+                    MultipageItem::try_from(map).map_err(|e| e.with_element_html(element)),
+                    url,
+                    warnings,
+                )
+                .map(|mut item| {
+                    item.rank = Some(i + 1);
+                    item.price_is_from = prefixed;
+                    item.page = Some(page);
+                    self.transform(self.finalize_item(
+                        self.add_element_fields(item, element),
+                        url,
+                        warnings,
+                    ))
+                })
             })
-            .collect::<HashSet<_>>()
-            .into_iter()
-            .collect::<Vec<_>>();
-        tracing::info!("Found {} elements", elements.len());
-        Ok(elements)
+            .collect()
+    }
+
+    /// Builds the items from the attributes read from the json state embedded on the page,
+    /// see [`ExtractionMode`]
+    fn embedded_items(
+        &self,
+        maps: Vec<HashMap<String, String>>,
+        url: &str,
+        page: usize,
+        warnings: &Warnings,
+    ) -> Vec<MultipageItem> {
+        maps.into_iter()
+            .enumerate()
+            .filter_map(|(i, mut map)| {
+                map.insert("category".to_string(), url.to_string());
+                if let Some(re) = &self.sku_from_href_regex {
+                    add_sku_from_href(&mut map, re);
+                }
+                self.add_missing_sku(&mut map);
+                let prefixed = self.remove_price_prefix(&mut map);
+                let mut item = log_item_error(MultipageItem::try_from(map), url, warnings)?;
+                item.rank = Some(i + 1);
+                item.price_is_from = prefixed;
+                item.page = Some(page);
+                item.seller = Some(self.name.clone());
+                Some(self.transform(self.finalize_item(item, url, warnings)))
+            })
+            .collect()
+    }
+}
+
+impl MultipageSpider {
+    /// Checks if the current page is a captcha/anti-bot page, saving a screenshot if enabled
+    async fn check_blocked(&self, client: &Client, url: &str) -> Result<(), SpiderError> {
+        let blocked_indicator = match &self.blocked_indicator_selector {
+            Some(blocked_indicator) => blocked_indicator,
+            None => return Ok(()),
+        };
+        let res = check_blocked(client, blocked_indicator, url).await;
+        if res.is_err() {
+            if let Some(screenshot_dir) = &self.screenshot_dir {
+                save_screenshot(client, screenshot_dir, &self.name, url).await;
+            }
+        }
+        res
+    }
+
+    /// Normalizes the uri of `item` (see [`Spider::normalize_url`]), sets its price format
+    /// (dropping it if out of bounds), splits its missing brand from the name if enabled and
+    /// fills its missing fields with the configured defaults
+    fn finalize_item(
+        &self,
+        mut item: MultipageItem,
+        url: &str,
+        warnings: &Warnings,
+    ) -> MultipageItem {
+        item.source_url = Some(page_url(url, item.page.unwrap_or(1)));
+        item.uri = item.uri.map(|uri| self.normalize_url(&uri));
+        self.price_bounds
+            .apply(&mut item.price, &item.sku, url, warnings);
+        if let Some(price) = &mut item.price {
+            price.structured = self.structured_price;
+        }
+        if let (None, Some(name), Some(brand_from_name)) =
+            (&item.brand, &item.name, &self.brand_from_name)
+        {
+            let known_brands = match brand_from_name {
+                BrandFromName::Uppercase => &[][..],
+                BrandFromName::KnownBrands(brands) => brands,
+            };
+            let (brand, name) = split_brand_from_name(name, known_brands);
+            if brand.is_some() {
+                item.brand = brand;
+                item.name = Some(name);
+            }
+        }
+        item.apply_field_defaults(&self.field_defaults);
+        item
+    }
+
+    /// Removes the configured prefix of the prices on `map`, returning whether they had one
+    fn remove_price_prefix(&self, map: &mut HashMap<String, String>) -> bool {
+        let mut prefixed = false;
+        for key in ["data-price", ".Showcase__salePrice"] {
+            if let Some(price) = map.get_mut(key) {
+                let stripped = match strip_price_prefix(price, &self.price_prefixes) {
+                    (stripped, true) => stripped.to_string(),
+                    (_, false) => continue,
+                };
+                *price = stripped;
+                prefixed = true;
+            }
+        }
+        prefixed
+    }
+
+    /// Synthesizes a sku for the items without `data-sku`, if enabled
+    fn add_missing_sku(&self, map: &mut HashMap<String, String>) {
+        if !self.synthesize_id || map.contains_key("data-sku") {
+            return;
+        }
+        let uri = map.get("href").map(|x| self.normalize_url(x));
+        let sku = synthesize_id(
+            uri.as_deref(),
+            map.get("title").map(String::as_str),
+            map.get(".Showcase__brand a").map(String::as_str),
+        );
+        if let Some(sku) = sku {
+            tracing::debug!("Synthesized sku {:?}", sku);
+            map.insert("data-sku".to_string(), sku);
+        }
+    }
+
+    /// Adds the rating, review count, seller, ean, promotions and missing price from `element`
+    /// to `item`
+    fn add_element_fields(&self, mut item: MultipageItem, element: ElementRef) -> MultipageItem {
+        item.rating = self
+            .rating
+            .as_ref()
+            .and_then(|x| x.extract_with(element, parse_rating));
+        item.reviews = self
+            .reviews
+            .as_ref()
+            .and_then(|x| x.extract_with(element, parse_reviews));
+        item.seller = self
+            .seller
+            .as_ref()
+            .and_then(|x| x.extract(element))
+            .or_else(|| Some(self.name.clone()));
+        item.ean = extract_ean(element, &self.ean_attr);
+        if let Some(selector) = &self.promotions_selector {
+            item.promotions = select_texts(element, selector);
+        }
+        if item.price.is_none() {
+            let price = self.price.as_ref().and_then(|x| {
+                x.extract_with(element, |x| parse_prefixed_price(x, &self.price_prefixes))
+            });
+            if let Some((price, prefixed)) = price {
+                item.price = Some(price);
+                item.price_is_from = prefixed;
+            }
+        }
+        if let (None, Some(int_selector)) = (&item.price, &self.price_int_selector) {
+            let int_txt = select_texts(element, int_selector).into_iter().next();
+            let dec_txt = self
+                .price_dec_selector
+                .as_ref()
+                .and_then(|x| select_texts(element, x).into_iter().next());
+            item.price = int_txt.and_then(|x| compose_price(&x, dec_txt.as_deref()));
+        }
+        item
     }
 }
 
@@ -153,8 +540,32 @@ pub struct MultipageItem {
     pub name: Option<String>,
     pub brand: Option<String>,
     pub category: Option<String>,
+    /// Position on the listing page (starting at 1), counting the excluded cards and before
+    /// removing duplicates
+    pub rank: Option<usize>,
+    /// Page of the listing the item was found on
+    pub page: Option<usize>,
+    /// Url of the listing page the item was scraped from
+    pub source_url: Option<String>,
     pub uri: Option<String>,
-    pub price: Option<f64>,
+    pub price: Option<Price>,
+    /// The price had a prefix (eg: "Desde S/ 9.90"), it's the lowest of a variable price
+    pub price_is_from: bool,
+    pub zone: Option<String>,
+    /// Marketplace seller, the store itself for first-party items
+    pub seller: Option<String>,
+    pub rating: Option<f64>,
+    pub reviews: Option<u32>,
+    /// Pack size parsed from the name
+    pub quantity: Option<f64>,
+    pub quantity_unit: Option<String>,
+    pub ean: Option<String>,
+    /// Promotion badges (eg: "2x1", "-30%")
+    #[serde(serialize_with = "serialize_list")]
+    pub promotions: Vec<String>,
+    /// Attributes from the detail page
+    #[serde(serialize_with = "serialize_json_map")]
+    pub attributes: HashMap<String, String>,
 }
 
 impl PartialEq for MultipageItem {
@@ -163,8 +574,74 @@ impl PartialEq for MultipageItem {
     }
 }
 
-impl Eq for MultipageItem {
-    fn assert_receiver_is_total_eq(&self) {}
+impl Eq for MultipageItem {}
+
+impl SpiderItem for MultipageItem {
+    const ID_FIELD: &'static str = "sku";
+
+    fn id(&self) -> &str {
+        &self.sku
+    }
+
+    fn price(&self) -> Option<f64> {
+        self.price.as_ref().map(|x| x.amount)
+    }
+
+    fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    fn set_zone(&mut self, zone: &str) {
+        self.zone = Some(zone.to_string());
+    }
+
+    fn common_row(&self) -> CommonRow {
+        CommonRow::from(self)
+    }
+
+    fn set_attributes(&mut self, attributes: HashMap<String, String>) {
+        self.attributes = attributes;
+    }
+
+    fn optional_field_mut(&mut self, field: &str) -> Option<&mut Option<String>> {
+        match field {
+            "brand" => Some(&mut self.brand),
+            "name" => Some(&mut self.name),
+            "category" => Some(&mut self.category),
+            "uri" => Some(&mut self.uri),
+            _ => None,
+        }
+    }
+
+    fn has_field(&self, field: &str) -> bool {
+        match field {
+            "id" => !self.sku.trim().is_empty(),
+            "price" => self.price.is_some(),
+            "name" => has_text(&self.name),
+            "brand" => has_text(&self.brand),
+            "category" => has_text(&self.category),
+            "uri" => has_text(&self.uri),
+            "seller" => has_text(&self.seller),
+            "ean" => has_text(&self.ean),
+            _ => false,
+        }
+    }
+}
+
+impl From<&MultipageItem> for CommonRow {
+    fn from(item: &MultipageItem) -> Self {
+        Self {
+            store: String::new(),
+            zone: item.zone.clone(),
+            id: item.sku.clone(),
+            name: item.name.clone(),
+            brand: item.brand.clone(),
+            category: item.category.clone(),
+            price: item.price(),
+            uri: item.uri.clone(),
+            seller: item.seller.clone(),
+        }
+    }
 }
 
 impl Hash for MultipageItem {
@@ -176,18 +653,23 @@ impl Hash for MultipageItem {
 impl TryFrom<HashMap<String, String>> for MultipageItem {
     type Error = SpiderError;
 
-    #[tracing::instrument(err(Debug))]
+    #[tracing::instrument]
     fn try_from(mut map: HashMap<String, String>) -> Result<Self, Self::Error> {
         let sku = map.remove("data-sku").context("Failed to obtain item id")?;
         let name = map.remove("title");
         let brand = map.remove(".Showcase__brand a");
         let category = map.remove("category");
         let uri = map.remove("href");
-        let price = map
-            .get("data-price")
-            .or_else(|| map.get(".Showcase__salePrice"))
-            .map(|x| parse_price(x.as_str()))
-            .transpose()?;
+        let price = ["data-price", ".Showcase__salePrice"]
+            .into_iter()
+            .filter_map(|key| map.get(key))
+            .find_map(|x| match parse_price(x) {
+                Ok(price) => Some(price),
+                Err(e) => {
+                    tracing::debug!(error.message = %e, "Ignoring invalid price.");
+                    None
+                }
+            });
         if name.is_none()
             && brand.is_none()
             && category.is_none()
@@ -196,13 +678,27 @@ impl TryFrom<HashMap<String, String>> for MultipageItem {
         {
             Err(SpiderError::NoDataExtracted(format!("{:?}", map)))
         } else {
+            let (quantity, quantity_unit) = name.as_deref().and_then(parse_quantity).unzip();
             Ok(Self {
                 sku,
                 name,
                 brand,
                 category,
+                rank: None,
+                page: None,
                 uri,
                 price,
+                price_is_from: false,
+                source_url: None,
+                zone: None,
+                seller: None,
+                rating: None,
+                reviews: None,
+                quantity,
+                quantity_unit,
+                ean: None,
+                promotions: Vec::new(),
+                attributes: HashMap::new(),
             })
         }
     }
@@ -224,16 +720,76 @@ impl Spider for MultipageSpider {
         self.subroutes.as_slice()
     }
 
+    fn strip_query_params(&self) -> &[String] {
+        &self.strip_query_params
+    }
+
+    fn strip_all_query(&self) -> bool {
+        self.strip_all_query
+    }
+
+    fn max_consecutive_failures(&self) -> Option<usize> {
+        self.max_consecutive_failures
+    }
+
+    fn max_total_items(&self) -> Option<usize> {
+        self.max_total_items
+    }
+
+    fn latency_tracker(&self) -> Option<&LatencyTracker> {
+        self.latency_tracker.as_ref()
+    }
+
+    fn profiler(&self) -> Option<&Profiler> {
+        Some(&self.profiler)
+    }
+
+    fn schema_tracker(&self) -> Option<&SchemaTracker> {
+        Some(&self.schema_tracker)
+    }
+
+    async fn close(&self) -> Result<(), SpiderError> {
+        close_client(&self.client).await
+    }
+
+    fn max_concurrency(&self) -> usize {
+        // All subroutes share the same browser session
+        1
+    }
+
+    fn blocked_backoff(&self) -> Option<Duration> {
+        self.blocked_backoff
+    }
+
+    fn time_budget(&self) -> Option<Duration> {
+        self.time_budget
+    }
+
+    fn zones(&self) -> &[ZoneConfig] {
+        self.zones.as_slice()
+    }
+
+    fn expected_counts(&self) -> &[ExpectedCount] {
+        self.expected_counts.as_slice()
+    }
+
+    async fn set_zone(&self, zone: &ZoneConfig) -> Result<(), SpiderError> {
+        let client = lock_client(&self.client).await?;
+        apply_zone(&client, &self.base_url, zone, self.delay).await
+    }
+
     fn delay(&self) -> std::time::Duration {
         self.delay
     }
 
-    #[tracing::instrument(skip(self))]
-    async fn scrape(&self, url: &str) -> Result<Vec<Self::Item>, SpiderError> {
+    #[tracing::instrument(skip(self, warnings))]
+    async fn scrape(&self, url: &str, warnings: &Warnings) -> Result<Vec<Self::Item>, SpiderError> {
         let mut elements = Vec::new();
+        let mut expected = None;
         let mut page = 1;
         loop {
-            let mut res = self.scrape_page(url, page).await?;
+            let (mut res, page_expected) = self.scrape_page(url, page, warnings).await?;
+            expected = expected.or(page_expected);
             if res.is_empty() {
                 break;
             } else {
@@ -242,8 +798,75 @@ impl Spider for MultipageSpider {
             }
         }
         tracing::info!("Found {} elements", elements.len());
+        if let Some(expected) = expected {
+            check_item_count(expected, elements.len());
+        }
+        if let Some(details) = &self.details {
+            details.add_attributes(&mut elements, url, warnings).await;
+        }
         Ok(elements)
     }
+
+    #[tracing::instrument(skip(self))]
+    fn extract_items(&self, document: &str, url: &str, warnings: &Warnings) -> Vec<Self::Item> {
+        self.parse_page(document, url, 1, warnings).0
+    }
+
+    async fn validate_selectors_against(
+        &self,
+        url: &str,
+    ) -> Result<Vec<(String, usize)>, SpiderError> {
+        let document = {
+            let client = lock_client(&self.client).await?;
+            navigate(
+                &client,
+                url,
+                self.pre_scrape_js.as_deref(),
+                self.iframe_selector.as_deref(),
+                &self.css_locator,
+                self.wait_condition,
+                &self.profiler,
+            )
+            .await?;
+            sleep(self.delay).await;
+            let document = client
+                .source()
+                .await
+                .context("Failed to obtain html content")?;
+            leave_frame(&client, self.iframe_selector.as_deref()).await;
+            document
+        };
+        let html = Html::parse_document(&document);
+        let mut counts = count_matches(
+            &html,
+            &self.selector,
+            &[
+                ("price", self.price.as_ref()),
+                ("rating", self.rating.as_ref()),
+                ("reviews", self.reviews.as_ref()),
+                ("seller", self.seller.as_ref()),
+            ],
+            &[
+                ("promotions", self.promotions_selector.as_ref()),
+                ("price_int", self.price_int_selector.as_ref()),
+                ("price_dec", self.price_dec_selector.as_ref()),
+            ],
+        );
+        EXTRACTORS.iter().for_each(|(class, _, _)| {
+            let selector = Selector::parse(class).unwrap();
+            let n = html
+                .select(&self.selector)
+                .filter(|element| element.select(&selector).next().is_some())
+                .count();
+            counts.push((class.to_string(), n));
+        });
+        Ok(counts)
+    }
+}
+
+/// Url of the `page` of the listing on `url`
+fn page_url(url: &str, page: usize) -> String {
+    format!("{}?page={}", url, page)
 }
 
 /// extractros are pairs of: (class, extract_all_text, [values_to_extract])
@@ -273,3 +896,225 @@ fn add_to_map(
             }
         });
 }
+
+/// Inserts `data-sku` using the first capture group of `re` over `href`,
+/// only if `data-sku` is not already present.
+fn add_sku_from_href(map: &mut HashMap<String, String>, re: &Regex) {
+    if map.contains_key("data-sku") {
+        return;
+    }
+    let sku = map
+        .get("href")
+        .and_then(|href| re.captures(href))
+        .and_then(|captures| captures.get(1))
+        .map(|x| x.as_str().to_string());
+    if let Some(sku) = sku {
+        map.insert("data-sku".to_string(), sku);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{tests::test_settings, FieldSelector};
+    use std::path::Path;
+
+    fn string_map(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn sku_from_href_keeps_present_sku() {
+        let re = Regex::new(r"/p/(\d+)").unwrap();
+        let mut map = string_map(&[("data-sku", "999"), ("href", "/p/12345/arroz")]);
+        add_sku_from_href(&mut map, &re);
+        assert_eq!(map.get("data-sku").map(String::as_str), Some("999"));
+    }
+
+    #[test]
+    fn sku_from_href_fills_missing_sku() {
+        let re = Regex::new(r"/p/(\d+)").unwrap();
+        let mut map = string_map(&[("href", "https://www.plazavea.com.pe/p/12345/arroz")]);
+        add_sku_from_href(&mut map, &re);
+        assert_eq!(map.get("data-sku").map(String::as_str), Some("12345"));
+    }
+
+    #[test]
+    fn sku_from_href_without_match_adds_nothing() {
+        let re = Regex::new(r"/p/(\d+)").unwrap();
+        let mut map = string_map(&[("href", "/arroz")]);
+        add_sku_from_href(&mut map, &re);
+        assert!(!map.contains_key("data-sku"));
+    }
+
+    #[test]
+    fn field_defaults_only_fill_missing_fields() {
+        let mut item = MultipageItem::try_from(string_map(&[
+            ("data-sku", "1"),
+            ("title", "Leche Gloria 400g"),
+            ("href", "/leche-gloria/p"),
+        ]))
+        .unwrap();
+        let defaults = string_map(&[
+            ("brand", "Sin marca"),
+            ("category", "Sin categoría"),
+            ("name", "Sin nombre"),
+            ("uri", "/"),
+        ]);
+        item.apply_field_defaults(&defaults);
+        assert_eq!(item.brand.as_deref(), Some("Sin marca"));
+        assert_eq!(item.category.as_deref(), Some("Sin categoría"));
+        assert_eq!(item.name.as_deref(), Some("Leche Gloria 400g"));
+        assert_eq!(item.uri.as_deref(), Some("/leche-gloria/p"));
+    }
+
+    /// Plaza Vea spider built from `settings` without a webdriver
+    async fn spider(settings: &Settings) -> MultipageSpider {
+        MultipageSpider::from_settings(settings, &settings.plaza_vea, None)
+            .await
+            .unwrap()
+    }
+
+    /// Listing page with a card for each `(title, href)`
+    fn listing(cards: &[(&str, &str)]) -> String {
+        let cards = cards
+            .iter()
+            .map(|(title, href)| {
+                format!(
+                    r#"<div class="Showcase">
+                        <div class="Showcase__content" title="{}"></div>
+                        <a class="Showcase__link" href="{}"></a>
+                    </div>"#,
+                    title, href
+                )
+            })
+            .collect::<String>();
+        format!(r#"<div class="showcase-grid">{}</div>"#, cards)
+    }
+
+    #[tokio::test]
+    async fn identical_cards_synthesize_the_same_sku() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.synthesize_id = true;
+        let spider = spider(&settings).await;
+        let document = listing(&[
+            ("Leche Gloria 400g", "/leche-gloria/p"),
+            ("Leche Gloria 400g", "/leche-gloria/p"),
+            ("Arroz Costeño 750g", "/arroz-costeno/p"),
+        ]);
+        let skus = || {
+            let mut skus = spider
+                .extract_items(&document, "http://mock/a", &Warnings::default())
+                .into_iter()
+                .map(|x| x.sku)
+                .collect::<Vec<_>>();
+            skus.sort();
+            skus
+        };
+        // The identical cards get the same sku, so they are deduplicated
+        let first = skus();
+        assert_eq!(first.len(), 2);
+        assert!(first.iter().all(|x| x.starts_with("synth-")));
+        assert_ne!(first[0], first[1]);
+        assert_eq!(skus(), first);
+    }
+
+    #[test]
+    fn invalid_price_is_ignored() {
+        let item = MultipageItem::try_from(string_map(&[
+            ("data-sku", "1"),
+            ("title", "Leche Gloria 400g"),
+            ("data-price", "Consultar"),
+        ]))
+        .unwrap();
+        assert!(item.price.is_none());
+        assert_eq!(item.name.as_deref(), Some("Leche Gloria 400g"));
+    }
+
+    #[test]
+    fn invalid_data_price_falls_back_to_sale_price() {
+        let item = MultipageItem::try_from(string_map(&[
+            ("data-sku", "1"),
+            ("data-price", "Consultar"),
+            (".Showcase__salePrice", "S/ 4.20"),
+        ]))
+        .unwrap();
+        assert_eq!(item.price.map(|x| x.amount), Some(4.2));
+    }
+
+    #[tokio::test]
+    async fn invalid_price_composes_price_from_parts() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.plaza_vea.common.price_int_selector = Some(".price-int".to_string());
+        settings.plaza_vea.common.price_dec_selector = Some(".price-dec".to_string());
+        let spider = spider(&settings).await;
+        let document = r#"<div class="showcase-grid">
+            <div class="Showcase" data-sku="1" data-price="Consultar">
+                <div class="Showcase__content" title="Leche Gloria 400g"></div>
+                <span class="price-int">S/ 4.</span><span class="price-dec">20</span>
+            </div>
+        </div>"#;
+        let items = spider.extract_items(document, "http://mock/a", &Warnings::default());
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].price.as_ref().map(|x| x.amount), Some(4.2));
+    }
+
+    #[tokio::test]
+    async fn price_falls_back_to_itemprop_meta() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.plaza_vea.common.price = Some(FieldSelector {
+            selector: Some(r#"meta[itemprop="price"]"#.to_string()),
+            attr: Some("content".to_string()),
+        });
+        let spider = spider(&settings).await;
+        let document = r#"<div class="showcase-grid">
+            <div class="Showcase" data-sku="1">
+                <div class="Showcase__content" title="Leche Gloria 400g"></div>
+                <meta itemprop="price" content="9.90">
+                <meta itemprop="priceCurrency" content="PEN">
+            </div>
+            <div class="Showcase" data-sku="2" data-price="4.20">
+                <div class="Showcase__content" title="Arroz Costeño 750g"></div>
+                <meta itemprop="price" content="5.50">
+            </div>
+        </div>"#;
+        let mut items = spider.extract_items(document, "http://mock/a", &Warnings::default());
+        items.sort_by(|a, b| a.sku.cmp(&b.sku));
+        let prices = items
+            .iter()
+            .map(|x| x.price.as_ref().map(|x| x.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(prices, [Some(9.9), Some(4.2)]);
+    }
+
+    #[tokio::test]
+    async fn rank_counts_the_excluded_cards() {
+        let mut settings = test_settings(Path::new("output_test"));
+        settings.plaza_vea.common.exclude_selector = Some(".Showcase--sponsored".to_string());
+        let spider = spider(&settings).await;
+        let document = r#"<div class="showcase-grid">
+            <div class="Showcase" data-sku="1" data-price="4.20">
+                <div class="Showcase__content" title="Leche Gloria 400g"></div>
+            </div>
+            <div class="Showcase Showcase--sponsored" data-sku="2" data-price="9.90">
+                <div class="Showcase__content" title="Aceite Primor 1L"></div>
+            </div>
+            <div class="Showcase" data-sku="3" data-price="5.50">
+                <div class="Showcase__content" title="Arroz Costeño 750g"></div>
+            </div>
+        </div>"#;
+        let mut ranks = spider
+            .extract_items(document, "http://mock/a", &Warnings::default())
+            .into_iter()
+            .map(|x| (x.sku, x.rank))
+            .collect::<Vec<_>>();
+        ranks.sort();
+        assert_eq!(
+            ranks,
+            [("1".to_string(), Some(1)), ("3".to_string(), Some(3))]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::oneshot;
+
+/// Directory with the fixture pages
+pub const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+/// Local http server serving the fixture pages on `dir` (eg: [`FIXTURES_DIR`]), used to run the spiders end to end
+/// without touching the real stores.
+/// `/{name}` serves `{dir}/{name}.html`, the server stops when dropped.
+pub struct FixtureServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl FixtureServer {
+    /// Starts serving `dir` on a random local port
+    pub fn start(dir: impl Into<PathBuf>) -> hyper::Result<Self> {
+        let dir = Arc::new(dir.into());
+        let make_service = make_service_fn(move |_| {
+            let dir = dir.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| serve_fixture(dir.clone(), req))) }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = rx.await;
+        }));
+        Ok(Self {
+            addr,
+            shutdown: Some(shutdown),
+        })
+    }
+
+    /// Base url of the server (eg: "http://127.0.0.1:12345")
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn serve_fixture(
+    dir: Arc<PathBuf>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let name = req.uri().path().trim_matches('/');
+    let path = Path::new(name);
+    // Only plain file names relative to `dir` are served
+    let is_safe = !name.is_empty() && path.components().all(|x| matches!(x, Component::Normal(_)));
+    let content = match is_safe {
+        true => tokio::fs::read(dir.join(format!("{}.html", name)))
+            .await
+            .ok(),
+        false => None,
+    };
+    let response = match content {
+        Some(content) => Response::builder()
+            .header("content-type", "text/html; charset=utf-8")
+            .body(Body::from(content)),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("Failed to build response"))
+}
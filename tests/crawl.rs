@@ -0,0 +1,106 @@
+//! Runs a whole crawl against the mock spider, with the configuration built inline instead
+//! of read from `configuration/*.yaml`
+use peru_prices::{
+    configuration::Settings,
+    crawler::{process_all, BoxedCrawler, Crawler},
+    spiders::{MockItem, MockResponse, MockSpider},
+};
+use std::{path::Path, time::Duration};
+use tokio_util::sync::CancellationToken;
+
+const DATE: &str = "20240101";
+
+fn settings(out_path: &Path) -> Settings {
+    Settings::from_yaml_str(&format!(
+        r#"
+out_path: {:?}
+headless: true
+delay_milis: 0
+spiders_buffer_size: 2
+infinite_scrolling:
+  scroll_delay_milis: 0
+  scroll_checks: 1
+metro:
+  name: "metro"
+  base_url: "https://www.metro.pe"
+  selector: ".product-item"
+  subroutes: []
+wong:
+  name: "wong"
+  base_url: "https://www.wong.pe"
+  selector: ".product-item"
+  subroutes: []
+plaza_vea:
+  name: "plaza_vea"
+  base_url: "https://www.plazavea.com.pe"
+  selector: ".showcase-grid > .Showcase"
+  subroutes: []
+"#,
+        out_path
+    ))
+    .expect("Invalid configuration")
+}
+
+fn crawler(
+    spider: MockSpider,
+    settings: &Settings,
+    cancel: &CancellationToken,
+) -> Box<dyn BoxedCrawler> {
+    Box::new(Crawler::new(spider, settings, DATE, cancel.clone()))
+}
+
+#[tokio::test]
+async fn crawl_writes_the_items_of_every_spider() {
+    let out_path = std::env::temp_dir().join(format!("peru-prices-crawl-{}", std::process::id()));
+    let settings = settings(&out_path);
+    let cancel = CancellationToken::new();
+    let store_a = MockSpider::new(
+        "store_a",
+        "http://mock",
+        vec![
+            (
+                "lacteos",
+                MockResponse::ok(vec![
+                    MockItem::new(1, Some("Leche Gloria 400g")),
+                    MockItem::new(2, Some("Yogurt Laive 1L")),
+                ]),
+            ),
+            (
+                "abarrotes",
+                MockResponse::ok(vec![
+                    MockItem::new(2, Some("Yogurt Laive 1L")),
+                    MockItem::new(3, Some("Arroz Costeño 750g")),
+                ]),
+            ),
+        ],
+        0,
+    );
+    let store_b = MockSpider::new(
+        "store_b",
+        "http://mock",
+        vec![(
+            "abarrotes",
+            MockResponse::ok(vec![MockItem::new(4, Some("Aceite Primor 1L"))]),
+        )],
+        0,
+    );
+    let crawlers = vec![
+        crawler(store_a, &settings, &cancel),
+        crawler(store_b, &settings, &cancel),
+    ];
+
+    let mut reports = process_all(crawlers, 2, Duration::ZERO, true, &cancel)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|report| report.items)
+        .collect::<Vec<_>>();
+    reports.sort();
+    assert_eq!(reports, [1, 3]);
+    for (name, n) in [("store_a", 3), ("store_b", 1)] {
+        let path = out_path.join(format!("{}_{}.csv", name, DATE));
+        let mut rdr = csv::Reader::from_path(&path).unwrap();
+        assert_eq!(rdr.records().count(), n, "{:?}", path);
+    }
+    std::fs::remove_dir_all(out_path).unwrap();
+}
@@ -0,0 +1,79 @@
+//! Runs the infinite scrolling spider against the fixture pages served locally
+mod common;
+
+use common::{FixtureServer, FIXTURES_DIR};
+use peru_prices::{
+    configuration::Settings,
+    spiders::{join_url, InfiniteScrollingSpider, Spider, Warnings},
+};
+
+/// Items loaded by `infinite_scroll.html` once scrolled to the end
+const FIXTURE_ITEMS: usize = 50;
+
+/// Configuration with the metro spider pointing to `base_url`
+fn settings(base_url: &str) -> Settings {
+    Settings::from_yaml_str(&format!(
+        r#"
+out_path: "output_test"
+headless: true
+delay_milis: 0
+spiders_buffer_size: 1
+infinite_scrolling:
+  scroll_delay_milis: 500
+  scroll_checks: 3
+metro:
+  name: "metro"
+  base_url: {:?}
+  selector: ".product-item"
+  subroutes: ["infinite_scroll"]
+wong:
+  name: "wong"
+  base_url: "https://www.wong.pe"
+  selector: ".product-item"
+  subroutes: []
+plaza_vea:
+  name: "plaza_vea"
+  base_url: "https://www.plazavea.com.pe"
+  selector: ".showcase-grid > .Showcase"
+  subroutes: []
+"#,
+        base_url
+    ))
+    .expect("Invalid configuration")
+}
+
+#[tokio::test]
+async fn server_serves_the_fixture_pages() {
+    let server = FixtureServer::start(FIXTURES_DIR).unwrap();
+    let response = reqwest::get(join_url(&server.url(), "infinite_scroll"))
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert!(response.text().await.unwrap().contains("product-item"));
+    let response = reqwest::get(join_url(&server.url(), "missing"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+#[ignore = "needs a webdriver running on the configured webdriver_url"]
+async fn spider_scrolls_until_every_item_is_loaded() {
+    let server = FixtureServer::start(FIXTURES_DIR).unwrap();
+    let settings = settings(&server.url());
+    let webdriver_url = settings.webdriver_endpoint(0).unwrap();
+    let spider =
+        InfiniteScrollingSpider::from_settings(&settings, &settings.metro, Some(&webdriver_url))
+            .await
+            .unwrap();
+    let url = join_url(spider.base_url(), &spider.subroutes()[0]);
+    let items = spider.scrape(&url, &Warnings::default()).await;
+    spider.close().await.unwrap();
+    let mut ids = items
+        .unwrap()
+        .into_iter()
+        .map(|item| item.id.parse::<usize>().unwrap())
+        .collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, (1..=FIXTURE_ITEMS).collect::<Vec<_>>());
+}
@@ -0,0 +1,17 @@
+//! The crawler runs the spiders on spawned tasks, so they need to be `Send + Sync` even
+//! though their webdriver session is mutated while scraping
+use peru_prices::{
+    crawler::Crawler,
+    spiders::{InfiniteScrollingSpider, MultipageSpider},
+};
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn spiders_are_send_and_sync() {
+    assert_send_sync::<fantoccini::Client>();
+    assert_send_sync::<InfiniteScrollingSpider>();
+    assert_send_sync::<MultipageSpider>();
+    assert_send_sync::<Crawler<InfiniteScrollingSpider>>();
+    assert_send_sync::<Crawler<MultipageSpider>>();
+}